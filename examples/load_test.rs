@@ -0,0 +1,131 @@
+// Opens many concurrent WebSocket connections against a running station and reports how long
+// each one takes to connect and to receive its first message. Meanwhile, and for a while after,
+// `/status` is polled to see how long requests needing the media player wait.
+//
+//   cargo run --release --example load_test -- ws://localhost:8080/ws 2000
+use futures::StreamExt;
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tokio::time::{sleep, timeout};
+
+const RECEIVE_TIMEOUT: Duration = Duration::from_secs(30);
+const STATUS_EVERY: Duration = Duration::from_millis(250);
+// How long `/status` keeps being polled once the clients are connected
+const HOLD: Duration = Duration::from_secs(20);
+
+#[tokio::main]
+async fn main() {
+    let mut args = std::env::args().skip(1);
+    let url = args
+        .next()
+        .unwrap_or_else(|| String::from("ws://localhost:8080/ws"));
+    let clients: usize = args.next().and_then(|n| n.parse().ok()).unwrap_or(1000);
+
+    println!("connecting {} clients to {}", clients, url);
+    let started = Instant::now();
+    let done = Arc::new(AtomicBool::new(false));
+    let status_url = format!("{}/status", url.replacen("ws", "http", 1).trim_end_matches("/ws"));
+    let status = tokio::spawn(poll_status(status_url, done.clone()));
+
+    let tasks: Vec<_> = (0..clients)
+        .map(|_| tokio::spawn(connect(url.clone())))
+        .collect();
+
+    let mut connect_times = Vec::with_capacity(clients);
+    let mut first_message_times = Vec::with_capacity(clients);
+    let mut failures = 0;
+    let mut sockets = Vec::with_capacity(clients);
+    for task in tasks {
+        match task.await {
+            Ok(Ok((socket, connected, first_message))) => {
+                connect_times.push(connected);
+                first_message_times.push(first_message);
+                sockets.push(socket);
+            }
+            Ok(Err(err)) => {
+                failures += 1;
+                eprintln!("client failed: {}", err);
+            }
+            Err(err) => {
+                failures += 1;
+                eprintln!("client task failed: {}", err);
+            }
+        }
+    }
+
+    println!("done in {:?}, {} failures", started.elapsed(), failures);
+    report("connect", &mut connect_times);
+    report("first message", &mut first_message_times);
+
+    sleep(HOLD).await;
+    done.store(true, Ordering::Relaxed);
+    let (mut status_times, busy) = status.await.unwrap_or_default();
+    println!(
+        "status: {} requests, {} answered busy",
+        status_times.len(),
+        busy
+    );
+    report("status", &mut status_times);
+    drop(sockets);
+}
+
+// Times `/status` until `done`, and counts how many times the station answered it was busy
+async fn poll_status(url: String, done: Arc<AtomicBool>) -> (Vec<Duration>, usize) {
+    let client = reqwest::Client::new();
+    let mut times = vec![];
+    let mut busy = 0;
+    while !done.load(Ordering::Relaxed) {
+        let started = Instant::now();
+        match client.get(url.as_str()).send().await {
+            Ok(res) => {
+                let body = res.text().await.unwrap_or_default();
+                times.push(started.elapsed());
+                if body.contains(r#""station":"busy""#) {
+                    busy += 1;
+                }
+            }
+            Err(err) => eprintln!("status failed: {}", err),
+        }
+        sleep(STATUS_EVERY).await;
+    }
+    (times, busy)
+}
+
+type Socket =
+    tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>;
+
+async fn connect(url: String) -> Result<(Socket, Duration, Duration), String> {
+    let started = Instant::now();
+    let (mut socket, _) = tokio_tungstenite::connect_async(url.as_str())
+        .await
+        .map_err(|err| err.to_string())?;
+    let connected = started.elapsed();
+
+    match timeout(RECEIVE_TIMEOUT, socket.next()).await {
+        Ok(Some(Ok(_))) => Ok((socket, connected, started.elapsed())),
+        Ok(Some(Err(err))) => Err(err.to_string()),
+        Ok(None) => Err(String::from("connection closed")),
+        Err(_) => Err(String::from("no message received")),
+    }
+}
+
+fn report(name: &str, times: &mut [Duration]) {
+    if times.is_empty() {
+        return;
+    }
+    times.sort();
+    let percentile = |p: usize| times[(times.len() * p / 100).min(times.len() - 1)];
+    println!(
+        "{}: p50 {:?}, p90 {:?}, p99 {:?}, max {:?}",
+        name,
+        percentile(50),
+        percentile(90),
+        percentile(99),
+        times[times.len() - 1]
+    );
+}
@@ -0,0 +1,389 @@
+use crate::{
+    config::AudioCacheConfig,
+    error::Error,
+    metrics::Metrics,
+    soundcloud::{ApiClient, Track},
+};
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use reqwest::{header::CONTENT_TYPE, Response};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+use tokio::io::AsyncWriteExt;
+
+// Cached audio is identified by its track only: it's looked up by the proxy and the failover
+// without resolving the track first, so there's no transcoding to compare with.
+#[derive(Debug, Clone)]
+struct Entry {
+    size: u64,
+    track: Track,
+    content_type: String,
+}
+
+// Assumed for the audio cached before its content type was kept
+const DEFAULT_CONTENT_TYPE: &str = "audio/mpeg";
+
+// Saved next to the audio, so that the cache can be indexed again on startup
+#[derive(Debug, Serialize, Deserialize)]
+struct Metadata {
+    #[serde(flatten)]
+    track: Track,
+    // Upstream content type, served along with the cached audio
+    content_type: Option<String>,
+}
+
+// A cached track's audio file
+#[derive(Debug, Clone)]
+pub struct CachedAudio {
+    pub path: PathBuf,
+    pub content_type: String,
+}
+
+// Aired and upcoming SoundCloud tracks, kept on disk within a size limit, so that they can be
+// served without going through SoundCloud again, even when it's down.
+#[derive(Debug, Default)]
+pub struct AudioCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    // Cached tracks, least recently used first
+    entries: Mutex<VecDeque<Entry>>,
+    downloading: Mutex<HashSet<u64>>,
+    metrics: Arc<Metrics>,
+}
+
+impl AudioCache {
+    pub fn new(config: &AudioCacheConfig, metrics: Arc<Metrics>) -> Self {
+        let cache = Self {
+            dir: config.dir.clone(),
+            max_bytes: config.max_mb * 1024 * 1024,
+            entries: Mutex::new(VecDeque::new()),
+            downloading: Mutex::new(HashSet::new()),
+            metrics,
+        };
+
+        if cache.is_enabled() {
+            match fs::create_dir_all(&cache.dir) {
+                Ok(_) => cache.index(),
+                Err(err) => tracing::warn!(
+                    "unable to create cache directory {}: {}",
+                    cache.dir.display(),
+                    err
+                ),
+            }
+        }
+        cache
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.max_bytes > 0
+    }
+
+    // Cached audio for the track, if any
+    pub fn get(&self, track_id: u64) -> Option<CachedAudio> {
+        let audio = self.touch(track_id).map(|entry| CachedAudio {
+            path: self.audio_path(entry.track.id),
+            content_type: entry.content_type,
+        });
+        match audio {
+            Some(_) => self.metrics.audio_cache_hits.inc(),
+            None => self.metrics.audio_cache_misses.inc(),
+        }
+        audio
+    }
+
+    // The cached track, streamed through the station's proxy
+    pub fn cached_track(&self, track_id: u64) -> Option<Track> {
+        self.touch(track_id).map(|entry| proxied(entry.track))
+    }
+
+    // Least recently used track that hasn't been played lately, to keep the station on air when
+    // SoundCloud is down
+    pub fn fallback_track(&self, played: &HashMap<u64, DateTime<Utc>>) -> Option<Track> {
+        let track_id = {
+            let entries = self.entries.lock().unwrap();
+            entries
+                .iter()
+                .find(|entry| !played.contains_key(&entry.track.id))
+                .or_else(|| entries.front())?
+                .track
+                .id
+        };
+        self.cached_track(track_id)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.lock().unwrap().is_empty()
+    }
+
+    pub fn contains(&self, track_id: u64) -> bool {
+        self.entries
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|entry| entry.track.id == track_id)
+    }
+
+    // Marks the track as being downloaded, returns false if it's cached or downloading already
+    pub fn start_download(&self, track_id: u64) -> bool {
+        if !self.is_enabled() || self.contains(track_id) {
+            return false;
+        }
+        self.downloading.lock().unwrap().insert(track_id)
+    }
+
+    pub fn finish_download(&self, track_id: u64) {
+        self.downloading.lock().unwrap().remove(&track_id);
+    }
+
+    // Downloads a resolved track, unless it's cached or being downloaded already
+    pub async fn download(&self, api: &ApiClient, track: &Track) -> Result<(), Error> {
+        let stream_url = match &track.url {
+            Some(url) => url.clone(),
+            None => return Ok(()),
+        };
+        if !self.start_download(track.id) {
+            return Ok(());
+        }
+
+        let result = match api.get_stream(stream_url.as_str(), None).await {
+            Ok(response) => self.store(track, response).await,
+            Err(err) => Err(err),
+        };
+        self.finish_download(track.id);
+        result
+    }
+
+    // Writes the whole response body to the cache, evicting the least recently used tracks
+    pub async fn store(&self, track: &Track, response: Response) -> Result<(), Error> {
+        let content_type = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or(DEFAULT_CONTENT_TYPE)
+            .to_string();
+        let part_path = self.dir.join(format!("{}.part", track.id));
+        let size = match write_body(&part_path, response).await {
+            Ok(size) => size,
+            Err(err) => {
+                let _ = tokio::fs::remove_file(&part_path).await;
+                return Err(err);
+            }
+        };
+
+        let metadata = serde_json::to_vec(&Metadata {
+            track: track.clone(),
+            content_type: Some(content_type.clone()),
+        })
+        .map_err(|_| Error::SoundcloudJsonParseError(String::from("Track")))?;
+        tokio::fs::write(self.metadata_path(track.id), metadata).await?;
+        tokio::fs::rename(&part_path, self.audio_path(track.id)).await?;
+
+        let evicted = {
+            let mut entries = self.entries.lock().unwrap();
+            entries.retain(|entry| entry.track.id != track.id);
+            entries.push_back(Entry {
+                size,
+                track: track.clone(),
+                content_type,
+            });
+            self.evict(&mut entries)
+        };
+        for track_id in evicted {
+            let _ = tokio::fs::remove_file(self.audio_path(track_id)).await;
+            let _ = tokio::fs::remove_file(self.metadata_path(track_id)).await;
+        }
+
+        tracing::debug!("cached track with id {}", track.id);
+        Ok(())
+    }
+
+    fn touch(&self, track_id: u64) -> Option<Entry> {
+        let mut entries = self.entries.lock().unwrap();
+        let position = entries
+            .iter()
+            .rposition(|entry| entry.track.id == track_id)?;
+        let entry = entries.remove(position)?;
+        entries.push_back(entry.clone());
+        Some(entry)
+    }
+
+    // Drops the least recently used entries until the cache fits its size limit
+    fn evict(&self, entries: &mut VecDeque<Entry>) -> Vec<u64> {
+        let mut total: u64 = entries.iter().map(|entry| entry.size).sum();
+        let mut evicted = vec![];
+        while total > self.max_bytes {
+            match entries.pop_front() {
+                Some(entry) => {
+                    total -= entry.size;
+                    evicted.push(entry.track.id);
+                }
+                None => break,
+            }
+        }
+        self.metrics.audio_cache_bytes.set(total as i64);
+        evicted
+    }
+
+    fn audio_path(&self, track_id: u64) -> PathBuf {
+        self.dir.join(format!("{}.mp3", track_id))
+    }
+
+    fn metadata_path(&self, track_id: u64) -> PathBuf {
+        self.dir.join(format!("{}.json", track_id))
+    }
+
+    // Picks up the tracks cached before a restart, oldest first, and cleans up partial downloads
+    fn index(&self) {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+
+        let mut cached = vec![];
+        for entry in entries.flatten() {
+            let path = entry.path();
+            match path.extension().and_then(|e| e.to_str()) {
+                Some("part") => {
+                    let _ = fs::remove_file(&path);
+                }
+                Some("mp3") => match read_entry(&path) {
+                    // Files named after something else than their track, e.g. by an older
+                    // version, would never be found
+                    Some(cached_entry) if path == self.audio_path(cached_entry.1.track.id) => {
+                        cached.push(cached_entry)
+                    }
+                    _ => {
+                        let _ = fs::remove_file(&path);
+                        let _ = fs::remove_file(path.with_extension("json"));
+                    }
+                },
+                _ => {}
+            }
+        }
+        cached.sort_by_key(|(modified, _)| *modified);
+
+        let mut entries: VecDeque<Entry> = cached.into_iter().map(|(_, entry)| entry).collect();
+        for track_id in self.evict(&mut entries) {
+            let _ = fs::remove_file(self.audio_path(track_id));
+            let _ = fs::remove_file(self.metadata_path(track_id));
+        }
+        tracing::info!("audio cache has {} tracks", entries.len());
+        *self.entries.lock().unwrap() = entries;
+    }
+}
+
+fn read_entry(path: &Path) -> Option<(std::time::SystemTime, Entry)> {
+    let metadata = fs::metadata(path).ok()?;
+    let saved: Metadata =
+        serde_json::from_slice(&fs::read(path.with_extension("json")).ok()?).ok()?;
+    let entry = Entry {
+        size: metadata.len(),
+        track: saved.track,
+        content_type: saved
+            .content_type
+            .unwrap_or_else(|| String::from(DEFAULT_CONTENT_TYPE)),
+    };
+    Some((metadata.modified().ok()?, entry))
+}
+
+// Cached tracks are served by the proxy, SoundCloud's stream url might not work anymore
+fn proxied(mut track: Track) -> Track {
+    track.url = Some(format!("/proxy/track/{}", track.id));
+    track
+}
+
+async fn write_body(path: &Path, response: Response) -> Result<u64, Error> {
+    let mut file = tokio::fs::File::create(path).await?;
+    let mut size = 0;
+    let mut body = response.bytes_stream();
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk?;
+        size += chunk.len() as u64;
+        file.write_all(&chunk).await?;
+    }
+    file.flush().await?;
+    Ok(size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(id: u64) -> Track {
+        Track {
+            id,
+            permalink_url: None,
+            artwork_url: None,
+            duration: Some(180_000),
+            title: Some(format!("Track {}", id)),
+            artist: None,
+            artist_permalink: None,
+            url: None,
+            token: None,
+            transcoding: None,
+            metadata: Default::default(),
+        }
+    }
+
+    fn cache(name: &str) -> AudioCache {
+        let dir =
+            std::env::temp_dir().join(format!("robo_radio_cache_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        AudioCache::new(&AudioCacheConfig { dir, max_mb: 1 }, Arc::default())
+    }
+
+    fn upstream(content_type: &str) -> Response {
+        axum::http::Response::builder()
+            .header(CONTENT_TYPE, content_type)
+            .body("0123456789")
+            .unwrap()
+            .into()
+    }
+
+    #[tokio::test]
+    async fn serves_the_upstream_content_type() {
+        let cache = cache("content_type");
+        cache.store(&track(1), upstream("audio/ogg")).await.unwrap();
+        assert_eq!(cache.get(1).unwrap().content_type, "audio/ogg");
+
+        // Kept over a restart
+        let reindexed = AudioCache::new(
+            &AudioCacheConfig {
+                dir: cache.dir.clone(),
+                max_mb: 1,
+            },
+            Arc::default(),
+        );
+        let audio = reindexed.get(1).unwrap();
+        assert_eq!(audio.content_type, "audio/ogg");
+        assert_eq!(fs::read(audio.path).unwrap(), b"0123456789");
+        fs::remove_dir_all(&cache.dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn assumes_mpeg_for_audio_cached_without_content_type() {
+        let cache = cache("legacy");
+        fs::write(cache.audio_path(2), b"0123456789").unwrap();
+        fs::write(
+            cache.metadata_path(2),
+            serde_json::to_vec(&track(2)).unwrap(),
+        )
+        .unwrap();
+
+        let reindexed = AudioCache::new(
+            &AudioCacheConfig {
+                dir: cache.dir.clone(),
+                max_mb: 1,
+            },
+            Arc::default(),
+        );
+        assert_eq!(reindexed.get(2).unwrap().content_type, DEFAULT_CONTENT_TYPE);
+        fs::remove_dir_all(&cache.dir).unwrap();
+    }
+}
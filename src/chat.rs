@@ -0,0 +1,418 @@
+use crate::{
+    config::ChatConfig,
+    error::Error,
+    journal::{self, Journal},
+    media_player::CurrentTrack,
+    metrics::Metrics,
+    presence::Listener,
+};
+use chrono::{DateTime, Duration, Utc};
+use regex::{Captures, Regex};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, VecDeque},
+    path::Path,
+    sync::Arc,
+};
+use uuid::Uuid;
+
+// The track that was on air when a message was sent
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatTrack {
+    pub id: u64,
+    pub title: String,
+    pub artist: String,
+}
+
+impl From<&CurrentTrack> for ChatTrack {
+    fn from(track: &CurrentTrack) -> Self {
+        Self {
+            id: track.id,
+            title: track.title.clone(),
+            artist: track.artist.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub id: String,
+    // Public id of the sender's session
+    pub author: String,
+    pub nickname: String,
+    pub avatar_seed: Option<String>,
+    pub text: String,
+    pub sent_at: DateTime<Utc>,
+    pub track: Option<ChatTrack>,
+    // Who gets muted along with the message, not logged
+    #[serde(skip)]
+    key: String,
+}
+
+// Lines of the chat log
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ChatLogEntry {
+    Message(ChatMessage),
+    Deleted { id: String, at: DateTime<Utc> },
+}
+
+// The station's chat: recent messages for newcomers, per-listener rate limits, moderators' mutes
+// and the blocklist. Messages are appended to the log in background, when there's one.
+#[derive(Debug)]
+pub struct Chat {
+    config: ChatConfig,
+    blocklist: Option<Regex>,
+    history: VecDeque<ChatMessage>,
+    // Recent posts and mutes, by listener key
+    posts: HashMap<String, VecDeque<DateTime<Utc>>>,
+    mutes: HashMap<String, DateTime<Utc>>,
+    log: Option<Journal<ChatLogEntry>>,
+    metrics: Arc<Metrics>,
+}
+
+impl Chat {
+    // Replays the log, if any, to get the history back
+    pub fn new(config: ChatConfig, metrics: Arc<Metrics>) -> Self {
+        let blocklist = blocklist_regex(&config.blocklist);
+        let mut chat = Self {
+            config,
+            blocklist,
+            history: VecDeque::new(),
+            posts: HashMap::new(),
+            mutes: HashMap::new(),
+            log: None,
+            metrics,
+        };
+        if let Some(path) = chat.config.log.clone() {
+            chat.load_log(&path);
+            chat.log = Some(Journal::open(path));
+        }
+        chat
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.config.enabled
+    }
+
+    // Oldest messages first
+    pub fn history(&self) -> Vec<ChatMessage> {
+        self.history.iter().cloned().collect()
+    }
+
+    pub fn post(
+        &mut self,
+        author: &Listener,
+        key: &str,
+        text: &str,
+        track: Option<ChatTrack>,
+        now: DateTime<Utc>,
+    ) -> Result<ChatMessage, Error> {
+        if !self.config.enabled {
+            return Err(Error::ChatDisabled);
+        }
+        let nickname = author
+            .nickname
+            .as_deref()
+            .ok_or(Error::ChatNicknameRequired)?;
+        let text = text.trim();
+        if text.is_empty() || text.chars().count() > self.config.max_chars {
+            return Err(Error::ChatInvalidMessage(self.config.max_chars));
+        }
+        if let Some(until) = self.mutes.get(key) {
+            if *until > now {
+                return Err(Error::ChatMuted(
+                    until.signed_duration_since(now).num_seconds() + 1,
+                ));
+            }
+            self.mutes.remove(key);
+        }
+        self.check_rate_limit(key, now)?;
+
+        let (text, filtered) = self.filter(text);
+        if filtered {
+            self.metrics.chat_messages_filtered.inc();
+        }
+        let message = ChatMessage {
+            id: Uuid::new_v4().as_simple().to_string(),
+            author: author.id.clone(),
+            nickname: nickname.to_string(),
+            avatar_seed: author.avatar_seed.clone(),
+            text,
+            sent_at: now,
+            track,
+            key: key.to_string(),
+        };
+        self.history.push_back(message.clone());
+        while self.history.len() > self.config.history {
+            self.history.pop_front();
+        }
+        self.append(ChatLogEntry::Message(message.clone()));
+        self.metrics.chat_messages.inc();
+        Ok(message)
+    }
+
+    pub fn delete(&mut self, message_id: &str, now: DateTime<Utc>) -> Result<ChatMessage, Error> {
+        let index = self
+            .history
+            .iter()
+            .position(|message| message.id == message_id)
+            .ok_or_else(|| Error::ChatMessageNotFound(message_id.to_string()))?;
+        let message = self.history.remove(index).unwrap();
+        self.append(ChatLogEntry::Deleted {
+            id: message.id.clone(),
+            at: now,
+        });
+        Ok(message)
+    }
+
+    // Mutes the author of a recent message, for `secs` or the configured duration
+    pub fn mute(
+        &mut self,
+        message_id: &str,
+        secs: Option<i64>,
+        now: DateTime<Utc>,
+    ) -> Result<(ChatMessage, DateTime<Utc>), Error> {
+        let message = self
+            .history
+            .iter()
+            .find(|message| message.id == message_id && !message.key.is_empty())
+            .cloned()
+            .ok_or_else(|| Error::ChatMessageNotFound(message_id.to_string()))?;
+        let until = now + Duration::seconds(secs.unwrap_or(self.config.mute_secs));
+        self.mutes.retain(|_, until| *until > now);
+        self.mutes.insert(message.key.clone(), until);
+        Ok((message, until))
+    }
+
+    // Masks the blocked words, e.g. in nicknames
+    pub fn mask(&self, text: &str) -> String {
+        self.filter(text).0
+    }
+
+    // Masks the blocked words, and tells whether there were any
+    fn filter(&self, text: &str) -> (String, bool) {
+        match &self.blocklist {
+            Some(blocklist) if blocklist.is_match(text) => {
+                let masked = blocklist
+                    .replace_all(text, |caps: &Captures| "*".repeat(caps[0].chars().count()));
+                (masked.into_owned(), true)
+            }
+            _ => (text.to_string(), false),
+        }
+    }
+
+    // Sliding window: at most `rate_limit` messages every `rate_window_secs`
+    fn check_rate_limit(&mut self, key: &str, now: DateTime<Utc>) -> Result<(), Error> {
+        let window = Duration::seconds(self.config.rate_window_secs);
+        self.posts.retain(|_, times| {
+            times
+                .back()
+                .map_or(false, |t| now.signed_duration_since(*t) < window)
+        });
+
+        let times = self.posts.entry(key.to_string()).or_default();
+        while times
+            .front()
+            .map_or(false, |t| now.signed_duration_since(*t) >= window)
+        {
+            times.pop_front();
+        }
+        if times.len() >= self.config.rate_limit {
+            return Err(Error::ChatRateLimited);
+        }
+        times.push_back(now);
+        Ok(())
+    }
+
+    fn append(&self, entry: ChatLogEntry) {
+        if let Some(log) = &self.log {
+            log.append(entry);
+        }
+    }
+
+    fn load_log(&mut self, path: &Path) {
+        for entry in journal::read(path) {
+            match entry {
+                ChatLogEntry::Message(message) => {
+                    self.history.push_back(message);
+                    while self.history.len() > self.config.history {
+                        self.history.pop_front();
+                    }
+                }
+                ChatLogEntry::Deleted { id, .. } => self.history.retain(|message| message.id != id),
+            }
+        }
+        tracing::info!("{} chat messages loaded from the log", self.history.len());
+    }
+}
+
+fn blocklist_regex(words: &[String]) -> Option<Regex> {
+    if words.is_empty() {
+        return None;
+    }
+    let words: Vec<String> = words.iter().map(|word| regex::escape(word)).collect();
+    match Regex::new(format!(r"(?i)\b(?:{})\b", words.join("|")).as_str()) {
+        Ok(regex) => Some(regex),
+        Err(err) => {
+            tracing::warn!("ignoring the chat blocklist: {}", err);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn listener(id: &str, nickname: Option<&str>) -> Listener {
+        Listener {
+            id: id.to_string(),
+            nickname: nickname.map(str::to_string),
+            avatar_seed: None,
+            connected_at: now(),
+            connected_secs: 0,
+            listening_secs: 0,
+        }
+    }
+
+    fn now() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2026, 10, 19, 12, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn refuses_invalid_messages() {
+        let mut chat = Chat::new(
+            ChatConfig {
+                max_chars: 5,
+                ..ChatConfig::default()
+            },
+            Arc::default(),
+        );
+        let robo = listener("a", Some("robo"));
+        assert!(matches!(
+            chat.post(&listener("a", None), "10.0.0.1", "hi", None, now()),
+            Err(Error::ChatNicknameRequired)
+        ));
+        assert!(matches!(
+            chat.post(&robo, "10.0.0.1", "   ", None, now()),
+            Err(Error::ChatInvalidMessage(5))
+        ));
+        assert!(matches!(
+            chat.post(&robo, "10.0.0.1", "hello!", None, now()),
+            Err(Error::ChatInvalidMessage(5))
+        ));
+        assert_eq!(
+            chat.post(&robo, "10.0.0.1", " hi ", None, now())
+                .unwrap()
+                .text,
+            "hi"
+        );
+
+        let mut chat = Chat::new(
+            ChatConfig {
+                enabled: false,
+                ..ChatConfig::default()
+            },
+            Arc::default(),
+        );
+        assert!(matches!(
+            chat.post(&robo, "10.0.0.1", "hi", None, now()),
+            Err(Error::ChatDisabled)
+        ));
+    }
+
+    #[test]
+    fn masks_blocked_words() {
+        let chat = Chat::new(
+            ChatConfig {
+                blocklist: vec![String::from("darn"), String::from("a.b")],
+                ..ChatConfig::default()
+            },
+            Arc::default(),
+        );
+        assert_eq!(chat.mask("Darn it, darned"), "**** it, darned");
+        assert_eq!(chat.mask("a.b axb"), "*** axb");
+        assert_eq!(chat.mask("fine"), "fine");
+    }
+
+    #[test]
+    fn rate_limits_listeners_across_sessions() {
+        let mut chat = Chat::new(
+            ChatConfig {
+                rate_limit: 2,
+                rate_window_secs: 10,
+                ..ChatConfig::default()
+            },
+            Arc::default(),
+        );
+        let first = listener("a", Some("robo"));
+        let reconnected = listener("b", Some("robo"));
+        assert!(chat.post(&first, "10.0.0.1", "one", None, now()).is_ok());
+        assert!(chat
+            .post(&reconnected, "10.0.0.1", "two", None, now())
+            .is_ok());
+        assert!(matches!(
+            chat.post(&reconnected, "10.0.0.1", "three", None, now()),
+            Err(Error::ChatRateLimited)
+        ));
+        assert!(chat.post(&first, "10.0.0.2", "other", None, now()).is_ok());
+
+        let later = now() + Duration::seconds(10);
+        assert!(chat
+            .post(&reconnected, "10.0.0.1", "four", None, later)
+            .is_ok());
+    }
+
+    #[test]
+    fn mutes_the_author_of_a_message() {
+        let mut chat = Chat::new(ChatConfig::default(), Arc::default());
+        let robo = listener("a", Some("robo"));
+        let message = chat.post(&robo, "10.0.0.1", "spam", None, now()).unwrap();
+
+        let (_, until) = chat.mute(message.id.as_str(), Some(60), now()).unwrap();
+        assert_eq!(until, now() + Duration::seconds(60));
+        assert!(matches!(
+            chat.post(
+                &listener("b", Some("robo")),
+                "10.0.0.1",
+                "more",
+                None,
+                now()
+            ),
+            Err(Error::ChatMuted(61))
+        ));
+        assert!(chat.post(&robo, "10.0.0.1", "sorry", None, until).is_ok());
+        assert!(matches!(
+            chat.mute("unknown", None, now()),
+            Err(Error::ChatMessageNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn keeps_the_last_messages() {
+        let mut chat = Chat::new(
+            ChatConfig {
+                history: 2,
+                rate_limit: 10,
+                ..ChatConfig::default()
+            },
+            Arc::default(),
+        );
+        let robo = listener("a", Some("robo"));
+        for text in ["one", "two", "three"] {
+            chat.post(&robo, "10.0.0.1", text, None, now()).unwrap();
+        }
+        let texts: Vec<String> = chat.history().into_iter().map(|m| m.text).collect();
+        assert_eq!(texts, vec!["two", "three"]);
+
+        let id = chat.history()[0].id.clone();
+        assert_eq!(chat.delete(id.as_str(), now()).unwrap().text, "two");
+        assert_eq!(chat.history().len(), 1);
+        assert!(matches!(
+            chat.delete(id.as_str(), now()),
+            Err(Error::ChatMessageNotFound(_))
+        ));
+    }
+}
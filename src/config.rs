@@ -0,0 +1,614 @@
+use crate::sources::SourceSpec;
+use std::{collections::HashMap, env, net::IpAddr, path::PathBuf, str::FromStr};
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub station_name: String,
+    pub sources: SourcesConfig,
+    pub moderator_token: Option<String>,
+    // Admin bearer tokens, mapped to the name used in the audit log
+    pub admin_tokens: HashMap<String, String>,
+    // How long past the current track's end before the station is considered stalled
+    pub readiness_grace_secs: i64,
+    pub requests: RequestsConfig,
+    pub skip: SkipConfig,
+    pub client_queue: ClientQueueConfig,
+    pub listeners: ListenersConfig,
+    pub prefetch: PrefetchConfig,
+    pub library: LibraryConfig,
+    pub audio_cache: AudioCacheConfig,
+    pub failover: FailoverConfig,
+    pub breaker: BreakerConfig,
+    pub supervisor: SupervisorConfig,
+    pub chat: ChatConfig,
+    pub history: HistoryConfig,
+    pub reactions: ReactionsConfig,
+    pub stats: StatsConfig,
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        let library = LibraryConfig::from_env();
+        let sources = SourcesConfig::from_env(&library);
+        if sources.sources.is_empty() {
+            panic!(
+                "none of $ROBO_RADIO_SOURCES, $ROBO_RADIO_SOUNDCLOUD_PLAYLIST_ID or $ROBO_RADIO_LIBRARY_DIRS is set"
+            );
+        }
+
+        Self {
+            station_name: env::var("ROBO_RADIO_STATION_NAME")
+                .unwrap_or_else(|_| String::from("robo_radio")),
+            sources,
+            moderator_token: env::var("ROBO_RADIO_MODERATOR_TOKEN").ok(),
+            admin_tokens: parse_admin_tokens(
+                env::var("ROBO_RADIO_ADMIN_TOKENS")
+                    .unwrap_or_default()
+                    .as_str(),
+            ),
+            readiness_grace_secs: env_or("ROBO_RADIO_READINESS_GRACE_SECS", 30),
+            requests: RequestsConfig::from_env(),
+            skip: SkipConfig::from_env(),
+            client_queue: ClientQueueConfig::from_env(),
+            listeners: ListenersConfig::from_env(),
+            prefetch: PrefetchConfig::from_env(),
+            library,
+            audio_cache: AudioCacheConfig::from_env(),
+            failover: FailoverConfig::from_env(),
+            breaker: BreakerConfig::from_env(),
+            supervisor: SupervisorConfig::from_env(),
+            chat: ChatConfig::from_env(),
+            history: HistoryConfig::from_env(),
+            reactions: ReactionsConfig::from_env(),
+            stats: StatsConfig::from_env(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RequestsConfig {
+    pub enabled: bool,
+    pub allow_urls: bool,
+    pub moderation: bool,
+    pub max_queue: usize,
+    pub per_client_limit: usize,
+    pub per_client_window_secs: i64,
+    pub cooldown_secs: i64,
+}
+
+impl RequestsConfig {
+    pub fn from_env() -> Self {
+        Self {
+            enabled: env_or("ROBO_RADIO_REQUESTS_ENABLED", true),
+            allow_urls: env_or("ROBO_RADIO_REQUESTS_ALLOW_URLS", false),
+            moderation: env_or("ROBO_RADIO_REQUESTS_MODERATION", false),
+            max_queue: env_or("ROBO_RADIO_REQUESTS_MAX_QUEUE", 20),
+            per_client_limit: env_or("ROBO_RADIO_REQUESTS_PER_CLIENT", 3),
+            per_client_window_secs: env_or("ROBO_RADIO_REQUESTS_WINDOW_SECS", 600),
+            cooldown_secs: env_or("ROBO_RADIO_REQUESTS_COOLDOWN_SECS", 3600),
+        }
+    }
+}
+
+impl Default for RequestsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            allow_urls: false,
+            moderation: false,
+            max_queue: 20,
+            per_client_limit: 3,
+            per_client_window_secs: 600,
+            cooldown_secs: 3600,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SkipConfig {
+    // Share of the current listeners that must vote to skip a track
+    pub vote_ratio: f64,
+    pub min_listeners: usize,
+}
+
+impl SkipConfig {
+    pub fn from_env() -> Self {
+        Self {
+            vote_ratio: env_or("ROBO_RADIO_SKIP_VOTE_RATIO", 0.5),
+            min_listeners: env_or("ROBO_RADIO_SKIP_MIN_LISTENERS", 3),
+        }
+    }
+}
+
+impl Default for SkipConfig {
+    fn default() -> Self {
+        Self {
+            vote_ratio: 0.5,
+            min_listeners: 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ClientQueueConfig {
+    // Max messages waiting to be sent to a single client
+    pub depth: usize,
+    // How long a client's queue can stay full before it gets disconnected
+    pub evict_after_secs: u64,
+}
+
+impl ClientQueueConfig {
+    pub fn from_env() -> Self {
+        Self {
+            depth: env_or("ROBO_RADIO_CLIENT_QUEUE_DEPTH", 64),
+            evict_after_secs: env_or("ROBO_RADIO_CLIENT_EVICT_SECS", 30),
+        }
+    }
+}
+
+impl Default for ClientQueueConfig {
+    fn default() -> Self {
+        Self {
+            depth: 64,
+            evict_after_secs: 30,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ListenersConfig {
+    // Reverse proxies whose `X-Forwarded-For` header is trusted to give the listener's ip
+    pub trusted_proxies: Vec<IpAddr>,
+    // Tell listeners apart by ip rather than by connection, for the rate limits, the reactions and
+    // the daily uniques. Only fit when listeners don't share ips, i.e. no NAT nor untrusted proxy
+    pub key_by_ip: bool,
+}
+
+impl ListenersConfig {
+    pub fn from_env() -> Self {
+        Self {
+            trusted_proxies: env::var("ROBO_RADIO_TRUSTED_PROXIES")
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|ip| !ip.is_empty())
+                .filter_map(|ip| match ip.parse() {
+                    Ok(ip) => Some(ip),
+                    Err(_) => {
+                        tracing::warn!("invalid ip `{}` in $ROBO_RADIO_TRUSTED_PROXIES", ip);
+                        None
+                    }
+                })
+                .collect(),
+            key_by_ip: env_or("ROBO_RADIO_KEY_LISTENERS_BY_IP", false),
+        }
+    }
+
+    // The listener's ip: the peer's, unless it's a trusted proxy, then the last hop of
+    // `X-Forwarded-For` that's not a trusted proxy itself.
+    pub fn client_ip(&self, peer: IpAddr, forwarded_for: Option<&str>) -> IpAddr {
+        let mut ip = peer;
+        for hop in forwarded_for.unwrap_or_default().rsplit(',') {
+            if !self.trusted_proxies.contains(&ip) {
+                break;
+            }
+            match hop.trim().parse() {
+                Ok(hop) => ip = hop,
+                Err(_) => break,
+            }
+        }
+        ip
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PrefetchConfig {
+    // How many upcoming tracks are resolved ahead of time, 0 disables prefetching
+    pub tracks: usize,
+    // How long a resolved stream url can be used before resolving it again
+    pub max_age_secs: i64,
+}
+
+impl PrefetchConfig {
+    pub fn from_env() -> Self {
+        Self {
+            tracks: env_or("ROBO_RADIO_PREFETCH_TRACKS", 2),
+            max_age_secs: env_or("ROBO_RADIO_PREFETCH_MAX_AGE_SECS", 600),
+        }
+    }
+}
+
+impl Default for PrefetchConfig {
+    fn default() -> Self {
+        Self {
+            tracks: 2,
+            max_age_secs: 600,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SourcesConfig {
+    pub sources: Vec<SourceSpec>,
+    // How often the SoundCloud sources are fetched again
+    pub refresh_secs: u64,
+}
+
+impl SourcesConfig {
+    // The playlist, if any, comes first, then the other sources and the local library
+    pub fn from_env(library: &LibraryConfig) -> Self {
+        let mut sources = vec![];
+        if let Ok(playlist) = env::var("ROBO_RADIO_SOUNDCLOUD_PLAYLIST_ID") {
+            match SourceSpec::playlist(playlist.as_str()) {
+                Ok(source) => sources.push(source),
+                Err(err) => tracing::warn!("ignoring playlist: {}", err),
+            }
+        }
+        for entry in env::var("ROBO_RADIO_SOURCES")
+            .unwrap_or_default()
+            .split(',')
+        {
+            if entry.trim().is_empty() {
+                continue;
+            }
+            match entry.parse() {
+                Ok(source) => sources.push(source),
+                Err(err) => tracing::warn!("ignoring source: {}", err),
+            }
+        }
+        if !library.dirs.is_empty() && !sources.iter().any(SourceSpec::is_library) {
+            sources.push(SourceSpec::library(library.weight));
+        }
+
+        Self {
+            sources,
+            refresh_secs: env_or("ROBO_RADIO_SOURCES_REFRESH_SECS", 900),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct LibraryConfig {
+    // Directories scanned for audio files, none disables the local library
+    pub dirs: Vec<PathBuf>,
+    pub rescan_secs: u64,
+    // Weight of the library among the station's sources
+    pub weight: u32,
+}
+
+impl LibraryConfig {
+    pub fn from_env() -> Self {
+        Self {
+            dirs: env_dirs("ROBO_RADIO_LIBRARY_DIRS"),
+            rescan_secs: env_or("ROBO_RADIO_LIBRARY_RESCAN_SECS", 60),
+            weight: env_or("ROBO_RADIO_LIBRARY_WEIGHT", 1),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AudioCacheConfig {
+    pub dir: PathBuf,
+    // Size limit of the cached audio, 0 disables the cache
+    pub max_mb: u64,
+}
+
+impl AudioCacheConfig {
+    pub fn from_env() -> Self {
+        Self {
+            dir: env::var("ROBO_RADIO_CACHE_DIR")
+                .map(PathBuf::from)
+                .unwrap_or_else(|_| env::temp_dir().join("robo_radio")),
+            max_mb: env_or("ROBO_RADIO_CACHE_MAX_MB", 512),
+        }
+    }
+}
+
+impl Default for AudioCacheConfig {
+    fn default() -> Self {
+        Self {
+            dir: env::temp_dir().join("robo_radio"),
+            max_mb: 512,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FailoverConfig {
+    // Directories of the emergency playlist, aired when the primary source is down. The audio
+    // cache is used when there's none.
+    pub dirs: Vec<PathBuf>,
+    // Consecutive outage errors of the primary source before failing over
+    pub threshold: usize,
+    // How often the primary source is probed while on the emergency playlist
+    pub probe_secs: u64,
+}
+
+impl FailoverConfig {
+    pub fn from_env() -> Self {
+        Self {
+            dirs: env_dirs("ROBO_RADIO_FAILOVER_DIRS"),
+            threshold: env_or("ROBO_RADIO_FAILOVER_THRESHOLD", 3),
+            probe_secs: env_or("ROBO_RADIO_FAILOVER_PROBE_SECS", 30),
+        }
+    }
+}
+
+impl Default for FailoverConfig {
+    fn default() -> Self {
+        Self {
+            dirs: vec![],
+            threshold: 3,
+            probe_secs: 30,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BreakerConfig {
+    // Consecutive failed SoundCloud calls before they get suspended
+    pub threshold: usize,
+    // How long calls are suspended before probing SoundCloud again, unless it asks for longer
+    pub open_secs: u64,
+}
+
+impl BreakerConfig {
+    pub fn from_env() -> Self {
+        Self {
+            threshold: env_or("ROBO_RADIO_BREAKER_THRESHOLD", 5),
+            open_secs: env_or("ROBO_RADIO_BREAKER_OPEN_SECS", 30),
+        }
+    }
+}
+
+impl Default for BreakerConfig {
+    fn default() -> Self {
+        Self {
+            threshold: 5,
+            open_secs: 30,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SupervisorConfig {
+    // Delay before restarting a crashed playout task, doubled after each crash in a row
+    pub backoff_secs: u64,
+    pub max_backoff_secs: u64,
+}
+
+impl SupervisorConfig {
+    pub fn from_env() -> Self {
+        Self {
+            backoff_secs: env_or("ROBO_RADIO_PLAYOUT_RESTART_BACKOFF_SECS", 1),
+            max_backoff_secs: env_or("ROBO_RADIO_PLAYOUT_RESTART_MAX_BACKOFF_SECS", 60),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ChatConfig {
+    pub enabled: bool,
+    // Last messages replayed to newcomers
+    pub history: usize,
+    pub max_chars: usize,
+    // Per-listener rate limit: at most `rate_limit` messages every `rate_window_secs`
+    pub rate_limit: usize,
+    pub rate_window_secs: i64,
+    // How long a listener stays muted, unless the moderator says otherwise
+    pub mute_secs: i64,
+    // Words masked in messages and nicknames, case insensitive
+    pub blocklist: Vec<String>,
+    // Where messages are logged as JSON lines, none keeps them in memory only
+    pub log: Option<PathBuf>,
+}
+
+impl ChatConfig {
+    pub fn from_env() -> Self {
+        Self {
+            enabled: env_or("ROBO_RADIO_CHAT_ENABLED", true),
+            history: env_or("ROBO_RADIO_CHAT_HISTORY", 50),
+            max_chars: env_or("ROBO_RADIO_CHAT_MAX_CHARS", 500),
+            rate_limit: env_or("ROBO_RADIO_CHAT_RATE_LIMIT", 5),
+            rate_window_secs: env_or("ROBO_RADIO_CHAT_RATE_WINDOW_SECS", 10),
+            mute_secs: env_or("ROBO_RADIO_CHAT_MUTE_SECS", 600),
+            blocklist: env::var("ROBO_RADIO_CHAT_BLOCKLIST")
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|word| !word.is_empty())
+                .map(str::to_string)
+                .collect(),
+            log: env::var("ROBO_RADIO_CHAT_LOG").ok().map(PathBuf::from),
+        }
+    }
+}
+
+impl Default for ChatConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            history: 50,
+            max_chars: 500,
+            rate_limit: 5,
+            rate_window_secs: 10,
+            mute_secs: 600,
+            blocklist: vec![],
+            log: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HistoryConfig {
+    // Last plays kept in memory
+    pub size: usize,
+    // Where plays are logged as JSON lines, none keeps them in memory only
+    pub log: Option<PathBuf>,
+}
+
+impl HistoryConfig {
+    pub fn from_env() -> Self {
+        Self {
+            size: env_or("ROBO_RADIO_HISTORY_SIZE", 100),
+            log: env::var("ROBO_RADIO_HISTORY_LOG").ok().map(PathBuf::from),
+        }
+    }
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        Self {
+            size: 100,
+            log: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ReactionsConfig {
+    // How much reactions weigh on the rotation, from 0 (not at all) to 0.9
+    pub weighting: f64,
+    // Reactions a track needs before it's ranked or weighted
+    pub min_reactions: u64,
+}
+
+impl ReactionsConfig {
+    pub fn from_env() -> Self {
+        Self {
+            weighting: env_or("ROBO_RADIO_REACTIONS_WEIGHTING", 0.5),
+            min_reactions: env_or("ROBO_RADIO_REACTIONS_MIN", 3),
+        }
+    }
+}
+
+impl Default for ReactionsConfig {
+    fn default() -> Self {
+        Self {
+            weighting: 0.5,
+            min_reactions: 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct StatsConfig {
+    // How often the listeners are counted
+    pub sample_secs: u64,
+    // How long per minute and per hour rollups are kept, per day ones are kept forever
+    pub minutes_retention_hours: i64,
+    pub hours_retention_days: i64,
+    // Where the rollups are saved, none keeps them in memory only
+    pub file: Option<PathBuf>,
+}
+
+impl StatsConfig {
+    pub fn from_env() -> Self {
+        Self {
+            sample_secs: env_or("ROBO_RADIO_STATS_SAMPLE_SECS", 60),
+            minutes_retention_hours: env_or("ROBO_RADIO_STATS_MINUTES_RETENTION_HOURS", 48),
+            hours_retention_days: env_or("ROBO_RADIO_STATS_HOURS_RETENTION_DAYS", 90),
+            file: env::var("ROBO_RADIO_STATS_FILE").ok().map(PathBuf::from),
+        }
+    }
+}
+
+impl Default for StatsConfig {
+    fn default() -> Self {
+        Self {
+            sample_secs: 60,
+            minutes_retention_hours: 48,
+            hours_retention_days: 90,
+            file: None,
+        }
+    }
+}
+
+// Parses a comma separated list of directories
+fn env_dirs(key: &str) -> Vec<PathBuf> {
+    env::var(key)
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .collect()
+}
+
+// Parses a comma separated list of `name:token` (or just `token`) entries.
+fn parse_admin_tokens(value: &str) -> HashMap<String, String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| match entry.split_once(':') {
+            Some((name, token)) => (token.to_string(), name.to_string()),
+            None => (entry.to_string(), String::from("admin")),
+        })
+        .collect()
+}
+
+// Reads `key` from the environment, falling back to `default` when it's unset or unparsable.
+fn env_or<T: FromStr>(key: &str, default: T) -> T {
+    match env::var(key) {
+        Ok(v) => v.parse().unwrap_or_else(|_| {
+            tracing::warn!("invalid value `{}` for ${}, using default", v, key);
+            default
+        }),
+        Err(_) => default,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    fn behind_proxy() -> ListenersConfig {
+        ListenersConfig {
+            trusted_proxies: vec![ip("10.0.0.1"), ip("10.0.0.2")],
+            key_by_ip: false,
+        }
+    }
+
+    #[test]
+    fn ignores_forwarded_for_from_untrusted_peers() {
+        let config = behind_proxy();
+        assert_eq!(
+            config.client_ip(ip("203.0.113.7"), Some("198.51.100.1")),
+            ip("203.0.113.7")
+        );
+        assert_eq!(
+            ListenersConfig::default().client_ip(ip("10.0.0.1"), Some("198.51.100.1")),
+            ip("10.0.0.1")
+        );
+    }
+
+    #[test]
+    fn takes_the_last_untrusted_hop_from_trusted_proxies() {
+        let config = behind_proxy();
+        // The first hop is whatever the client claims, only the proxies' own additions count
+        assert_eq!(
+            config.client_ip(ip("10.0.0.1"), Some("192.0.2.9, 198.51.100.1, 10.0.0.2")),
+            ip("198.51.100.1")
+        );
+        assert_eq!(config.client_ip(ip("10.0.0.1"), Some("::1")), ip("::1"));
+    }
+
+    #[test]
+    fn falls_back_to_the_proxy_without_a_usable_forwarded_for() {
+        let config = behind_proxy();
+        assert_eq!(config.client_ip(ip("10.0.0.1"), None), ip("10.0.0.1"));
+        assert_eq!(
+            config.client_ip(ip("10.0.0.1"), Some("unknown")),
+            ip("10.0.0.1")
+        );
+        assert_eq!(
+            config.client_ip(ip("10.0.0.1"), Some("198.51.100.1, garbage")),
+            ip("10.0.0.1")
+        );
+    }
+}
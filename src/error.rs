@@ -1,21 +1,188 @@
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
 use reqwest::Error as ReqwestError;
-use reqwest_middleware::Error as MiddlewareReqwestError;
+use serde::Serialize;
+use serde_json::json;
 use thiserror::Error;
 
+// How a failed SoundCloud call should be handled
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SoundcloudErrorKind {
+    // The client id or the stream url isn't accepted anymore
+    Auth,
+    // The resource doesn't exist, or isn't available to us
+    NotFound,
+    // Too many requests, back off for a while
+    RateLimited,
+    // SoundCloud or the network is having trouble, worth retrying later
+    Transient,
+}
+
 #[derive(Error, Debug)]
 pub enum Error {
     #[error("error parsing JSON")]
     SoundcloudJsonParseError(String),
-    #[error(transparent)]
-    SoundcloudRequestError(#[from] MiddlewareReqwestError),
+    #[error("error requesting SoundCloud: {0}")]
+    SoundcloudRequestError(ReqwestError),
     #[error("error from SoundCloud response with code `{0}`")]
     SoundcloudResponseError(u16),
+    #[error("SoundCloud denied access with code `{0}`")]
+    SoundcloudAuthError(u16),
+    #[error("not found on SoundCloud")]
+    SoundcloudNotFound,
+    #[error("rate limited by SoundCloud")]
+    SoundcloudRateLimited(Option<u64>),
+    #[error("SoundCloud calls are suspended for {0}s")]
+    SoundcloudCircuitOpen(u64),
     #[error("error from SoundCloud text response")]
     SoundcloudTextResponseError(#[from] ReqwestError),
-    #[error("track from SoundCloud with title `{0}` is incomplete")]
-    SoundcloudIncompleteTrack(String),
+    #[error("track `{0}` from SoundCloud has no `{1}`")]
+    SoundcloudIncompleteTrack(u64, &'static str),
+    #[error("invalid `{0}` header for SoundCloud")]
+    SoundcloudInvalidHeader(&'static str),
     #[error(transparent)]
     WebSocketError(#[from] axum::Error),
     #[error("can't update soundcloud client id")]
     SoundcloudClientIdUpdateError(String),
+    #[error("song requests are disabled")]
+    RequestsDisabled,
+    #[error("`{0}` is not a valid track id or SoundCloud URL")]
+    RequestInvalidTrack(String),
+    #[error("requests by SoundCloud URL are not allowed")]
+    RequestUrlNotAllowed,
+    #[error("track `{0}` is not in the station's playlist")]
+    RequestTrackNotInPlaylist(u64),
+    #[error("track `{0}` has already been requested")]
+    RequestDuplicate(u64),
+    #[error("track `{0}` has been played recently")]
+    RequestCooldown(u64),
+    #[error("too many requests, try again later")]
+    RequestRateLimited,
+    #[error("the requests queue is full")]
+    RequestQueueFull,
+    #[error("request `{0}` not found")]
+    RequestNotFound(String),
+    #[error("skip voting needs at least {0} listeners")]
+    SkipNotEnoughListeners(usize),
+    #[error("already voted to skip this track")]
+    SkipAlreadyVoted,
+    #[error("the playout task is not available")]
+    PlayoutUnavailable,
+    #[error("command not allowed while playout is `{0}`")]
+    PlayoutInvalidCommand(String),
+    #[error("listener `{0}` not found")]
+    ListenerNotFound(String),
+    #[error("track `{0}` is not in the upcoming queue")]
+    QueueTrackNotFound(u64),
+    #[error("forbidden")]
+    Forbidden,
+    #[error("unauthorized")]
+    Unauthorized,
+    #[error("the playlist has no tracks")]
+    PlaylistEmpty,
+    #[error("unable to read audio file `{0}`")]
+    LibraryFileError(String),
+    #[error("track `{0}` can't be aired without its `{1}`")]
+    TrackIncomplete(u64, &'static str),
+    #[error("media `{0}` not found")]
+    MediaNotFound(u64),
+    #[error("`{0}` is not a SoundCloud permalink")]
+    InvalidPermalink(String),
+    #[error("`{0}` is not a valid source")]
+    InvalidSource(String),
+    #[error("`{0}` must be at most {1} characters, without control characters")]
+    InvalidProfile(&'static str, usize),
+    #[error("the chat is disabled")]
+    ChatDisabled,
+    #[error("set a nickname to chat")]
+    ChatNicknameRequired,
+    #[error("messages must have between 1 and {0} characters")]
+    ChatInvalidMessage(usize),
+    #[error("too many messages, slow down")]
+    ChatRateLimited,
+    #[error("muted for {0}s")]
+    ChatMuted(i64),
+    #[error("chat message `{0}` not found")]
+    ChatMessageNotFound(String),
+    #[error("nothing is on air")]
+    ReactionNotOnAir,
+    #[error("track `{0}` is not on air")]
+    ReactionTrackNotOnAir(u64),
+    #[error("invalid stats query: {0}")]
+    InvalidStatsQuery(String),
+    #[error(transparent)]
+    IoError(#[from] std::io::Error),
+}
+
+impl Error {
+    // Classification of SoundCloud errors, `None` for the other ones
+    pub fn soundcloud_kind(&self) -> Option<SoundcloudErrorKind> {
+        match self {
+            Error::SoundcloudAuthError(_) => Some(SoundcloudErrorKind::Auth),
+            Error::SoundcloudNotFound => Some(SoundcloudErrorKind::NotFound),
+            Error::SoundcloudRateLimited(_) => Some(SoundcloudErrorKind::RateLimited),
+            Error::SoundcloudRequestError(_)
+            | Error::SoundcloudTextResponseError(_)
+            | Error::SoundcloudCircuitOpen(_) => Some(SoundcloudErrorKind::Transient),
+            Error::SoundcloudResponseError(code) if *code >= 500 || *code == 408 => {
+                Some(SoundcloudErrorKind::Transient)
+            }
+            _ => None,
+        }
+    }
+
+    // Whether SoundCloud as a whole is unavailable, rather than a single resource
+    pub fn is_outage(&self) -> bool {
+        matches!(
+            self.soundcloud_kind(),
+            Some(SoundcloudErrorKind::RateLimited | SoundcloudErrorKind::Transient)
+        )
+    }
+}
+
+impl IntoResponse for Error {
+    fn into_response(self) -> Response {
+        let status = match self {
+            Error::RequestsDisabled => StatusCode::SERVICE_UNAVAILABLE,
+            Error::RequestInvalidTrack(_)
+            | Error::InvalidSource(_)
+            | Error::InvalidPermalink(_)
+            | Error::InvalidProfile(..)
+            | Error::ChatNicknameRequired
+            | Error::ChatInvalidMessage(_)
+            | Error::InvalidStatsQuery(_)
+            | Error::RequestUrlNotAllowed
+            | Error::RequestTrackNotInPlaylist(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            Error::RequestDuplicate(_) | Error::RequestCooldown(_) => StatusCode::CONFLICT,
+            Error::RequestRateLimited | Error::RequestQueueFull | Error::ChatRateLimited => {
+                StatusCode::TOO_MANY_REQUESTS
+            }
+            Error::ChatDisabled => StatusCode::SERVICE_UNAVAILABLE,
+            Error::ChatMuted(_) => StatusCode::FORBIDDEN,
+            Error::RequestNotFound(_) => StatusCode::NOT_FOUND,
+            Error::SkipNotEnoughListeners(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            Error::SkipAlreadyVoted | Error::ReactionNotOnAir | Error::ReactionTrackNotOnAir(_) => {
+                StatusCode::CONFLICT
+            }
+            Error::PlayoutUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+            Error::PlayoutInvalidCommand(_) => StatusCode::CONFLICT,
+            Error::ListenerNotFound(_)
+            | Error::QueueTrackNotFound(_)
+            | Error::ChatMessageNotFound(_) => StatusCode::NOT_FOUND,
+            Error::Forbidden => StatusCode::FORBIDDEN,
+            Error::Unauthorized => StatusCode::UNAUTHORIZED,
+            Error::MediaNotFound(_) | Error::SoundcloudNotFound => StatusCode::NOT_FOUND,
+            Error::SoundcloudRateLimited(_) | Error::SoundcloudCircuitOpen(_) => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            Error::LibraryFileError(_) | Error::IoError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            _ => StatusCode::BAD_GATEWAY,
+        };
+
+        (status, Json(json!({ "error": self.to_string() }))).into_response()
+    }
 }
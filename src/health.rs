@@ -0,0 +1,178 @@
+use crate::{media_player::Source, web::playout::PlayoutState};
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+use std::{fmt::Display, sync::Mutex};
+
+const DEFAULT_GRACE_SECS: i64 = 30;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PlayoutTask {
+    NotStarted,
+    Running,
+    Stopped,
+    Crashed,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LastError {
+    pub message: String,
+    pub at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HealthState {
+    pub started_at: DateTime<Utc>,
+    pub playlist_loaded: bool,
+    // The emergency playlist stands in for the sources while they're down
+    pub source: Source,
+    pub track_loaded: bool,
+    pub playout_task: PlayoutTask,
+    // Restarts of the playout task after a crash
+    pub playout_restarts: u64,
+    pub playout_state: PlayoutState,
+    pub track_ends_at: Option<DateTime<Utc>>,
+    pub last_error: Option<LastError>,
+    pub grace_secs: i64,
+}
+
+// Tracks the station's liveness, kept apart from the station itself so that it can be checked
+// even when the station lock is held by a stalled task.
+#[derive(Debug)]
+pub struct Health {
+    state: Mutex<HealthState>,
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Self::new(DEFAULT_GRACE_SECS)
+    }
+}
+
+impl Health {
+    pub fn new(grace_secs: i64) -> Self {
+        Self {
+            state: Mutex::new(HealthState {
+                started_at: Utc::now(),
+                playlist_loaded: false,
+                source: Source::Primary,
+                track_loaded: false,
+                playout_task: PlayoutTask::NotStarted,
+                playout_restarts: 0,
+                playout_state: PlayoutState::Playing,
+                track_ends_at: None,
+                last_error: None,
+                grace_secs,
+            }),
+        }
+    }
+
+    pub fn playlist_loaded(&self) {
+        self.state.lock().unwrap().playlist_loaded = true;
+    }
+
+    pub fn source(&self, source: Source) {
+        self.state.lock().unwrap().source = source;
+    }
+
+    pub fn track_loaded(&self) {
+        self.state.lock().unwrap().track_loaded = true;
+    }
+
+    pub fn playout_task(&self, task: PlayoutTask) {
+        self.state.lock().unwrap().playout_task = task;
+    }
+
+    pub fn playout_restarted(&self) {
+        self.state.lock().unwrap().playout_restarts += 1;
+    }
+
+    pub fn playout_state(&self, playout_state: PlayoutState) {
+        self.state.lock().unwrap().playout_state = playout_state;
+    }
+
+    pub fn on_air(&self, ends_at: DateTime<Utc>) {
+        self.state.lock().unwrap().track_ends_at = Some(ends_at);
+    }
+
+    pub fn record_error(&self, err: &dyn Display) {
+        self.state.lock().unwrap().last_error = Some(LastError {
+            message: err.to_string(),
+            at: Utc::now(),
+        });
+    }
+
+    pub fn snapshot(&self) -> HealthState {
+        self.state.lock().unwrap().clone()
+    }
+
+    // Returns why the station is not ready, if that's the case
+    pub fn readiness(&self) -> Result<(), String> {
+        let state = self.snapshot();
+        if !state.playlist_loaded && state.source != Source::Fallback {
+            return Err(String::from("playlist not loaded"));
+        }
+        if !state.track_loaded {
+            return Err(String::from("no track loaded"));
+        }
+        if state.playout_task != PlayoutTask::Running {
+            return Err(format!("playout task is {:?}", state.playout_task).to_lowercase());
+        }
+
+        if let (true, Some(ends_at)) = (state.playout_state.is_on_air(), state.track_ends_at) {
+            if Utc::now() > ends_at + Duration::seconds(state.grace_secs) {
+                return Err(format!(
+                    "playout stalled, track should have ended at {}",
+                    ends_at
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn on_air(health: &Health) {
+        health.track_loaded();
+        health.playout_task(PlayoutTask::Running);
+        health.on_air(Utc::now() + Duration::minutes(3));
+    }
+
+    #[test]
+    fn needs_a_playlist_or_the_emergency_one() {
+        let health = Health::default();
+        on_air(&health);
+        assert_eq!(health.readiness(), Err(String::from("playlist not loaded")));
+
+        health.source(Source::Fallback);
+        assert_eq!(health.readiness(), Ok(()));
+
+        health.source(Source::Primary);
+        health.playlist_loaded();
+        assert_eq!(health.readiness(), Ok(()));
+    }
+
+    #[test]
+    fn needs_the_playout_on_time() {
+        let health = Health::default();
+        health.playlist_loaded();
+        on_air(&health);
+        health.playout_task(PlayoutTask::Crashed);
+        assert_eq!(
+            health.readiness(),
+            Err(String::from("playout task is crashed"))
+        );
+
+        health.playout_task(PlayoutTask::Running);
+        health.on_air(Utc::now() - Duration::minutes(1));
+        assert!(health.readiness().is_err());
+
+        // A paused station isn't expected to move on
+        health.playout_state(PlayoutState::Paused);
+        assert_eq!(health.readiness(), Ok(()));
+    }
+}
@@ -0,0 +1,394 @@
+use crate::{
+    config::{HistoryConfig, ReactionsConfig},
+    error::Error,
+    journal::{self, Journal},
+    media_player::{CurrentTrack, Source},
+    stats::Stats,
+};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+};
+
+// How much earlier than planned a play can end without counting as skipped
+const SKIP_TOLERANCE_MS: i64 = 5_000;
+
+// How listeners feel about the track on air
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Reaction {
+    Like,
+    Fire,
+    SkipWorthy,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReactionCounts {
+    pub like: u64,
+    pub fire: u64,
+    pub skip_worthy: u64,
+}
+
+impl ReactionCounts {
+    pub fn total(&self) -> u64 {
+        self.like + self.fire + self.skip_worthy
+    }
+
+    // A fire counts as two likes, and a skip-worthy takes two away
+    pub fn score(&self) -> i64 {
+        self.like as i64 + 2 * self.fire as i64 - 2 * self.skip_worthy as i64
+    }
+
+    fn count(&mut self, reaction: Reaction) -> &mut u64 {
+        match reaction {
+            Reaction::Like => &mut self.like,
+            Reaction::Fire => &mut self.fire,
+            Reaction::SkipWorthy => &mut self.skip_worthy,
+        }
+    }
+
+    fn add(&mut self, other: &ReactionCounts) {
+        self.like += other.like;
+        self.fire += other.fire;
+        self.skip_worthy += other.skip_worthy;
+    }
+}
+
+// A track that went on air
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Play {
+    pub track_id: u64,
+    pub title: String,
+    pub artist: String,
+    pub source: Source,
+    pub started_at: DateTime<Utc>,
+    // Planned length in milliseconds, a play that ended earlier was cut short
+    pub duration: u64,
+    // None while on air
+    pub ended_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub reactions: ReactionCounts,
+}
+
+impl Play {
+    // Whether the play was cut short, by a skip or a pause
+    pub fn skipped(&self) -> bool {
+        let planned_end = self.started_at + Duration::milliseconds(self.duration as i64);
+        self.ended_at.map_or(false, |ended_at| {
+            ended_at < planned_end - Duration::milliseconds(SKIP_TOLERANCE_MS)
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TrackRanking {
+    pub track_id: u64,
+    pub title: String,
+    pub artist: String,
+    pub plays: u64,
+    pub reactions: ReactionCounts,
+    pub score: i64,
+}
+
+#[derive(Debug, Clone, Default)]
+struct TrackTotals {
+    title: String,
+    artist: String,
+    plays: u64,
+    reactions: ReactionCounts,
+}
+
+// The station's plays and the listeners' reactions to them. Finished plays are added to the log,
+// when there's one, and read back on start to keep the rankings.
+#[derive(Debug)]
+pub struct PlayHistory {
+    size: usize,
+    current: Option<Play>,
+    // Listeners' reactions to the current play, one each
+    reactions: HashMap<String, Reaction>,
+    // Last finished plays, newest last
+    recent: VecDeque<Play>,
+    tracks: HashMap<u64, TrackTotals>,
+    log: Option<Journal<Play>>,
+    // Finished plays are counted in the station's statistics
+    stats: Arc<Stats>,
+}
+
+impl PlayHistory {
+    pub fn new(config: &HistoryConfig, stats: Arc<Stats>) -> Self {
+        let mut history = Self {
+            size: config.size,
+            current: None,
+            reactions: HashMap::new(),
+            recent: VecDeque::new(),
+            tracks: HashMap::new(),
+            log: None,
+            stats,
+        };
+        if let Some(path) = &config.log {
+            for play in journal::read(path) {
+                history.record(play);
+            }
+            tracing::info!(
+                "{} tracks loaded from the play history",
+                history.tracks.len()
+            );
+            history.log = Some(Journal::open(path.clone()));
+        }
+        history
+    }
+
+    // Starts a play of the track, unless it's the one already on air, and finishes the previous
+    // one. Returns whether it's a new play.
+    pub fn start(&mut self, track: &CurrentTrack, source: Source) -> bool {
+        if let Some(current) = &self.current {
+            if current.track_id == track.id && current.started_at == track.started_at {
+                return false;
+            }
+        }
+        self.finish(track.started_at);
+        self.current = Some(Play {
+            track_id: track.id,
+            title: track.title.clone(),
+            artist: track.artist.clone(),
+            source,
+            started_at: track.started_at,
+            duration: track.duration,
+            ended_at: None,
+            reactions: ReactionCounts::default(),
+        });
+        true
+    }
+
+    // Sets the listener's reaction to the current play, `track_id` being the track it reacts to
+    pub fn react(
+        &mut self,
+        listener: &str,
+        track_id: Option<u64>,
+        reaction: Reaction,
+    ) -> Result<(u64, ReactionCounts), Error> {
+        let play = self.current.as_mut().ok_or(Error::ReactionNotOnAir)?;
+        if let Some(track_id) = track_id.filter(|id| *id != play.track_id) {
+            return Err(Error::ReactionTrackNotOnAir(track_id));
+        }
+        if let Some(previous) = self.reactions.insert(listener.to_string(), reaction) {
+            *play.reactions.count(previous) -= 1;
+        }
+        *play.reactions.count(reaction) += 1;
+        Ok((play.track_id, play.reactions))
+    }
+
+    pub fn current_reactions(&self) -> Option<(u64, ReactionCounts)> {
+        self.current
+            .as_ref()
+            .map(|play| (play.track_id, play.reactions))
+    }
+
+    // Newest first, starting with the one on air
+    pub fn recent(&self, limit: usize) -> Vec<Play> {
+        self.current
+            .iter()
+            .chain(self.recent.iter().rev())
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+
+    // Best scored tracks among the ones with enough reactions
+    pub fn most_loved(&self, limit: usize, config: &ReactionsConfig) -> Vec<TrackRanking> {
+        let mut ranking: Vec<TrackRanking> = self
+            .tracks
+            .iter()
+            .filter(|(_, totals)| {
+                totals.reactions.total() >= config.min_reactions && totals.reactions.score() > 0
+            })
+            .map(|(track_id, totals)| TrackRanking {
+                track_id: *track_id,
+                title: totals.title.clone(),
+                artist: totals.artist.clone(),
+                plays: totals.plays,
+                reactions: totals.reactions,
+                score: totals.reactions.score(),
+            })
+            .collect();
+        ranking.sort_by(|a, b| b.score.cmp(&a.score).then(b.plays.cmp(&a.plays)));
+        ranking.truncate(limit);
+        ranking
+    }
+
+    // Rotation weights of the tracks with enough reactions, from their average score per play:
+    // above 1 for the liked ones, below for the disliked ones
+    pub fn track_weights(&self, config: &ReactionsConfig) -> HashMap<u64, f64> {
+        let weighting = config.weighting.clamp(0.0, 0.9);
+        if weighting == 0.0 {
+            return HashMap::new();
+        }
+        self.tracks
+            .iter()
+            .filter(|(_, totals)| totals.reactions.total() >= config.min_reactions)
+            .filter_map(|(track_id, totals)| {
+                let score = totals.reactions.score() as f64 / totals.plays.max(1) as f64;
+                let weight = 1.0 + weighting * score / (1.0 + score.abs());
+                (weight != 1.0).then_some((*track_id, weight))
+            })
+            .collect()
+    }
+
+    // Ends the current play, at the latest when it was planned to end
+    fn finish(&mut self, now: DateTime<Utc>) {
+        if let Some(mut play) = self.current.take() {
+            let planned_end = play.started_at + Duration::milliseconds(play.duration as i64);
+            play.ended_at = Some(now.min(planned_end).max(play.started_at));
+            self.reactions.clear();
+            if let Some(log) = &self.log {
+                log.append(play.clone());
+            }
+            self.stats.play_finished(&play);
+            self.record(play);
+        }
+    }
+
+    fn record(&mut self, play: Play) {
+        let totals = self.tracks.entry(play.track_id).or_default();
+        totals.title = play.title.clone();
+        totals.artist = play.artist.clone();
+        totals.plays += 1;
+        totals.reactions.add(&play.reactions);
+
+        self.recent.push_back(play);
+        while self.recent.len() > self.size {
+            self.recent.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::soundcloud::Track;
+    use chrono::TimeZone;
+
+    fn aired(history: &mut PlayHistory, track_id: u64, minute: u32) {
+        let track = Track {
+            id: track_id,
+            permalink_url: None,
+            artwork_url: None,
+            duration: Some(180_000),
+            title: Some(format!("Track {}", track_id)),
+            artist: Some(String::from("Robo")),
+            artist_permalink: None,
+            url: Some(format!("https://example.com/{}.mp3", track_id)),
+            token: None,
+            transcoding: None,
+            metadata: Default::default(),
+        };
+        let started_at = Utc.with_ymd_and_hms(2026, 10, 19, 12, minute, 0).unwrap();
+        let track = CurrentTrack::new(&track, started_at).unwrap();
+        history.start(&track, Source::Primary);
+    }
+
+    fn react(history: &mut PlayHistory, listener: &str, reaction: Reaction) -> ReactionCounts {
+        history.react(listener, None, reaction).unwrap().1
+    }
+
+    fn reactions(min_reactions: u64) -> ReactionsConfig {
+        ReactionsConfig {
+            weighting: 0.5,
+            min_reactions,
+        }
+    }
+
+    #[test]
+    fn scores_fire_twice_and_skip_worthy_against() {
+        let counts = ReactionCounts {
+            like: 3,
+            fire: 2,
+            skip_worthy: 1,
+        };
+        assert_eq!(counts.total(), 6);
+        assert_eq!(counts.score(), 3 + 4 - 2);
+    }
+
+    #[test]
+    fn replaces_a_listener_reaction() {
+        let mut history = PlayHistory::new(&HistoryConfig::default(), Arc::default());
+        aired(&mut history, 1, 0);
+
+        react(&mut history, "10.0.0.1", Reaction::Like);
+        let counts = react(&mut history, "10.0.0.1", Reaction::Fire);
+        assert_eq!(counts.like, 0);
+        assert_eq!(counts.fire, 1);
+        assert_eq!(counts.total(), 1);
+
+        let counts = react(&mut history, "10.0.0.2", Reaction::SkipWorthy);
+        assert_eq!(counts.total(), 2);
+        assert_eq!(counts.score(), 0);
+    }
+
+    #[test]
+    fn refuses_reactions_to_tracks_off_air() {
+        let mut history = PlayHistory::new(&HistoryConfig::default(), Arc::default());
+        assert!(matches!(
+            history.react("10.0.0.1", None, Reaction::Like),
+            Err(Error::ReactionNotOnAir)
+        ));
+
+        aired(&mut history, 1, 0);
+        assert!(matches!(
+            history.react("10.0.0.1", Some(2), Reaction::Like),
+            Err(Error::ReactionTrackNotOnAir(2))
+        ));
+        assert!(history.react("10.0.0.1", Some(1), Reaction::Like).is_ok());
+    }
+
+    #[test]
+    fn ranks_tracks_with_enough_reactions() {
+        let mut history = PlayHistory::new(&HistoryConfig::default(), Arc::default());
+        aired(&mut history, 1, 0);
+        react(&mut history, "10.0.0.1", Reaction::Like);
+        react(&mut history, "10.0.0.2", Reaction::Like);
+        aired(&mut history, 2, 3);
+        react(&mut history, "10.0.0.1", Reaction::Fire);
+        react(&mut history, "10.0.0.2", Reaction::Like);
+        react(&mut history, "10.0.0.3", Reaction::Like);
+        aired(&mut history, 3, 6);
+
+        let ranking = history.most_loved(10, &reactions(3));
+        assert_eq!(ranking.len(), 1);
+        assert_eq!(ranking[0].track_id, 2);
+        assert_eq!(ranking[0].score, 4);
+
+        let ranking = history.most_loved(10, &reactions(2));
+        let ids: Vec<u64> = ranking.iter().map(|r| r.track_id).collect();
+        assert_eq!(ids, vec![2, 1]);
+    }
+
+    #[test]
+    fn weighs_tracks_with_enough_reactions() {
+        let mut history = PlayHistory::new(&HistoryConfig::default(), Arc::default());
+        aired(&mut history, 1, 0);
+        for listener in ["10.0.0.1", "10.0.0.2", "10.0.0.3"] {
+            react(&mut history, listener, Reaction::Fire);
+        }
+        aired(&mut history, 2, 3);
+        for listener in ["10.0.0.1", "10.0.0.2", "10.0.0.3"] {
+            react(&mut history, listener, Reaction::SkipWorthy);
+        }
+        aired(&mut history, 3, 6);
+        react(&mut history, "10.0.0.1", Reaction::Fire);
+        aired(&mut history, 4, 9);
+
+        let weights = history.track_weights(&reactions(3));
+        assert!(weights[&1] > 1.0 && weights[&1] < 1.5);
+        assert!(weights[&2] < 1.0 && weights[&2] > 0.5);
+        assert!(!weights.contains_key(&3));
+
+        let unweighted = ReactionsConfig {
+            weighting: 0.0,
+            min_reactions: 3,
+        };
+        assert!(history.track_weights(&unweighted).is_empty());
+    }
+}
@@ -0,0 +1,76 @@
+use serde::{de::DeserializeOwned, Serialize};
+use std::path::{Path, PathBuf};
+use tokio::{
+    fs::OpenOptions,
+    io::AsyncWriteExt,
+    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+};
+
+// Append-only file of JSON lines, written in background so that nobody waits on the disk
+#[derive(Debug, Clone)]
+pub struct Journal<T> {
+    entries: UnboundedSender<T>,
+}
+
+impl<T: Serialize + Send + 'static> Journal<T> {
+    pub fn open(path: PathBuf) -> Self {
+        let (entries, receiver) = unbounded_channel();
+        tokio::spawn(write_entries(path, receiver));
+        Self { entries }
+    }
+
+    pub fn append(&self, entry: T) {
+        let _ = self.entries.send(entry);
+    }
+}
+
+// Entries written so far, skipping the lines that can't be read back
+pub fn read<T: DeserializeOwned>(path: &Path) -> Vec<T> {
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(err) => {
+            tracing::info!("nothing to read from `{}`: {}", path.display(), err);
+            return vec![];
+        }
+    };
+    content
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .filter_map(|line| match serde_json::from_str(line) {
+            Ok(entry) => Some(entry),
+            Err(err) => {
+                tracing::warn!("skipping line `{}` of `{}`: {}", line, path.display(), err);
+                None
+            }
+        })
+        .collect()
+}
+
+// Appends the entries until the journal is dropped
+async fn write_entries<T: Serialize>(path: PathBuf, mut entries: UnboundedReceiver<T>) {
+    let mut file = match OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .await
+    {
+        Ok(file) => file,
+        Err(err) => {
+            tracing::error!("unable to open `{}`: {}", path.display(), err);
+            return;
+        }
+    };
+    while let Some(entry) = entries.recv().await {
+        let mut line = match serde_json::to_string(&entry) {
+            Ok(line) => line,
+            Err(err) => {
+                tracing::warn!("unable to write to `{}`: {}", path.display(), err);
+                continue;
+            }
+        };
+        line.push('\n');
+        if let Err(err) = file.write_all(line.as_bytes()).await {
+            tracing::warn!("unable to write to `{}`: {}", path.display(), err);
+        }
+    }
+}
@@ -1,4 +1,18 @@
+pub mod audio_cache;
+pub mod chat;
+pub mod config;
 pub mod error;
+pub mod health;
+pub mod history;
+pub mod journal;
+pub mod library;
 pub mod media_player;
+pub mod metadata;
+pub mod metrics;
+pub mod presence;
+pub mod request_queue;
+pub mod rotation;
 pub mod soundcloud;
+pub mod sources;
+pub mod stats;
 pub mod web;
@@ -0,0 +1,230 @@
+use self::tags::{read_artwork, read_tags};
+use crate::{
+    error::Error,
+    metadata::{text, ArtworkImage, TrackMetadata},
+    soundcloud::Track,
+};
+use anyhow::Result;
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::SystemTime,
+};
+
+pub use self::tags::Artwork;
+
+mod tags;
+
+// Local tracks get ids in their own range, so that they never clash with SoundCloud's ones and
+// they're still safe to use as JSON numbers.
+const LOCAL_ID_BASE: u64 = 1 << 50;
+const EXTENSIONS: [&str; 4] = ["mp3", "ogg", "oga", "flac"];
+
+pub fn is_local(track_id: u64) -> bool {
+    (LOCAL_ID_BASE..LOCAL_ID_BASE << 1).contains(&track_id)
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LocalTrack {
+    pub id: u64,
+    #[serde(skip)]
+    pub path: PathBuf,
+    pub title: String,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub genre: Option<String>,
+    // Milliseconds
+    pub duration: u64,
+    pub has_artwork: bool,
+    pub size: u64,
+    #[serde(skip)]
+    modified: Option<SystemTime>,
+}
+
+impl LocalTrack {
+    // Same shape as SoundCloud's tracks, with urls pointing to the `/media` routes
+    pub fn to_track(&self) -> Track {
+        let url = format!("/media/{}", self.id);
+        Track {
+            id: self.id,
+            permalink_url: Some(url.clone()),
+            artwork_url: self
+                .has_artwork
+                .then(|| format!("/media/{}/artwork", self.id)),
+            duration: Some(self.duration),
+            title: Some(self.title.clone()),
+            artist: Some(
+                self.artist
+                    .clone()
+                    .unwrap_or_else(|| String::from("Unknown artist")),
+            ),
+            artist_permalink: Some(String::new()),
+            url: Some(url),
+            token: Some(String::new()),
+            transcoding: None,
+            metadata: TrackMetadata {
+                // Embedded pictures come in any size
+                artwork: self
+                    .has_artwork
+                    .then(|| ArtworkImage {
+                        src: format!("/media/{}/artwork", self.id),
+                        sizes: String::from("any"),
+                        media_type: None,
+                    })
+                    .into_iter()
+                    .collect(),
+                genre: self.genre.clone(),
+                album: self.album.clone(),
+                ..TrackMetadata::default()
+            },
+        }
+    }
+}
+
+// Audio files found in the configured directories. It's cheap to clone, all the clones share the
+// same tracks.
+#[derive(Debug, Clone, Default)]
+pub struct LocalLibrary {
+    dirs: Vec<PathBuf>,
+    tracks: Arc<RwLock<HashMap<u64, LocalTrack>>>,
+}
+
+impl LocalLibrary {
+    pub fn new(dirs: Vec<PathBuf>) -> Self {
+        Self {
+            dirs,
+            tracks: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.dirs.is_empty()
+    }
+
+    pub fn tracks_ids(&self) -> Vec<u64> {
+        self.tracks.read().unwrap().keys().copied().collect()
+    }
+
+    pub fn get(&self, track_id: u64) -> Option<LocalTrack> {
+        self.tracks.read().unwrap().get(&track_id).cloned()
+    }
+
+    // Rescans the directories, reading the tags of new or modified files only. Returns whether
+    // tracks have been added or removed.
+    pub async fn scan(&self) -> Result<bool, Error> {
+        let dirs = self.dirs.clone();
+        let known = self.tracks.read().unwrap().clone();
+        let tracks = tokio::task::spawn_blocking(move || scan_dirs(&dirs, &known))
+            .await
+            .map_err(|err| Error::LibraryFileError(err.to_string()))?;
+
+        let mut current = self.tracks.write().unwrap();
+        let changed =
+            tracks.len() != current.len() || tracks.keys().any(|id| !current.contains_key(id));
+        if changed {
+            tracing::info!("(re)loaded local library with {} tracks", tracks.len());
+        }
+        *current = tracks;
+        Ok(changed)
+    }
+
+    pub async fn artwork(&self, track_id: u64) -> Result<Option<Artwork>, Error> {
+        let track = match self.get(track_id) {
+            Some(track) if track.has_artwork => track,
+            _ => return Ok(None),
+        };
+        tokio::task::spawn_blocking(move || read_artwork(&track.path))
+            .await
+            .map_err(|err| Error::LibraryFileError(err.to_string()))?
+    }
+}
+
+fn scan_dirs(dirs: &[PathBuf], known: &HashMap<u64, LocalTrack>) -> HashMap<u64, LocalTrack> {
+    let mut tracks = HashMap::new();
+    for dir in dirs {
+        scan_dir(dir, known, &mut tracks);
+    }
+    tracks
+}
+
+fn scan_dir(dir: &Path, known: &HashMap<u64, LocalTrack>, tracks: &mut HashMap<u64, LocalTrack>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) => {
+            tracing::warn!("unable to read directory {}: {}", dir.display(), err);
+            return;
+        }
+    };
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+        // Symlinked directories aren't followed, to avoid loops
+        if entry.file_type().map(|t| t.is_dir()).unwrap_or(false) {
+            scan_dir(&path, known, tracks);
+            continue;
+        }
+        if !is_audio_file(&path) {
+            continue;
+        }
+        let metadata = match fs::metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+
+        let id = track_id(&path);
+        let modified = metadata.modified().ok();
+        if let Some(track) = known.get(&id) {
+            if track.modified == modified && track.size == metadata.len() {
+                tracks.insert(id, track.clone());
+                continue;
+            }
+        }
+
+        match read_tags(&path) {
+            Ok(tags) => {
+                let title = tags.title.unwrap_or_else(|| {
+                    path.file_stem()
+                        .map(|stem| stem.to_string_lossy().to_string())
+                        .unwrap_or_default()
+                });
+                tracks.insert(
+                    id,
+                    LocalTrack {
+                        id,
+                        path,
+                        title,
+                        artist: tags.artist,
+                        album: text(tags.album),
+                        genre: text(tags.genre),
+                        duration: tags.duration,
+                        has_artwork: tags.has_artwork,
+                        size: metadata.len(),
+                        modified,
+                    },
+                );
+            }
+            Err(err) => tracing::warn!("skipping {}: {}", path.display(), err),
+        }
+    }
+}
+
+fn is_audio_file(path: &Path) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .map(|e| EXTENSIONS.contains(&e.to_lowercase().as_str()))
+        .unwrap_or(false)
+}
+
+// Stable id from the file's path (FNV-1a), so that tracks keep their id across rescans and restarts
+fn track_id(path: &Path) -> u64 {
+    let hash = path
+        .to_string_lossy()
+        .bytes()
+        .fold(0xcbf29ce484222325_u64, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+        });
+    LOCAL_ID_BASE | (hash & (LOCAL_ID_BASE - 1))
+}
@@ -0,0 +1,124 @@
+use crate::error::Error;
+use anyhow::Result;
+use std::{fs::File, path::Path};
+use symphonia::core::{
+    errors::Error as SymphoniaError,
+    formats::{FormatOptions, FormatReader},
+    io::MediaSourceStream,
+    meta::{MetadataOptions, MetadataRevision, StandardTagKey},
+    probe::{Hint, ProbeResult},
+    units::TimeBase,
+};
+
+#[derive(Debug, Clone, Default)]
+pub struct Tags {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub genre: Option<String>,
+    pub has_artwork: bool,
+    // Milliseconds
+    pub duration: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Artwork {
+    pub media_type: String,
+    pub data: Vec<u8>,
+}
+
+// Reads the ID3/Vorbis tags of an audio file, and computes its duration from its packets.
+pub fn read_tags(path: &Path) -> Result<Tags, Error> {
+    let mut probed = open(path)?;
+    let mut tags = Tags::default();
+
+    // ID3 tags come before the container, the others are part of it
+    if let Some(revision) = probed.metadata.get().as_ref().and_then(|m| m.current()) {
+        apply_revision(&mut tags, revision);
+    }
+    if let Some(revision) = probed.format.metadata().current() {
+        apply_revision(&mut tags, revision);
+    }
+
+    tags.duration = duration(probed.format.as_mut())
+        .ok_or_else(|| Error::LibraryFileError(path.display().to_string()))?;
+    Ok(tags)
+}
+
+pub fn read_artwork(path: &Path) -> Result<Option<Artwork>, Error> {
+    let mut probed = open(path)?;
+    let artwork = |revision: &MetadataRevision| {
+        revision.visuals().first().map(|visual| Artwork {
+            media_type: visual.media_type.clone(),
+            data: visual.data.to_vec(),
+        })
+    };
+
+    let from_id3 = probed
+        .metadata
+        .get()
+        .as_ref()
+        .and_then(|m| m.current().and_then(artwork));
+    Ok(from_id3.or_else(|| probed.format.metadata().current().and_then(artwork)))
+}
+
+fn open(path: &Path) -> Result<ProbeResult, Error> {
+    let file = File::open(path)?;
+    let stream = MediaSourceStream::new(Box::new(file), Default::default());
+    let mut hint = Hint::new();
+    if let Some(extension) = path.extension().and_then(|e| e.to_str()) {
+        hint.with_extension(extension);
+    }
+
+    symphonia::default::get_probe()
+        .format(
+            &hint,
+            stream,
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )
+        .map_err(|err| Error::LibraryFileError(format!("{}: {}", path.display(), err)))
+}
+
+fn apply_revision(tags: &mut Tags, revision: &MetadataRevision) {
+    for tag in revision.tags() {
+        match tag.std_key {
+            Some(StandardTagKey::TrackTitle) => tags.title = Some(tag.value.to_string()),
+            Some(StandardTagKey::Artist) => tags.artist = Some(tag.value.to_string()),
+            Some(StandardTagKey::AlbumArtist) if tags.artist.is_none() => {
+                tags.artist = Some(tag.value.to_string())
+            }
+            Some(StandardTagKey::Album) => tags.album = Some(tag.value.to_string()),
+            Some(StandardTagKey::Genre) => tags.genre = Some(tag.value.to_string()),
+            _ => {}
+        }
+    }
+    tags.has_artwork |= !revision.visuals().is_empty();
+}
+
+// Sums the duration of all the packets of the default track: headers (e.g. Xing for VBR MP3s)
+// are often missing or approximate.
+fn duration(reader: &mut dyn FormatReader) -> Option<u64> {
+    let track = reader.default_track()?;
+    let track_id = track.id;
+    let time_base = track.codec_params.time_base.or_else(|| {
+        track
+            .codec_params
+            .sample_rate
+            .map(|rate| TimeBase::new(1, rate))
+    })?;
+
+    let mut frames = 0;
+    loop {
+        match reader.next_packet() {
+            Ok(packet) if packet.track_id() == track_id => frames += packet.dur,
+            Ok(_) => {}
+            Err(SymphoniaError::IoError(_)) => break,
+            Err(SymphoniaError::ResetRequired) => break,
+            Err(_) => return None,
+        }
+    }
+
+    let time = time_base.calc_time(frames);
+    Some(time.seconds * 1000 + (time.frac * 1000.0) as u64)
+}
@@ -1,18 +1,39 @@
 use axum::{
     http::{header, HeaderValue},
-    routing::get,
+    routing::{delete, get, post, put},
     Router,
 };
 use axum_extra::routing::SpaRouter;
 use robo_radio::{
+    config::Config,
     error::Error,
     web::{
-        handlers::{index_handler, websocket_handler},
-        radio::{go_live, Station, StationService},
+        admin::{
+            announcement_handler, ban_handler, bans_handler, insert_queue_handler, kick_handler,
+            listeners_handler, playout_handler, queue_handler, reload_playlist_handler,
+            remove_queue_handler, resolve_handler, rotate_client_id_handler, skip_handler,
+            switch_playlist_handler, unban_handler,
+        },
+        handlers::{
+            approve_request_handler, chat_history_handler, create_request_handler,
+            delete_chat_message_handler, healthz_handler, history_handler, index_handler,
+            list_requests_handler, metrics_handler, most_loved_handler, mute_chat_author_handler,
+            pending_requests_handler, presence_handler, readyz_handler, reject_request_handler,
+            stats_csv_handler, stats_handler, status_handler, tracks_stats_csv_handler,
+            websocket_handler,
+        },
+        failover::probe_primary,
+        media::{artwork_handler, media_handler, watch_library},
+        playout::PlayoutControl,
+        prefetch::prefetch_upcoming,
+        proxy::proxy_track_handler,
+        radio::{Station, StationService},
+        sources::refresh_sources,
+        stats::sample_stats,
+        supervisor::supervise_playout,
     },
 };
-use std::{env, net::SocketAddr, sync::Arc};
-use tokio::sync::Mutex;
+use std::{env, net::SocketAddr, sync::Arc, time::Duration};
 use tower_http::{
     set_header::SetResponseHeaderLayer,
     trace::{DefaultOnResponse, TraceLayer},
@@ -31,17 +52,55 @@ async fn main() -> Result<(), Error> {
         .compact()
         .init();
 
-    let playlist_id = match env::var_os("ROBO_RADIO_SOUNDCLOUD_PLAYLIST_ID") {
-        Some(v) => v.into_string().unwrap(),
-        None => panic!("$ROBO_RADIO_SOUNDCLOUD_PLAYLIST_ID is not set"),
-    };
+    let config = Config::from_env();
 
-    let station = Station::new(playlist_id.as_str()).await?;
-    let station_service: StationService = Arc::new(Mutex::new(station));
+    let (playout, playout_commands) = PlayoutControl::channel();
+    let station = Station::new(&config, playout).await?;
+    let station_service: StationService = Arc::new(station);
 
     let app = Router::with_state(station_service.clone())
         .route("/", get(index_handler))
         .route("/ws", get(websocket_handler))
+        .route("/metrics", get(metrics_handler))
+        .route("/healthz", get(healthz_handler))
+        .route("/readyz", get(readyz_handler))
+        .route("/status", get(status_handler))
+        .route("/media/:id", get(media_handler))
+        .route("/media/:id/artwork", get(artwork_handler))
+        .route("/proxy/track/:id", get(proxy_track_handler))
+        .route(
+            "/api/requests",
+            get(list_requests_handler).post(create_request_handler),
+        )
+        .route("/api/requests/pending", get(pending_requests_handler))
+        .route("/api/requests/:id/approve", post(approve_request_handler))
+        .route("/api/requests/:id/reject", post(reject_request_handler))
+        .route("/api/listeners", get(presence_handler))
+        .route("/api/history", get(history_handler))
+        .route("/api/tracks/loved", get(most_loved_handler))
+        .route("/api/chat", get(chat_history_handler))
+        .route("/api/chat/:id", delete(delete_chat_message_handler))
+        .route("/api/chat/:id/mute", post(mute_chat_author_handler))
+        .route("/api/stats", get(stats_handler))
+        .route("/api/stats.csv", get(stats_csv_handler))
+        .route("/api/stats/tracks.csv", get(tracks_stats_csv_handler))
+        .route("/admin/playout", post(playout_handler))
+        .route("/admin/skip", post(skip_handler))
+        .route("/admin/playlist", put(switch_playlist_handler))
+        .route("/admin/playlist/reload", post(reload_playlist_handler))
+        .route("/admin/resolve", get(resolve_handler))
+        .route(
+            "/admin/queue",
+            get(queue_handler).post(insert_queue_handler),
+        )
+        .route("/admin/queue/:track_id", delete(remove_queue_handler))
+        .route("/admin/client_id/rotate", post(rotate_client_id_handler))
+        .route("/admin/listeners", get(listeners_handler))
+        .route("/admin/listeners/:id/kick", post(kick_handler))
+        .route("/admin/listeners/:id/ban", post(ban_handler))
+        .route("/admin/bans", get(bans_handler))
+        .route("/admin/bans/:ip", delete(unban_handler))
+        .route("/admin/announcements", post(announcement_handler))
         .merge(SpaRouter::new("/assets", "assets"))
         .layer(SetResponseHeaderLayer::if_not_present(
             header::CACHE_CONTROL,
@@ -55,9 +114,29 @@ async fn main() -> Result<(), Error> {
             ),
         );
 
-    tokio::spawn(async move {
-        go_live(station_service.clone()).await;
-    });
+    tokio::spawn(prefetch_upcoming(station_service.clone()));
+    tokio::spawn(watch_library(
+        station_service.clone(),
+        Duration::from_secs(config.library.rescan_secs),
+    ));
+    tokio::spawn(refresh_sources(
+        station_service.clone(),
+        Duration::from_secs(config.sources.refresh_secs),
+    ));
+    tokio::spawn(probe_primary(
+        station_service.clone(),
+        Duration::from_secs(config.failover.probe_secs),
+    ));
+    tokio::spawn(sample_stats(
+        station_service.clone(),
+        Duration::from_secs(config.stats.sample_secs),
+    ));
+
+    tokio::spawn(supervise_playout(
+        station_service.clone(),
+        playout_commands,
+        config.supervisor.clone(),
+    ));
 
     // Use "[::]" to listen on both IPv4 (0.0.0.0) and IPv6
     let srv_host = env::var("ROBO_RADIO_HOST").unwrap_or_else(|_| "0.0.0.0".to_string());
@@ -71,7 +150,7 @@ async fn main() -> Result<(), Error> {
 
     tracing::info!("server started and listening on {}", addr);
     axum::Server::bind(&addr)
-        .serve(app.into_make_service())
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
         .await
         .unwrap();
 
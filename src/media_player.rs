@@ -1,125 +1,971 @@
 use crate::{
-    error::Error,
-    soundcloud::{ApiClient, Track},
+    audio_cache::AudioCache,
+    config::{Config, FailoverConfig, PrefetchConfig},
+    error::{Error, SoundcloudErrorKind},
+    health::Health,
+    library::{self, LocalLibrary},
+    metadata::TrackMetadata,
+    metrics::Metrics,
+    request_queue::{RequestQueue, RequestedTrack, SongRequest},
+    rotation::{Rotation, RotationDiff},
+    soundcloud::{ApiClient, Resource, Track},
+    sources::{FetchedSources, SourceKind, SourceSpec, SourcesRefresh},
+    stats::Stats,
 };
 use anyhow::Result;
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, Duration, Utc};
 use rand::seq::SliceRandom;
 use rand::thread_rng;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::Arc,
+};
+use tokio::sync::Mutex;
 
 #[derive(Debug, Clone, Serialize)]
 pub struct CurrentTrack {
     pub started_at: DateTime<Utc>,
     pub id: u64,
     pub permalink_url: String,
-    // pub artwork_url: String,
+    pub artwork_url: Option<String>,
     pub duration: u64,
     pub title: String,
     pub artist: String,
     pub artist_permalink: String,
     pub url: String,
+    // Same audio, streamed through the station
+    pub proxy_url: String,
     pub token: String,
+    #[serde(skip)]
+    pub transcoding: Option<String>,
+    pub metadata: TrackMetadata,
 }
 
 impl CurrentTrack {
-    pub fn new(track: &Track) -> Self {
-        Self {
-            started_at: Utc::now(),
+    // A track can't be aired without its stream url and its duration, the other missing fields
+    // are left empty
+    pub fn new(track: &Track, started_at: DateTime<Utc>) -> Result<Self, Error> {
+        let url = track
+            .url
+            .clone()
+            .ok_or(Error::TrackIncomplete(track.id, "url"))?;
+        let duration = track
+            .duration
+            .filter(|duration| *duration > 0)
+            .ok_or(Error::TrackIncomplete(track.id, "duration"))?;
+        Ok(Self {
+            started_at,
             id: track.id,
-            permalink_url: track.permalink_url.as_ref().unwrap().clone(),
-            // artwork_url: track.artwork_url.as_ref().unwrap().clone(),
-            duration: *track.duration.as_ref().unwrap(),
-            title: track.title.as_ref().unwrap().clone(),
-            artist: track.artist.as_ref().unwrap().clone(),
-            artist_permalink: track.artist_permalink.as_ref().unwrap().clone(),
-            url: track.url.as_ref().unwrap().clone(),
-            token: track.token.as_ref().unwrap().clone(),
+            permalink_url: track.permalink_url.clone().unwrap_or_default(),
+            artwork_url: track
+                .metadata
+                .artwork_url()
+                .map(str::to_string)
+                .or_else(|| track.artwork_url.clone()),
+            duration,
+            title: track.title.clone().unwrap_or_default(),
+            artist: track.artist.clone().unwrap_or_default(),
+            artist_permalink: track.artist_permalink.clone().unwrap_or_default(),
+            url,
+            proxy_url: format!("/proxy/track/{}", track.id),
+            token: track.token.clone().unwrap_or_default(),
+            transcoding: track.transcoding.clone(),
+            metadata: track.metadata.clone(),
+        })
+    }
+
+    pub fn to_track(&self) -> Track {
+        Track {
+            id: self.id,
+            permalink_url: Some(self.permalink_url.clone()),
+            artwork_url: self.artwork_url.clone(),
+            duration: Some(self.duration),
+            title: Some(self.title.clone()),
+            artist: Some(self.artist.clone()),
+            artist_permalink: Some(self.artist_permalink.clone()),
+            url: Some(self.url.clone()),
+            token: Some(self.token.clone()),
+            transcoding: self.transcoding.clone(),
+            metadata: self.metadata.clone(),
         }
     }
+
+    pub fn ends_at(&self) -> DateTime<Utc> {
+        self.started_at + Duration::milliseconds(self.duration as i64)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UpcomingSource {
+    Inserted,
+    Request,
+    Playlist,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct UpcomingTrack {
+    pub track_id: u64,
+    pub source: UpcomingSource,
+}
+
+#[derive(Debug, Clone)]
+struct PrefetchedTrack {
+    track: Track,
+    resolved_at: DateTime<Utc>,
+}
+
+// Upcoming tracks to be resolved ahead of time. It's taken out of the media player, so that the
+// network calls don't need to hold its lock.
+#[derive(Debug)]
+pub struct Prefetch {
+    api: ApiClient,
+    client_id: String,
+    tracks_ids: Vec<u64>,
+    // Tracks whose waveform is still missing
+    waveforms_ids: Vec<u64>,
+}
+
+impl Prefetch {
+    pub fn is_empty(&self) -> bool {
+        self.tracks_ids.is_empty()
+    }
+
+    pub async fn resolve(self) -> Vec<Track> {
+        let mut tracks = vec![];
+        for track_id in self.tracks_ids {
+            match self.api.get_track(self.client_id.as_ref(), track_id).await {
+                Ok(mut track) => {
+                    if self.waveforms_ids.contains(&track_id) {
+                        track.metadata.waveform =
+                            self.api.get_waveform(&track).await.unwrap_or_default();
+                    }
+                    tracks.push(track)
+                }
+                Err(err) => {
+                    tracing::warn!("unable to prefetch track with id {}: {}", track_id, err)
+                }
+            }
+        }
+        tracks
+    }
+}
+
+// Where the aired tracks come from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Source {
+    // The configured sources
+    #[default]
+    Primary,
+    // The emergency playlist, or the audio cache, while the primary source is down
+    Fallback,
+}
+
+// Resolves SoundCloud permalinks without holding the media player
+#[derive(Debug, Clone)]
+pub struct Lookup {
+    api: ApiClient,
+    client_id: String,
+}
+
+impl Lookup {
+    pub async fn resolve(&self, url: &str) -> Result<Resource, Error> {
+        self.api.resolve(self.client_id.as_ref(), url).await
+    }
+
+    pub async fn resolve_track_id(&self, url: &str) -> Result<u64, Error> {
+        self.api
+            .resolve_track_id(self.client_id.as_ref(), url)
+            .await
+    }
+
+    pub async fn get_track(&self, track_id: u64) -> Result<Track, Error> {
+        self.api.get_track(self.client_id.as_ref(), track_id).await
+    }
+}
+
+// Client id and sources fetched again while on the emergency playlist
+#[derive(Debug, Clone)]
+pub struct Probe {
+    api: ApiClient,
+    sources: Vec<SourceSpec>,
+    library: LocalLibrary,
+}
+
+impl Probe {
+    pub async fn run(self) -> Result<(String, FetchedSources), Error> {
+        let client_id = self.api.get_client_id().await?;
+        let fetched = SourcesRefresh::new(self.api, client_id.clone(), self.sources, self.library)
+            .run()
+            .await;
+        Ok((client_id, fetched))
+    }
 }
 
 #[derive(Default, Debug, Clone)]
 pub struct MediaPlayer {
-    playlist_id: Option<String>,
+    sources: Vec<SourceSpec>,
     client_id: String,
     client_id_timestamp: DateTime<Utc>,
     api: ApiClient,
-    tracks_ids: Vec<u64>,
+    rotation: Rotation,
+    inserted_tracks_ids: VecDeque<u64>,
+    requests: RequestQueue,
+    last_played: HashMap<u64, DateTime<Utc>>,
+    prefetch_config: PrefetchConfig,
+    prefetched: HashMap<u64, PrefetchedTrack>,
+    library: LocalLibrary,
+    audio_cache: Arc<AudioCache>,
+    failover_config: FailoverConfig,
+    fallback_library: LocalLibrary,
+    fallback_tracks_ids: Vec<u64>,
+    source: Source,
+    health: Arc<Health>,
+    stats: Arc<Stats>,
+    metrics: Arc<Metrics>,
     pub current_track: Option<CurrentTrack>,
 }
 
 impl MediaPlayer {
-    pub async fn new() -> Result<Self, Error> {
-        let api = ApiClient::new();
-        let client_id = api.get_client_id().await?;
-        let client_id_timestamp = Utc::now();
+    // Starts on the emergency playlist when SoundCloud can't be reached, if there's one
+    pub async fn new(
+        config: &Config,
+        audio_cache: Arc<AudioCache>,
+        api: ApiClient,
+        health: Arc<Health>,
+        stats: Arc<Stats>,
+        metrics: Arc<Metrics>,
+    ) -> Result<Self, Error> {
+        let library = LocalLibrary::new(config.library.dirs.clone());
+        if library.is_enabled() {
+            library.scan().await?;
+        }
+        let fallback_library = LocalLibrary::new(config.failover.dirs.clone());
+        if fallback_library.is_enabled() {
+            fallback_library.scan().await?;
+        }
 
-        Ok(Self {
+        let sources = config.sources.sources.clone();
+        let mut media_player = Self {
             api,
-            client_id,
-            client_id_timestamp,
-            tracks_ids: vec![],
+            client_id: String::new(),
+            client_id_timestamp: Utc::now(),
+            rotation: Rotation::new(&weights(&sources)),
+            inserted_tracks_ids: VecDeque::new(),
+            requests: RequestQueue::new(config.requests.clone()),
+            last_played: HashMap::new(),
+            prefetch_config: config.prefetch.clone(),
+            prefetched: HashMap::new(),
+            library,
+            audio_cache,
+            failover_config: config.failover.clone(),
+            fallback_library,
+            fallback_tracks_ids: vec![],
+            source: Source::Primary,
+            health,
+            stats,
+            metrics,
             current_track: None,
-            playlist_id: None,
-        })
+            sources,
+        };
+
+        let result = match media_player.api.get_client_id().await {
+            Ok(client_id) => {
+                media_player.client_id = client_id;
+                media_player.reload_playlist().await
+            }
+            Err(err) => Err(err),
+        };
+        if let Err(err) = result {
+            media_player.fail_over(err)?;
+        }
+        Ok(media_player)
     }
 
-    pub async fn refresh_client_id(&mut self) -> Result<(), Error> {
-        let client_id = self.api.get_client_id().await?;
+    // Fetches a new client id, without holding the media player meanwhile
+    pub async fn refresh_client_id(player: &Mutex<Self>) -> Result<(), Error> {
+        let api = player.lock().await.api.clone();
+        let client_id = api.get_client_id().await?;
+        player.lock().await.set_client_id(client_id);
+        Ok(())
+    }
+
+    fn set_client_id(&mut self, client_id: String) {
         self.client_id = client_id;
         self.client_id_timestamp = Utc::now();
-        Ok(())
+        // Stream urls resolved with the old client id might not work anymore
+        self.prefetched.clear();
+        self.metrics.client_id_rotations.inc();
+    }
+
+    // Loads the tracks of the given sources
+    pub async fn load_sources(&mut self, sources: Vec<SourceSpec>) -> Result<RotationDiff, Error> {
+        let fetched = self.fetch_sources(sources).run().await;
+        self.apply_sources(fetched)
+    }
+
+    // Plans fetching the current sources again
+    pub fn refresh_sources(&self) -> SourcesRefresh {
+        self.fetch_sources(self.sources.clone())
+    }
+
+    fn fetch_sources(&self, sources: Vec<SourceSpec>) -> SourcesRefresh {
+        SourcesRefresh::new(
+            self.api.clone(),
+            self.client_id.clone(),
+            sources,
+            self.library.clone(),
+        )
     }
 
-    pub async fn load_playlist(&mut self, playlist_id: &str) -> Result<(), Error> {
-        let mut playlist = self
-            .api
-            .get_playlist(self.client_id.as_ref(), playlist_id.as_ref())
-            .await?;
+    // Merges the fetched tracks into the rotation, or starts a new rotation when the sources
+    // changed. Nothing changes when none of the SoundCloud sources could be fetched.
+    pub fn apply_sources(&mut self, fetched: FetchedSources) -> Result<RotationDiff, Error> {
+        let FetchedSources { sources, tracks } = fetched;
+        let soundcloud = sources.iter().filter(|source| !source.is_library()).count();
+        let failed = sources
+            .iter()
+            .zip(&tracks)
+            .filter(|(source, tracks)| !source.is_library() && tracks.is_err())
+            .count();
+        if soundcloud > 0 && failed == soundcloud {
+            return Err(tracks
+                .into_iter()
+                .find_map(Result::err)
+                .unwrap_or(Error::PlaylistEmpty));
+        }
 
-        self.playlist_id = Some(playlist_id.to_string());
-        playlist.tracks_ids.shuffle(&mut thread_rng());
-        self.tracks_ids = playlist.tracks_ids.clone();
+        let previous: HashSet<u64> = self.rotation.tracks().collect();
+        if sources != self.sources {
+            self.rotation = Rotation::new(&weights(&sources));
+            self.sources = sources;
+        }
+        self.rotation
+            .update(tracks.into_iter().map(Result::ok).collect());
+        let diff = self.rotation.diff(&previous);
+        self.metrics.playlist_reloads.inc();
+        self.metrics
+            .playlist_tracks_added
+            .add(diff.added.len() as u64);
+        self.metrics
+            .playlist_tracks_removed
+            .add(diff.removed.len() as u64);
+        self.health.playlist_loaded();
 
         tracing::info!(
-            "(re)loaded playlist with {} tracks",
-            playlist.tracks_ids.clone().len()
+            "(re)loaded {} sources with {} tracks, {} added and {} removed",
+            self.sources.len(),
+            self.rotation.len(),
+            diff.added.len(),
+            diff.removed.len()
         );
+        if !diff.is_empty() {
+            tracing::debug!("added {:?}, removed {:?}", diff.added, diff.removed);
+        }
+        Ok(diff)
+    }
 
-        Ok(())
+    // Plans replacing the SoundCloud sources with a single playlist, given by id or permalink,
+    // keeping the local library
+    pub fn switch_playlist(&self, playlist: &str) -> Result<SourcesRefresh, Error> {
+        let mut sources = vec![SourceSpec::playlist(playlist)?];
+        sources.extend(
+            self.sources
+                .iter()
+                .filter(|source| source.is_library())
+                .cloned(),
+        );
+        Ok(self.fetch_sources(sources))
+    }
+
+    pub fn sources(&self) -> &[SourceSpec] {
+        &self.sources
+    }
+
+    pub fn library(&self) -> &LocalLibrary {
+        &self.library
+    }
+
+    pub fn fallback_library(&self) -> &LocalLibrary {
+        &self.fallback_library
     }
 
-    pub async fn load_next_track(&mut self) -> Result<(), Error> {
+    // Picks up the tracks added to or removed from the local library, without reloading the
+    // other sources
+    pub fn sync_library(&mut self) {
+        let library_ids = self.library.tracks_ids();
+        let listed = self
+            .sources
+            .iter()
+            .map(|source| source.is_library().then(|| library_ids.clone()))
+            .collect();
+        self.rotation.update(listed);
+    }
+
+    // Loads the next track, to be aired at `starts_at`. When the primary source fails too many
+    // times in a row, the station fails over to the emergency playlist. The media player is only
+    // locked between SoundCloud calls, so that nobody waits on them.
+    pub async fn load_next_track(
+        player: &Mutex<Self>,
+        starts_at: DateTime<Utc>,
+    ) -> Result<(), Error> {
+        if player.lock().await.source == Source::Primary {
+            match Self::load_primary_track(player, starts_at).await {
+                Ok(()) => return Ok(()),
+                Err(err) => player.lock().await.fail_over(err)?,
+            }
+        }
+        player.lock().await.load_fallback_track(starts_at)
+    }
+
+    // Tracks that can't be resolved are skipped, each track of the rotation getting one chance,
+    // unless SoundCloud is down: they're kept for later and the circuit breaker fails the next
+    // attempts fast. Only outages count towards failing over.
+    async fn load_primary_track(
+        player: &Mutex<Self>,
+        starts_at: DateTime<Utc>,
+    ) -> Result<(), Error> {
+        let mut outages = 0;
+        let mut attempts_left = player.lock().await.rotation.len().max(1);
+        let mut client_id_refreshed = false;
         loop {
-            self.ensure_client_id_validity().await?;
-            self.ensure_playlist_not_empty().await?;
+            let track_id = Self::pop_next_track_id(player).await?;
+            let origin = match library::is_local(track_id) {
+                true => "library",
+                false => "soundcloud",
+            };
+            let (resolved, lookup) = {
+                let mut media_player = player.lock().await;
+                (media_player.take_resolved(track_id), media_player.lookup())
+            };
+            let resolved = match resolved {
+                Some(resolved) => resolved,
+                None => lookup.get_track(track_id).await,
+            };
+
+            let mut media_player = player.lock().await;
+            let err = match resolved.and_then(|track| media_player.air(&track, starts_at)) {
+                Ok(()) => {
+                    media_player.stats.track_loaded(origin, true);
+                    return Ok(());
+                }
+                Err(err) => err,
+            };
 
-            let track_id = self.tracks_ids.pop().unwrap();
-            if let Ok(track) = self.api.get_track(self.client_id.as_ref(), track_id).await {
-                self.current_track = Some(CurrentTrack::new(&track));
-                break;
+            // The cached copy airs in place of the track, nothing was skipped
+            if let Some(track) = media_player.audio_cache.cached_track(track_id) {
+                if media_player.air(&track, starts_at).is_ok() {
+                    tracing::warn!("aired track with id {} from the cache: {}", track_id, err);
+                    media_player.metrics.fallback_tracks.inc();
+                    media_player.stats.track_loaded("fallback", true);
+                    return Ok(());
+                }
             }
-            tracing::warn!("skipping track with id {} because of some error", track_id);
-            continue;
+            media_player.stats.track_loaded(origin, false);
+            media_player.metrics.tracks_skipped_resolve_error.inc();
+            media_player.health.record_error(&err);
+
+            match err.soundcloud_kind() {
+                Some(SoundcloudErrorKind::Auth) if !client_id_refreshed => {
+                    tracing::warn!("client id rejected, fetching a new one");
+                    media_player.rotation.requeue(track_id);
+                    client_id_refreshed = true;
+                    drop(media_player);
+                    Self::refresh_client_id(player).await?;
+                    continue;
+                }
+                _ if err.is_outage() => media_player.rotation.requeue(track_id),
+                Some(SoundcloudErrorKind::NotFound) => {
+                    tracing::warn!("removing track with id {}, gone from SoundCloud", track_id);
+                    media_player.rotation.purge(track_id);
+                }
+                _ => tracing::warn!("skipping track with id {} because of: {}", track_id, err),
+            }
+
+            outages = match err.is_outage() {
+                true => outages + 1,
+                false => 0,
+            };
+            attempts_left -= 1;
+            if outages >= media_player.failover_config.threshold || attempts_left == 0 {
+                return Err(err);
+            }
+        }
+    }
+
+    pub fn source(&self) -> Source {
+        self.source
+    }
+
+    // Switches to the emergency playlist, or gives up with `err` when there's none
+    pub fn fail_over(&mut self, err: Error) -> Result<(), Error> {
+        if !self.has_fallback() {
+            return Err(err);
         }
+
+        tracing::error!("primary source is down, failing over: {}", err);
+        self.health.record_error(&err);
+        self.source = Source::Fallback;
+        self.health.source(self.source);
+        self.metrics.failovers.inc();
+        self.metrics.on_fallback.set(1);
         Ok(())
     }
 
-    async fn ensure_client_id_validity(&mut self) -> Result<(), Error> {
-        let now = Utc::now();
-        let elapsed = now.signed_duration_since(self.client_id_timestamp);
-        if elapsed.num_days() >= 1 {
-            self.refresh_client_id().await?;
+    // Checks whether the primary source is back, without holding the media player
+    pub fn probe(&self) -> Probe {
+        Probe {
+            api: self.api.clone(),
+            sources: self.sources.clone(),
+            library: self.library.clone(),
         }
+    }
+
+    // Switches back to the primary source, with the client id and sources of a successful probe
+    pub fn restore(&mut self, client_id: String, fetched: FetchedSources) -> Result<(), Error> {
+        self.apply_sources(fetched)?;
+        self.client_id = client_id;
+        self.client_id_timestamp = Utc::now();
+        self.prefetched.clear();
+        self.source = Source::Primary;
+        self.health.source(self.source);
+        self.metrics.on_fallback.set(0);
+        tracing::info!("primary source is back");
         Ok(())
     }
 
-    async fn ensure_playlist_not_empty(&mut self) -> Result<(), Error> {
-        if self.tracks_ids.is_empty() {
-            self.load_playlist(self.clone().playlist_id.as_ref().unwrap().as_str())
-                .await?;
+    fn has_fallback(&self) -> bool {
+        !self.fallback_library.tracks_ids().is_empty() || !self.audio_cache.is_empty()
+    }
+
+    fn load_fallback_track(&mut self, starts_at: DateTime<Utc>) -> Result<(), Error> {
+        let track = self.next_fallback_track().ok_or(Error::PlaylistEmpty)?;
+        self.metrics.fallback_tracks.inc();
+        let result = self.air(&track, starts_at);
+        self.stats.track_loaded("fallback", result.is_ok());
+        result
+    }
+
+    // The shuffled emergency playlist, or the cached tracks when there's none
+    fn next_fallback_track(&mut self) -> Option<Track> {
+        if self.fallback_tracks_ids.is_empty() {
+            self.fallback_tracks_ids = self.fallback_library.tracks_ids();
+            self.fallback_tracks_ids.shuffle(&mut thread_rng());
         }
+        while let Some(track_id) = self.fallback_tracks_ids.pop() {
+            if let Some(track) = self.fallback_library.get(track_id) {
+                return Some(track.to_track());
+            }
+        }
+        self.audio_cache.fallback_track(&self.last_played)
+    }
+
+    // Plans which upcoming tracks need to be resolved (again), forgetting the ones that aren't
+    // upcoming anymore. Stream urls are refreshed halfway through their max age, so that they're
+    // still fresh when the track goes on air.
+    pub fn prefetch(&mut self) -> Prefetch {
+        // Local tracks are always at hand
+        let mut upcoming: Vec<u64> = self
+            .upcoming(self.prefetch_config.tracks)
+            .into_iter()
+            .map(|track| track.track_id)
+            .filter(|id| !library::is_local(*id))
+            .collect();
+        self.prefetched.retain(|id, _| upcoming.contains(id));
+        // SoundCloud is left alone while it's down
+        if self.source == Source::Fallback {
+            upcoming.clear();
+        }
+
+        let refresh_after = Duration::seconds(self.prefetch_config.max_age_secs / 2);
+        let now = Utc::now();
+        let tracks_ids: Vec<u64> = upcoming
+            .into_iter()
+            .filter(|id| match self.prefetched.get(id) {
+                Some(prefetched) => {
+                    now.signed_duration_since(prefetched.resolved_at) >= refresh_after
+                }
+                None => true,
+            })
+            .collect();
+        let waveforms_ids = tracks_ids
+            .iter()
+            .copied()
+            .filter(|id| match self.prefetched.get(id) {
+                Some(prefetched) => prefetched.track.metadata.waveform.is_empty(),
+                None => true,
+            })
+            .collect();
+
+        Prefetch {
+            api: self.api.clone(),
+            client_id: self.client_id.clone(),
+            tracks_ids,
+            waveforms_ids,
+        }
+    }
+
+    pub fn store_prefetched(&mut self, tracks: Vec<Track>) {
+        let resolved_at = Utc::now();
+        for mut track in tracks {
+            // A refreshed stream url doesn't bring the waveform along again
+            if track.metadata.waveform.is_empty() {
+                if let Some(prefetched) = self.prefetched.get_mut(&track.id) {
+                    track.metadata.waveform =
+                        std::mem::take(&mut prefetched.track.metadata.waveform);
+                }
+            }
+            self.prefetched
+                .insert(track.id, PrefetchedTrack { track, resolved_at });
+        }
+    }
+
+    pub fn lookup(&self) -> Lookup {
+        Lookup {
+            api: self.api.clone(),
+            client_id: self.client_id.clone(),
+        }
+    }
+
+    // Resolves a single track, e.g. when its stream url expired. It's on the way to the listener,
+    // so it doesn't wait for the waveform.
+    pub fn prefetch_track(&self, track_id: u64) -> Prefetch {
+        Prefetch {
+            api: self.api.clone(),
+            client_id: self.client_id.clone(),
+            tracks_ids: vec![track_id],
+            waveforms_ids: vec![],
+        }
+    }
+
+    // Whether the track is on air, upcoming or in the playlist
+    pub fn is_known(&self, track_id: u64) -> bool {
+        matches!(&self.current_track, Some(track) if track.id == track_id)
+            || self.rotation.contains(track_id)
+            || self.inserted_tracks_ids.contains(&track_id)
+            || self
+                .requests
+                .queued()
+                .iter()
+                .any(|request| request.track_id == track_id)
+    }
+
+    // Latest resolution of the track, if any
+    pub fn stream_track(&self, track_id: u64) -> Option<Track> {
+        match self.prefetched.get(&track_id) {
+            Some(prefetched) => Some(prefetched.track.clone()),
+            None => self
+                .current_track
+                .as_ref()
+                .filter(|track| track.id == track_id)
+                .map(CurrentTrack::to_track),
+        }
+    }
+
+    // The track that will follow the current one, if it has been resolved already
+    pub fn next_track(&self) -> Option<CurrentTrack> {
+        if self.source == Source::Fallback {
+            return None;
+        }
+        let track_id = self.upcoming(1).first()?.track_id;
+        let track = match library::is_local(track_id) {
+            true => self.library.get(track_id)?.to_track(),
+            false => self.prefetched.get(&track_id)?.track.clone(),
+        };
+        let starts_at = match self.current_track.as_ref() {
+            Some(current) => current.ends_at(),
+            None => Utc::now(),
+        };
+        CurrentTrack::new(&track, starts_at).ok()
+    }
+
+    pub async fn reload_playlist(&mut self) -> Result<RotationDiff, Error> {
+        self.load_sources(self.sources.clone()).await
+    }
+
+    // The first playlist among the sources
+    pub fn playlist_id(&self) -> Option<&str> {
+        self.sources.iter().find_map(|source| match &source.kind {
+            SourceKind::Playlist(playlist_id) => Some(playlist_id.as_str()),
+            _ => None,
+        })
+    }
+
+    // The next `limit` tracks, in the same order `load_next_track` will pick them
+    pub fn upcoming(&self, limit: usize) -> Vec<UpcomingTrack> {
+        let inserted = self
+            .inserted_tracks_ids
+            .iter()
+            .map(|id| (*id, UpcomingSource::Inserted));
+        let requested = self
+            .requests
+            .queued()
+            .into_iter()
+            .map(|r| (r.track_id, UpcomingSource::Request));
+        let playlist = self
+            .rotation
+            .upcoming()
+            .map(|id| (id, UpcomingSource::Playlist));
+
+        inserted
+            .chain(requested)
+            .chain(playlist)
+            .take(limit)
+            .map(|(track_id, source)| UpcomingTrack { track_id, source })
+            .collect()
+    }
+
+    pub fn remove_upcoming(&mut self, track_id: u64) -> bool {
+        let inserted = self.inserted_tracks_ids.len();
+        self.inserted_tracks_ids.retain(|id| *id != track_id);
+        let removed_planned = self.rotation.remove(track_id);
+        let removed_request = self.requests.remove_track(track_id);
+        removed_request || removed_planned || inserted != self.inserted_tracks_ids.len()
+    }
+
+    pub fn playlist_size(&self) -> usize {
+        self.rotation.len()
+    }
+
+    // Tracks still to be played before the sources start over
+    pub fn remaining_tracks(&self) -> usize {
+        self.rotation.remaining()
+    }
+
+    // Lets the listeners' reactions weigh on how often tracks come up
+    pub fn set_track_weights(&mut self, weights: HashMap<u64, f64>) {
+        self.rotation.set_weights(weights);
+    }
+
+    pub fn queue_depth(&self) -> usize {
+        self.inserted_tracks_ids.len() + self.requests.queued().len()
+    }
+
+    pub fn client_id_timestamp(&self) -> DateTime<Utc> {
+        self.client_id_timestamp
+    }
+
+    pub fn insert_next(&mut self, track_id: u64) {
+        self.inserted_tracks_ids.push_back(track_id);
+    }
+
+    // Airs the current track again from its beginning, at `started_at`
+    pub fn rewind_current_track(&mut self, started_at: DateTime<Utc>) {
+        if let Some(track) = self.current_track.as_mut() {
+            track.started_at = started_at;
+        }
+    }
+
+    // Whether the track can be requested, before resolving its permalink if it's given by one
+    pub fn check_request(&self, track: &RequestedTrack) -> Result<(), Error> {
+        if !self.requests.config().enabled {
+            return Err(Error::RequestsDisabled);
+        }
+        match track {
+            RequestedTrack::Id(id) if !self.rotation.contains(*id) => {
+                Err(Error::RequestTrackNotInPlaylist(*id))
+            }
+            RequestedTrack::Url(_) if !self.requests.config().allow_urls => {
+                Err(Error::RequestUrlNotAllowed)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn request_track(&mut self, track_id: u64, requester: &str) -> Result<SongRequest, Error> {
+        let last_played_at = self.last_played.get(&track_id).copied();
+        self.requests.submit(track_id, requester, last_played_at)
+    }
+
+    pub fn approve_request(&mut self, request_id: &str) -> Result<SongRequest, Error> {
+        self.requests.approve(request_id)
+    }
+
+    pub fn reject_request(&mut self, request_id: &str) -> Result<SongRequest, Error> {
+        self.requests.reject(request_id)
+    }
+
+    pub fn queued_requests(&self) -> Vec<SongRequest> {
+        self.requests.queued()
+    }
+
+    pub fn pending_requests(&self) -> Vec<SongRequest> {
+        self.requests.pending()
+    }
+
+    // Tracks inserted by the operators, then listeners' requests, always come before the shuffled
+    // playlist. The client id is renewed daily, and the sources are fetched again when the
+    // rotation is empty.
+    async fn pop_next_track_id(player: &Mutex<Self>) -> Result<u64, Error> {
+        let client_id_age =
+            Utc::now().signed_duration_since(player.lock().await.client_id_timestamp);
+        if client_id_age.num_days() >= 1 {
+            Self::refresh_client_id(player).await?;
+        }
+
+        let refresh = {
+            let mut media_player = player.lock().await;
+            if let Some(track_id) = media_player
+                .inserted_tracks_ids
+                .pop_front()
+                .or_else(|| media_player.requests.pop().map(|request| request.track_id))
+            {
+                media_player.rotation.remove(track_id);
+                return Ok(track_id);
+            }
+            if !media_player.rotation.is_empty() {
+                return media_player.rotation.pop().ok_or(Error::PlaylistEmpty);
+            }
+            media_player.refresh_sources()
+        };
+        let fetched = refresh.run().await;
+        let mut media_player = player.lock().await;
+        media_player.apply_sources(fetched)?;
+        media_player.rotation.pop().ok_or(Error::PlaylistEmpty)
+    }
+
+    fn air(&mut self, track: &Track, starts_at: DateTime<Utc>) -> Result<(), Error> {
+        self.current_track = Some(CurrentTrack::new(track, starts_at)?);
+        self.track_played(track.id);
+        self.metrics.tracks_aired.inc();
+        self.health.track_loaded();
         Ok(())
     }
+
+    // Remember when tracks have been aired, so that they can't be requested again too soon.
+    fn track_played(&mut self, track_id: u64) {
+        let now = Utc::now();
+        let cooldown = Duration::seconds(self.requests.config().cooldown_secs);
+        self.last_played
+            .retain(|_, played_at| now.signed_duration_since(*played_at) < cooldown);
+        self.last_played.insert(track_id, now);
+    }
+
+    // The local track, or the prefetched one when it's still fresh. `None` when it has to be
+    // resolved.
+    fn take_resolved(&mut self, track_id: u64) -> Option<Result<Track, Error>> {
+        if library::is_local(track_id) {
+            return Some(match self.library.get(track_id) {
+                Some(track) => Ok(track.to_track()),
+                None => Err(Error::MediaNotFound(track_id)),
+            });
+        }
+
+        let max_age = Duration::seconds(self.prefetch_config.max_age_secs);
+        match self.prefetched.remove(&track_id) {
+            Some(prefetched)
+                if Utc::now().signed_duration_since(prefetched.resolved_at) < max_age =>
+            {
+                self.metrics.prefetch_hits.inc();
+                Some(Ok(prefetched.track))
+            }
+            _ => {
+                self.metrics.prefetch_misses.inc();
+                None
+            }
+        }
+    }
+}
+
+fn weights(sources: &[SourceSpec]) -> Vec<u32> {
+    sources.iter().map(|source| source.weight).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{AudioCacheConfig, RequestsConfig};
+
+    fn track(id: u64, url: Option<&str>) -> Track {
+        Track {
+            id,
+            permalink_url: None,
+            artwork_url: None,
+            duration: Some(180_000),
+            title: Some(format!("Track {}", id)),
+            artist: None,
+            artist_permalink: None,
+            url: url.map(str::to_string),
+            token: None,
+            transcoding: None,
+            metadata: Default::default(),
+        }
+    }
+
+    // A station without an emergency playlist, whose tracks are all resolved already
+    fn player(tracks: Vec<Track>, threshold: usize) -> Mutex<MediaPlayer> {
+        let mut rotation = Rotation::new(&[1]);
+        rotation.update(vec![Some(tracks.iter().map(|track| track.id).collect())]);
+        let resolved_at = Utc::now();
+        let prefetched = tracks
+            .into_iter()
+            .map(|track| (track.id, PrefetchedTrack { track, resolved_at }))
+            .collect();
+        Mutex::new(MediaPlayer {
+            sources: vec![],
+            client_id: String::from("client_id"),
+            client_id_timestamp: Utc::now(),
+            api: ApiClient::default(),
+            rotation,
+            inserted_tracks_ids: VecDeque::new(),
+            requests: RequestQueue::new(RequestsConfig::default()),
+            last_played: HashMap::new(),
+            prefetch_config: PrefetchConfig::default(),
+            prefetched,
+            library: LocalLibrary::new(vec![]),
+            audio_cache: Arc::new(AudioCache::new(
+                &AudioCacheConfig {
+                    max_mb: 0,
+                    ..AudioCacheConfig::default()
+                },
+                Arc::default(),
+            )),
+            failover_config: FailoverConfig {
+                threshold,
+                ..FailoverConfig::default()
+            },
+            fallback_library: LocalLibrary::new(vec![]),
+            fallback_tracks_ids: vec![],
+            source: Source::Primary,
+            health: Arc::default(),
+            stats: Arc::default(),
+            metrics: Arc::default(),
+            current_track: None,
+        })
+    }
+
+    #[tokio::test]
+    async fn skips_unplayable_tracks_past_the_failover_threshold() {
+        // Tracks without a stream url, e.g. with HLS transcodings only
+        let mut tracks: Vec<Track> = (1..=3).map(|id| track(id, None)).collect();
+        tracks.push(track(4, Some("https://cf-media.sndcdn.com/4.mp3")));
+        let player = player(tracks, 2);
+        // The playable track comes last
+        for id in (1..=4).rev() {
+            player.lock().await.rotation.requeue(id);
+        }
+
+        MediaPlayer::load_next_track(&player, Utc::now())
+            .await
+            .unwrap();
+        let player = player.lock().await;
+        assert_eq!(player.current_track.as_ref().unwrap().id, 4);
+        assert_eq!(player.source, Source::Primary);
+    }
+
+    #[tokio::test]
+    async fn gives_up_once_every_track_failed() {
+        let player = player((1..=3).map(|id| track(id, None)).collect(), 10);
+
+        assert!(matches!(
+            MediaPlayer::load_next_track(&player, Utc::now()).await,
+            Err(Error::TrackIncomplete(_, "url"))
+        ));
+        let player = player.lock().await;
+        assert!(player.current_track.is_none());
+        // The tracks are still there, for when they're fixed
+        assert_eq!(player.rotation.len(), 3);
+    }
 }
@@ -0,0 +1,130 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+// Points the waveforms are reduced to, plenty for a progress bar
+pub const WAVEFORM_POINTS: usize = 100;
+
+// Artwork sizes offered to the listeners, smallest first. SoundCloud returns `-large` urls, the
+// other sizes are the same url with another suffix.
+const ARTWORK_SIZES: [(&str, &str); 3] = [
+    ("large", "100x100"),
+    ("t300x300", "300x300"),
+    ("t500x500", "500x500"),
+];
+
+// An artwork in the shape `MediaSession` expects
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ArtworkImage {
+    pub src: String,
+    pub sizes: String,
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    pub media_type: Option<String>,
+}
+
+// Descriptive data about a track, for SoundCloud and local tracks alike. Everything is optional:
+// SoundCloud leaves out a lot depending on the track and its uploader.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TrackMetadata {
+    // Smallest first
+    pub artwork: Vec<ArtworkImage>,
+    pub genre: Option<String>,
+    pub tags: Vec<String>,
+    pub description: Option<String>,
+    pub label: Option<String>,
+    pub album: Option<String>,
+    pub release_date: Option<String>,
+    pub explicit: Option<bool>,
+    pub waveform_url: Option<String>,
+    // Loudness from 0 to 1, `WAVEFORM_POINTS` of them, empty until it's been fetched
+    pub waveform: Vec<f32>,
+}
+
+impl TrackMetadata {
+    // The largest artwork
+    pub fn artwork_url(&self) -> Option<&str> {
+        self.artwork.last().map(|image| image.src.as_str())
+    }
+}
+
+// A SoundCloud artwork in all the `ARTWORK_SIZES`, or as is when it's not a `-large` url
+pub fn soundcloud_artwork(url: &str) -> Vec<ArtworkImage> {
+    let media_type = image_type(url);
+    let (base, extension) = match url.rsplit_once("-large.") {
+        Some(parts) => parts,
+        None => {
+            return vec![ArtworkImage {
+                src: url.to_string(),
+                sizes: String::from("any"),
+                media_type,
+            }]
+        }
+    };
+    ARTWORK_SIZES
+        .iter()
+        .map(|(suffix, sizes)| ArtworkImage {
+            src: format!("{}-{}.{}", base, suffix, extension),
+            sizes: sizes.to_string(),
+            media_type: media_type.clone(),
+        })
+        .collect()
+}
+
+fn image_type(url: &str) -> Option<String> {
+    let path = url.split('?').next().unwrap_or_default();
+    match path.rsplit_once('.').map(|(_, extension)| extension) {
+        Some("jpg" | "jpeg") => Some(String::from("image/jpeg")),
+        Some("png") => Some(String::from("image/png")),
+        _ => None,
+    }
+}
+
+// SoundCloud's tag lists are space separated, tags with spaces are quoted, e.g.
+// `house "deep house" chill`
+pub fn parse_tags(tag_list: &str) -> Vec<String> {
+    let mut tags = vec![];
+    let mut quoted = false;
+    for (index, part) in tag_list.split('"').enumerate() {
+        quoted = index % 2 == 1;
+        match quoted {
+            true => tags.push(part.trim().to_string()),
+            false => tags.extend(part.split_whitespace().map(str::to_string)),
+        }
+    }
+    if quoted {
+        tracing::debug!("unbalanced quotes in tag list `{}`", tag_list);
+    }
+    tags.retain(|tag| !tag.is_empty());
+    tags.dedup();
+    tags
+}
+
+// Reduces SoundCloud's waveform samples, from 0 to `height`, to `WAVEFORM_POINTS` points from 0
+// to 1, keeping the peak of each slice.
+pub fn reduce_waveform(samples: &[u32], height: u32) -> Vec<f32> {
+    if samples.is_empty() || height == 0 {
+        return vec![];
+    }
+    let points = WAVEFORM_POINTS.min(samples.len());
+    (0..points)
+        .map(|point| {
+            let start = point * samples.len() / points;
+            let end = ((point + 1) * samples.len() / points).max(start + 1);
+            let peak = samples[start..end].iter().copied().max().unwrap_or(0);
+            let level = (peak.min(height) as f32) / (height as f32);
+            (level * 100.0).round() / 100.0
+        })
+        .collect()
+}
+
+// A non-empty string
+pub fn text(value: Option<String>) -> Option<String> {
+    value
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+}
+
+// A non-empty string field of a JSON object
+pub fn text_field(value: Option<&Value>, field: &str) -> Option<String> {
+    text(value?.get(field)?.as_str().map(str::to_string))
+}
@@ -0,0 +1,410 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+// Latency buckets (in seconds) for SoundCloud requests
+const LATENCY_BUCKETS: [f64; 10] = [0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0];
+
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add(&self, value: u64) {
+        self.0.fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Gauge(AtomicI64);
+
+impl Gauge {
+    pub fn set(&self, value: i64) {
+        self.0.store(value, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug)]
+pub struct Histogram {
+    buckets: Vec<AtomicU64>,
+    sum_micros: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            buckets: LATENCY_BUCKETS.iter().map(|_| AtomicU64::new(0)).collect(),
+            sum_micros: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Histogram {
+    pub fn observe(&self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(self.buckets.iter()) {
+            if secs <= *bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(self.buckets.iter()) {
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{},le=\"{}\"}} {}",
+                name,
+                labels,
+                bound,
+                bucket.load(Ordering::Relaxed)
+            );
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        let _ = writeln!(out, "{}_bucket{{{},le=\"+Inf\"}} {}", name, labels, count);
+        let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, sum);
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, count);
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    pub listeners: Gauge,
+    pub listeners_peak: Gauge,
+    pub unique_listeners: Gauge,
+    // How late the last track went on air, compared to its planned start
+    pub playout_lateness_ms: Gauge,
+    pub ws_connects: Counter,
+    pub ws_disconnects: Counter,
+    pub tracks_aired: Counter,
+    pub tracks_skipped_resolve_error: Counter,
+    pub client_id_rotations: Counter,
+    pub playlist_reloads: Counter,
+    // Tracks that joined or left the rotation when the sources were synced
+    pub playlist_tracks_added: Counter,
+    pub playlist_tracks_removed: Counter,
+    pub broadcast_send_failures: Counter,
+    pub messages_dropped: Counter,
+    pub messages_coalesced: Counter,
+    pub clients_evicted: Counter,
+    pub prefetch_hits: Counter,
+    pub prefetch_misses: Counter,
+    // How much the schedule slid because tracks went on air too late
+    pub playout_drift_ms: Counter,
+    // How late tracks went on air, summed up, including the ones that didn't get rescheduled
+    pub playout_late_ms: Counter,
+    pub audio_cache_hits: Counter,
+    pub audio_cache_misses: Counter,
+    pub audio_cache_bytes: Gauge,
+    // Tracks aired from the emergency playlist or the cache because SoundCloud couldn't resolve them
+    pub fallback_tracks: Counter,
+    pub failovers: Counter,
+    // 1 while the station airs the emergency playlist
+    pub on_fallback: Gauge,
+    // 0 closed, 1 half-open, 2 open
+    pub breaker_state: Gauge,
+    pub breaker_trips: Counter,
+    pub soundcloud_calls_rejected: Counter,
+    pub playout_crashes: Counter,
+    pub playout_restarts: Counter,
+    // Time listeners spent connected while the station was on air
+    pub listening_seconds: Counter,
+    pub chat_messages: Counter,
+    // Chat messages with blocked words masked
+    pub chat_messages_filtered: Counter,
+    // SoundCloud request latencies, by response status
+    soundcloud_requests: Mutex<BTreeMap<String, Histogram>>,
+}
+
+impl Metrics {
+    pub fn set_listeners(&self, count: usize) {
+        self.listeners.set(count as i64);
+        self.listeners_peak
+            .0
+            .fetch_max(count as i64, Ordering::Relaxed);
+    }
+
+    pub fn observe_soundcloud_request(&self, status: &str, duration: Duration) {
+        let mut requests = self.soundcloud_requests.lock().unwrap();
+        requests
+            .entry(status.to_string())
+            .or_default()
+            .observe(duration);
+    }
+
+    // Renders all the metrics in Prometheus text format, labelled with the station name.
+    pub fn render(&self, station: &str) -> String {
+        let station = format!("station=\"{}\"", escape_label(station));
+        let mut out = String::new();
+
+        let gauges = [
+            ("robo_radio_listeners", "Current listeners", &self.listeners),
+            (
+                "robo_radio_listeners_peak",
+                "Peak listeners since start",
+                &self.listeners_peak,
+            ),
+            (
+                "robo_radio_unique_listeners",
+                "Unique listeners today",
+                &self.unique_listeners,
+            ),
+            (
+                "robo_radio_playout_lateness_milliseconds",
+                "How late the current track went on air",
+                &self.playout_lateness_ms,
+            ),
+            (
+                "robo_radio_audio_cache_bytes",
+                "Size of the cached audio",
+                &self.audio_cache_bytes,
+            ),
+            (
+                "robo_radio_on_fallback",
+                "Whether the station airs the emergency playlist",
+                &self.on_fallback,
+            ),
+            (
+                "robo_radio_soundcloud_breaker_state",
+                "SoundCloud circuit breaker state (0 closed, 1 half-open, 2 open)",
+                &self.breaker_state,
+            ),
+        ];
+        for (name, help, gauge) in gauges {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} gauge", name);
+            let _ = writeln!(out, "{}{{{}}} {}", name, station, gauge.get());
+        }
+
+        let counters = [
+            (
+                "robo_radio_ws_connects_total",
+                "WebSocket connections",
+                &self.ws_connects,
+            ),
+            (
+                "robo_radio_ws_disconnects_total",
+                "WebSocket disconnections",
+                &self.ws_disconnects,
+            ),
+            (
+                "robo_radio_tracks_aired_total",
+                "Tracks aired",
+                &self.tracks_aired,
+            ),
+            (
+                "robo_radio_tracks_skipped_total",
+                "Tracks skipped because of resolve errors",
+                &self.tracks_skipped_resolve_error,
+            ),
+            (
+                "robo_radio_client_id_rotations_total",
+                "SoundCloud client id rotations",
+                &self.client_id_rotations,
+            ),
+            (
+                "robo_radio_playlist_reloads_total",
+                "Playlist reloads",
+                &self.playlist_reloads,
+            ),
+            (
+                "robo_radio_playlist_tracks_added_total",
+                "Tracks added to the rotation by syncs",
+                &self.playlist_tracks_added,
+            ),
+            (
+                "robo_radio_playlist_tracks_removed_total",
+                "Tracks removed from the rotation by syncs",
+                &self.playlist_tracks_removed,
+            ),
+            (
+                "robo_radio_broadcast_send_failures_total",
+                "Messages that couldn't be sent to clients",
+                &self.broadcast_send_failures,
+            ),
+            (
+                "robo_radio_messages_dropped_total",
+                "Messages dropped because a client's queue was full",
+                &self.messages_dropped,
+            ),
+            (
+                "robo_radio_messages_coalesced_total",
+                "Queued messages superseded by a newer one",
+                &self.messages_coalesced,
+            ),
+            (
+                "robo_radio_clients_evicted_total",
+                "Clients disconnected for being too slow",
+                &self.clients_evicted,
+            ),
+            (
+                "robo_radio_prefetch_hits_total",
+                "Tracks aired from the prefetched ones",
+                &self.prefetch_hits,
+            ),
+            (
+                "robo_radio_prefetch_misses_total",
+                "Tracks that had to be resolved when going on air",
+                &self.prefetch_misses,
+            ),
+            (
+                "robo_radio_playout_drift_milliseconds_total",
+                "Cumulative drift of the playout schedule",
+                &self.playout_drift_ms,
+            ),
+            (
+                "robo_radio_playout_late_milliseconds_total",
+                "Cumulative lateness of the tracks going on air",
+                &self.playout_late_ms,
+            ),
+            (
+                "robo_radio_audio_cache_hits_total",
+                "Tracks served from the audio cache",
+                &self.audio_cache_hits,
+            ),
+            (
+                "robo_radio_audio_cache_misses_total",
+                "Tracks not found in the audio cache",
+                &self.audio_cache_misses,
+            ),
+            (
+                "robo_radio_fallback_tracks_total",
+                "Tracks aired from the emergency playlist or the audio cache",
+                &self.fallback_tracks,
+            ),
+            (
+                "robo_radio_failovers_total",
+                "Switches to the emergency playlist",
+                &self.failovers,
+            ),
+            (
+                "robo_radio_soundcloud_breaker_trips_total",
+                "Times SoundCloud calls got suspended",
+                &self.breaker_trips,
+            ),
+            (
+                "robo_radio_soundcloud_calls_rejected_total",
+                "SoundCloud calls rejected while suspended",
+                &self.soundcloud_calls_rejected,
+            ),
+            (
+                "robo_radio_playout_crashes_total",
+                "Playout task crashes",
+                &self.playout_crashes,
+            ),
+            (
+                "robo_radio_playout_restarts_total",
+                "Playout task restarts after a crash",
+                &self.playout_restarts,
+            ),
+            (
+                "robo_radio_listening_seconds_total",
+                "Time spent listening while on air",
+                &self.listening_seconds,
+            ),
+            (
+                "robo_radio_chat_messages_total",
+                "Chat messages sent",
+                &self.chat_messages,
+            ),
+            (
+                "robo_radio_chat_messages_filtered_total",
+                "Chat messages with blocked words",
+                &self.chat_messages_filtered,
+            ),
+        ];
+        for (name, help, counter) in counters {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            let _ = writeln!(out, "{}{{{}}} {}", name, station, counter.get());
+        }
+
+        let name = "robo_radio_audio_cache_hit_ratio";
+        let hits = self.audio_cache_hits.get() as f64;
+        let lookups = hits + self.audio_cache_misses.get() as f64;
+        let ratio = if lookups > 0.0 { hits / lookups } else { 0.0 };
+        let _ = writeln!(out, "# HELP {} Share of audio cache lookups that hit", name);
+        let _ = writeln!(out, "# TYPE {} gauge", name);
+        let _ = writeln!(out, "{}{{{}}} {}", name, station, ratio);
+
+        let name = "robo_radio_soundcloud_request_duration_seconds";
+        let _ = writeln!(out, "# HELP {} SoundCloud requests latency by status", name);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        for (status, histogram) in self.soundcloud_requests.lock().unwrap().iter() {
+            let labels = format!("{},status=\"{}\"", station, escape_label(status));
+            histogram.render(&mut out, name, labels.as_str());
+        }
+
+        out
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_listeners_peak() {
+        let metrics = Metrics::default();
+        metrics.set_listeners(3);
+        metrics.set_listeners(1);
+        assert_eq!(metrics.listeners.get(), 1);
+        assert_eq!(metrics.listeners_peak.get(), 3);
+    }
+
+    #[test]
+    fn renders_cumulative_histograms() {
+        let metrics = Metrics::default();
+        metrics.observe_soundcloud_request("200", Duration::from_millis(80));
+        metrics.observe_soundcloud_request("200", Duration::from_millis(400));
+        metrics.observe_soundcloud_request("200", Duration::from_secs(120));
+        let out = metrics.render("robo");
+
+        let name = "robo_radio_soundcloud_request_duration_seconds";
+        let labels = "station=\"robo\",status=\"200\"";
+        for (le, count) in [("0.05", 0), ("0.1", 1), ("0.5", 2), ("60", 2), ("+Inf", 3)] {
+            let line = format!("{}_bucket{{{},le=\"{}\"}} {}", name, labels, le, count);
+            assert!(out.contains(&line), "missing `{}`", line);
+        }
+        assert!(out.contains(&format!("{}_sum{{{}}} 120.48\n", name, labels)));
+        assert!(out.contains(&format!("{}_count{{{}}} 3\n", name, labels)));
+    }
+
+    #[test]
+    fn escapes_label_values() {
+        assert_eq!(escape_label("robo"), "robo");
+        assert_eq!(escape_label("a \"b\" \\ c\nd"), "a \\\"b\\\" \\\\ c\\nd");
+        let out = Metrics::default().render("the \"best\" radio");
+        assert!(out.contains("robo_radio_listeners{station=\"the \\\"best\\\" radio\"} 0\n"));
+    }
+}
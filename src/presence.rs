@@ -0,0 +1,372 @@
+use crate::{error::Error, metrics::Metrics};
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{hash_map::RandomState, HashMap, HashSet},
+    hash::{BuildHasher, Hash, Hasher},
+    sync::Arc,
+};
+use uuid::Uuid;
+
+const MAX_NICKNAME_CHARS: usize = 32;
+const MAX_AVATAR_SEED_CHARS: usize = 64;
+
+// What a listener chose to show the others, nothing by default
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Profile {
+    pub nickname: Option<String>,
+    pub avatar_seed: Option<String>,
+}
+
+impl Profile {
+    // Trims the fields, blank ones are cleared
+    pub fn new(nickname: Option<&str>, avatar_seed: Option<&str>) -> Result<Self, Error> {
+        Ok(Self {
+            nickname: profile_field(nickname, MAX_NICKNAME_CHARS, "nickname")?,
+            avatar_seed: profile_field(avatar_seed, MAX_AVATAR_SEED_CHARS, "avatar_seed")?,
+        })
+    }
+}
+
+fn profile_field(
+    value: Option<&str>,
+    max_chars: usize,
+    field: &'static str,
+) -> Result<Option<String>, Error> {
+    let value = match value.map(str::trim) {
+        Some(value) if !value.is_empty() => value,
+        _ => return Ok(None),
+    };
+    if value.chars().count() > max_chars || value.chars().any(char::is_control) {
+        return Err(Error::InvalidProfile(field, max_chars));
+    }
+    Ok(Some(value.to_string()))
+}
+
+// A listener as shown to the others: a per-session id and whatever profile it set, never its ip
+// nor the id used by admins
+#[derive(Debug, Clone, Serialize)]
+pub struct Listener {
+    pub id: String,
+    pub nickname: Option<String>,
+    pub avatar_seed: Option<String>,
+    pub connected_at: DateTime<Utc>,
+    pub connected_secs: i64,
+    // Time connected while the station was on air
+    pub listening_secs: i64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PresenceTotals {
+    pub listeners: usize,
+    pub date: NaiveDate,
+    pub unique_listeners_today: usize,
+    pub listening_hours_today: f64,
+    pub listening_hours_total: f64,
+}
+
+#[derive(Debug)]
+struct Session {
+    public_id: String,
+    // What makes a listener unique for the day, its connection or its ip when keyed by ip
+    key: String,
+    profile: Profile,
+    connected_at: DateTime<Utc>,
+    listened: Duration,
+    // Start of the current listening span, while the station is on air
+    listening_since: Option<DateTime<Utc>>,
+}
+
+impl Session {
+    fn listening(&self, now: DateTime<Utc>) -> Duration {
+        match self.listening_since {
+            Some(since) => self.listened + now.signed_duration_since(since),
+            None => self.listened,
+        }
+    }
+
+    fn listener(&self, now: DateTime<Utc>) -> Listener {
+        Listener {
+            id: self.public_id.clone(),
+            nickname: self.profile.nickname.clone(),
+            avatar_seed: self.profile.avatar_seed.clone(),
+            connected_at: self.connected_at,
+            connected_secs: now.signed_duration_since(self.connected_at).num_seconds(),
+            listening_secs: self.listening(now).num_seconds(),
+        }
+    }
+}
+
+// Who's listening, and for how long. Unique listeners are counted from salted hashes of their
+// ips, the salt changes every day and is never stored.
+#[derive(Debug)]
+pub struct Presence {
+    sessions: HashMap<String, Session>,
+    on_air: bool,
+    day: NaiveDate,
+    salt: RandomState,
+    uniques: HashSet<u64>,
+    // Listening time of the spans closed so far
+    listened_today: Duration,
+    listened_total: Duration,
+    metrics: Arc<Metrics>,
+}
+
+impl Presence {
+    pub fn new(on_air: bool, now: DateTime<Utc>, metrics: Arc<Metrics>) -> Self {
+        Self {
+            sessions: HashMap::new(),
+            on_air,
+            day: now.date_naive(),
+            salt: RandomState::new(),
+            uniques: HashSet::new(),
+            listened_today: Duration::zero(),
+            listened_total: Duration::zero(),
+            metrics,
+        }
+    }
+
+    pub fn join(&mut self, client_id: &str, key: &str, now: DateTime<Utc>) -> Listener {
+        self.roll_over(now);
+        let session = Session {
+            public_id: Uuid::new_v4().as_simple().to_string(),
+            key: key.to_string(),
+            profile: Profile::default(),
+            connected_at: now,
+            listened: Duration::zero(),
+            listening_since: self.on_air.then_some(now),
+        };
+        self.count_unique(key);
+        let listener = session.listener(now);
+        self.sessions.insert(client_id.to_string(), session);
+        listener
+    }
+
+    pub fn leave(&mut self, client_id: &str, now: DateTime<Utc>) -> Option<Listener> {
+        self.roll_over(now);
+        let mut session = self.sessions.remove(client_id)?;
+        self.close_span(&mut session, now);
+        Some(session.listener(now))
+    }
+
+    pub fn set_profile(
+        &mut self,
+        client_id: &str,
+        profile: Profile,
+        now: DateTime<Utc>,
+    ) -> Option<Listener> {
+        let session = self.sessions.get_mut(client_id)?;
+        session.profile = profile;
+        Some(session.listener(now))
+    }
+
+    pub fn profile(&self, client_id: &str) -> Option<Profile> {
+        self.sessions
+            .get(client_id)
+            .map(|session| session.profile.clone())
+    }
+
+    pub fn listener(&self, client_id: &str, now: DateTime<Utc>) -> Option<Listener> {
+        self.sessions
+            .get(client_id)
+            .map(|session| session.listener(now))
+    }
+
+    // Listening time only counts while the station is on air
+    pub fn set_on_air(&mut self, on_air: bool, now: DateTime<Utc>) {
+        self.roll_over(now);
+        if self.on_air == on_air {
+            return;
+        }
+        self.on_air = on_air;
+        let mut sessions = std::mem::take(&mut self.sessions);
+        for session in sessions.values_mut() {
+            if on_air {
+                session.listening_since = Some(now);
+            } else {
+                self.close_span(session, now);
+            }
+        }
+        self.sessions = sessions;
+    }
+
+    // Oldest listeners first
+    pub fn listeners(&self, now: DateTime<Utc>) -> Vec<Listener> {
+        let mut listeners: Vec<Listener> = self
+            .sessions
+            .values()
+            .map(|session| session.listener(now))
+            .collect();
+        listeners.sort_by_key(|listener| listener.connected_at);
+        listeners
+    }
+
+    pub fn totals(&mut self, now: DateTime<Utc>) -> PresenceTotals {
+        self.roll_over(now);
+        let ongoing = self
+            .sessions
+            .values()
+            .filter_map(|session| session.listening_since)
+            .fold(Duration::zero(), |total, since| {
+                total + now.signed_duration_since(since)
+            });
+        PresenceTotals {
+            listeners: self.sessions.len(),
+            date: self.day,
+            unique_listeners_today: self.uniques.len(),
+            listening_hours_today: hours(self.listened_today + ongoing),
+            listening_hours_total: hours(self.listened_total + ongoing),
+        }
+    }
+
+    // Starts a new day, the current listeners count for it too and their listening time is split
+    // at midnight
+    fn roll_over(&mut self, now: DateTime<Utc>) {
+        let today = now.date_naive();
+        if today == self.day {
+            return;
+        }
+        let midnight = today.and_hms_opt(0, 0, 0).unwrap().and_utc();
+        let mut sessions = std::mem::take(&mut self.sessions);
+        for session in sessions.values_mut() {
+            if session.listening_since.is_some() {
+                self.close_span(session, midnight);
+                session.listening_since = Some(midnight);
+            }
+        }
+
+        self.day = today;
+        self.salt = RandomState::new();
+        self.uniques.clear();
+        self.listened_today = Duration::zero();
+        for session in sessions.values() {
+            self.count_unique(session.key.as_str());
+        }
+        self.sessions = sessions;
+    }
+
+    fn close_span(&mut self, session: &mut Session, now: DateTime<Utc>) {
+        if let Some(since) = session.listening_since.take() {
+            let span = now.signed_duration_since(since).max(Duration::zero());
+            session.listened += span;
+            self.listened_today += span;
+            self.listened_total += span;
+            self.metrics
+                .listening_seconds
+                .add(span.num_seconds() as u64);
+        }
+    }
+
+    fn count_unique(&mut self, key: &str) {
+        let mut hasher = self.salt.build_hasher();
+        key.hash(&mut hasher);
+        self.uniques.insert(hasher.finish());
+        self.metrics.unique_listeners.set(self.uniques.len() as i64);
+    }
+}
+
+fn hours(duration: Duration) -> f64 {
+    duration.num_seconds() as f64 / 3600.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn noon() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2026, 10, 19, 12, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn validates_profiles() {
+        assert_eq!(
+            Profile::new(Some("  robo "), Some(" ")).unwrap(),
+            Profile {
+                nickname: Some(String::from("robo")),
+                avatar_seed: None,
+            }
+        );
+        assert!(matches!(
+            Profile::new(Some(&"a".repeat(MAX_NICKNAME_CHARS + 1)), None),
+            Err(Error::InvalidProfile("nickname", MAX_NICKNAME_CHARS))
+        ));
+        assert!(matches!(
+            Profile::new(None, Some("se\u{7}ed")),
+            Err(Error::InvalidProfile("avatar_seed", MAX_AVATAR_SEED_CHARS))
+        ));
+    }
+
+    #[test]
+    fn counts_unique_listeners_by_key() {
+        let mut presence = Presence::new(true, noon(), Arc::default());
+        presence.join("a", "10.0.0.1", noon());
+        presence.join("b", "10.0.0.1", noon());
+        presence.join("c", "10.0.0.2", noon());
+        presence.leave("c", noon());
+
+        let totals = presence.totals(noon());
+        assert_eq!(totals.listeners, 2);
+        assert_eq!(totals.unique_listeners_today, 2);
+    }
+
+    #[test]
+    fn shows_listeners_without_their_ids() {
+        let mut presence = Presence::new(true, noon(), Arc::default());
+        let first = presence.join("a", "10.0.0.1", noon());
+        presence.join("b", "10.0.0.2", noon() + Duration::seconds(1));
+        assert_ne!(first.id, "a");
+
+        let profile = Profile::new(Some("robo"), None).unwrap();
+        let later = noon() + Duration::seconds(90);
+        let listener = presence.set_profile("a", profile.clone(), later).unwrap();
+        assert_eq!(listener.id, first.id);
+        assert_eq!(listener.nickname.as_deref(), Some("robo"));
+        assert_eq!(listener.connected_secs, 90);
+        assert_eq!(presence.profile("a"), Some(profile));
+        assert!(presence
+            .set_profile("unknown", Profile::default(), later)
+            .is_none());
+
+        let ids: Vec<String> = presence
+            .listeners(later)
+            .into_iter()
+            .map(|l| l.id)
+            .collect();
+        assert_eq!(ids[0], first.id);
+        assert_eq!(ids.len(), 2);
+    }
+
+    #[test]
+    fn counts_listening_time_while_on_air() {
+        let mut presence = Presence::new(false, noon(), Arc::default());
+        presence.join("a", "10.0.0.1", noon());
+        presence.set_on_air(true, noon() + Duration::minutes(10));
+        presence.set_on_air(false, noon() + Duration::minutes(40));
+        presence.set_on_air(true, noon() + Duration::minutes(50));
+
+        let left = presence.leave("a", noon() + Duration::minutes(80)).unwrap();
+        assert_eq!(left.connected_secs, 80 * 60);
+        assert_eq!(left.listening_secs, 60 * 60);
+        let totals = presence.totals(noon() + Duration::minutes(90));
+        assert_eq!(totals.listening_hours_today, 1.0);
+        assert_eq!(totals.listening_hours_total, 1.0);
+    }
+
+    #[test]
+    fn splits_the_days_at_midnight() {
+        let evening = Utc.with_ymd_and_hms(2026, 10, 19, 23, 0, 0).unwrap();
+        let mut presence = Presence::new(true, evening, Arc::default());
+        presence.join("a", "10.0.0.1", evening);
+        presence.join("b", "10.0.0.2", evening);
+        presence.leave("b", evening + Duration::minutes(30));
+
+        let morning = evening + Duration::hours(3);
+        let totals = presence.totals(morning);
+        assert_eq!(totals.date, morning.date_naive());
+        // Only the listener still connected counts for the new day
+        assert_eq!(totals.unique_listeners_today, 1);
+        assert_eq!(totals.listening_hours_today, 2.0);
+        assert_eq!(totals.listening_hours_total, 3.5);
+    }
+}
@@ -0,0 +1,340 @@
+use crate::{config::RequestsConfig, error::Error, soundcloud::permalink};
+use anyhow::Result;
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use uuid::Uuid;
+
+// What a listener asked for: either a track id from the playlist or a SoundCloud URL.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RequestedTrack {
+    Id(u64),
+    Url(String),
+}
+
+impl RequestedTrack {
+    pub fn parse(input: &str) -> Result<Self, Error> {
+        let input = input.trim();
+        if let Ok(id) = input.parse::<u64>() {
+            return Ok(Self::Id(id));
+        }
+
+        permalink(input)
+            .map(Self::Url)
+            .ok_or_else(|| Error::RequestInvalidTrack(input.to_string()))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RequestStatus {
+    Pending,
+    Queued,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SongRequest {
+    pub id: String,
+    pub track_id: u64,
+    #[serde(skip_serializing)]
+    pub requester: String,
+    pub requested_at: DateTime<Utc>,
+    pub status: RequestStatus,
+}
+
+// Listeners' requests: approved ones are played before the shuffled playlist, the others wait
+// for a moderator when moderation is enabled.
+#[derive(Debug, Clone, Default)]
+pub struct RequestQueue {
+    config: RequestsConfig,
+    queued: VecDeque<SongRequest>,
+    pending: Vec<SongRequest>,
+    submissions: HashMap<String, VecDeque<DateTime<Utc>>>,
+}
+
+impl RequestQueue {
+    pub fn new(config: RequestsConfig) -> Self {
+        Self {
+            config,
+            ..Default::default()
+        }
+    }
+
+    pub fn config(&self) -> &RequestsConfig {
+        &self.config
+    }
+
+    pub fn submit(
+        &mut self,
+        track_id: u64,
+        requester: &str,
+        last_played_at: Option<DateTime<Utc>>,
+    ) -> Result<SongRequest, Error> {
+        if !self.config.enabled {
+            return Err(Error::RequestsDisabled);
+        }
+
+        let now = Utc::now();
+        if self.contains(track_id) {
+            return Err(Error::RequestDuplicate(track_id));
+        }
+        if let Some(played_at) = last_played_at {
+            if now.signed_duration_since(played_at) < Duration::seconds(self.config.cooldown_secs) {
+                return Err(Error::RequestCooldown(track_id));
+            }
+        }
+        if self.queued.len() + self.pending.len() >= self.config.max_queue {
+            return Err(Error::RequestQueueFull);
+        }
+        self.check_rate_limit(requester, now)?;
+
+        let status = if self.config.moderation {
+            RequestStatus::Pending
+        } else {
+            RequestStatus::Queued
+        };
+        let request = SongRequest {
+            id: Uuid::new_v4().as_simple().to_string(),
+            track_id,
+            requester: requester.to_string(),
+            requested_at: now,
+            status,
+        };
+
+        match status {
+            RequestStatus::Pending => self.pending.push(request.clone()),
+            RequestStatus::Queued => self.queued.push_back(request.clone()),
+        }
+        tracing::info!(
+            "track {} requested by {} ({:?})",
+            track_id,
+            requester,
+            status
+        );
+
+        Ok(request)
+    }
+
+    pub fn approve(&mut self, request_id: &str) -> Result<SongRequest, Error> {
+        let mut request = self.take_pending(request_id)?;
+        request.status = RequestStatus::Queued;
+        self.queued.push_back(request.clone());
+        tracing::info!(
+            "request {} for track {} approved",
+            request.id,
+            request.track_id
+        );
+        Ok(request)
+    }
+
+    pub fn reject(&mut self, request_id: &str) -> Result<SongRequest, Error> {
+        let request = self.take_pending(request_id)?;
+        tracing::info!(
+            "request {} for track {} rejected",
+            request.id,
+            request.track_id
+        );
+        Ok(request)
+    }
+
+    pub fn pop(&mut self) -> Option<SongRequest> {
+        self.queued.pop_front()
+    }
+
+    // Drops any queued or pending request for the given track
+    pub fn remove_track(&mut self, track_id: u64) -> bool {
+        let count = self.queued.len() + self.pending.len();
+        self.queued.retain(|r| r.track_id != track_id);
+        self.pending.retain(|r| r.track_id != track_id);
+        count != self.queued.len() + self.pending.len()
+    }
+
+    pub fn queued(&self) -> Vec<SongRequest> {
+        self.queued.iter().cloned().collect()
+    }
+
+    pub fn pending(&self) -> Vec<SongRequest> {
+        self.pending.clone()
+    }
+
+    fn contains(&self, track_id: u64) -> bool {
+        self.queued
+            .iter()
+            .chain(self.pending.iter())
+            .any(|r| r.track_id == track_id)
+    }
+
+    fn take_pending(&mut self, request_id: &str) -> Result<SongRequest, Error> {
+        match self.pending.iter().position(|r| r.id == request_id) {
+            Some(idx) => Ok(self.pending.remove(idx)),
+            None => Err(Error::RequestNotFound(request_id.to_string())),
+        }
+    }
+
+    // Sliding window: at most `per_client_limit` submissions every `per_client_window_secs`.
+    fn check_rate_limit(&mut self, requester: &str, now: DateTime<Utc>) -> Result<(), Error> {
+        let window = Duration::seconds(self.config.per_client_window_secs);
+        self.submissions.retain(|_, times| {
+            times
+                .back()
+                .map_or(false, |t| now.signed_duration_since(*t) < window)
+        });
+
+        let times = self.submissions.entry(requester.to_string()).or_default();
+        while times
+            .front()
+            .map_or(false, |t| now.signed_duration_since(*t) >= window)
+        {
+            times.pop_front();
+        }
+        if times.len() >= self.config.per_client_limit {
+            return Err(Error::RequestRateLimited);
+        }
+        times.push_back(now);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue(config: RequestsConfig) -> RequestQueue {
+        RequestQueue::new(RequestsConfig {
+            per_client_limit: 10,
+            ..config
+        })
+    }
+
+    #[test]
+    fn parses_ids_and_permalinks() {
+        assert_eq!(
+            RequestedTrack::parse(" 42 ").unwrap(),
+            RequestedTrack::Id(42)
+        );
+        assert_eq!(
+            RequestedTrack::parse("https://m.soundcloud.com/artist/track?si=abc").unwrap(),
+            RequestedTrack::Url(String::from("https://soundcloud.com/artist/track"))
+        );
+        assert!(matches!(
+            RequestedTrack::parse("https://example.com/track"),
+            Err(Error::RequestInvalidTrack(_))
+        ));
+    }
+
+    #[test]
+    fn plays_requests_in_order() {
+        let mut queue = queue(RequestsConfig::default());
+        let first = queue.submit(1, "10.0.0.1", None).unwrap();
+        queue.submit(2, "10.0.0.2", None).unwrap();
+        assert_eq!(first.status, RequestStatus::Queued);
+        assert!(matches!(
+            queue.submit(1, "10.0.0.3", None),
+            Err(Error::RequestDuplicate(1))
+        ));
+
+        assert_eq!(queue.pop().unwrap().track_id, 1);
+        assert_eq!(queue.pop().unwrap().track_id, 2);
+        assert!(queue.pop().is_none());
+        // Played requests can be asked for again
+        assert!(queue.submit(1, "10.0.0.3", None).is_ok());
+    }
+
+    #[test]
+    fn refuses_tracks_played_recently() {
+        let mut queue = queue(RequestsConfig::default());
+        let recently = Utc::now() - Duration::minutes(10);
+        assert!(matches!(
+            queue.submit(1, "10.0.0.1", Some(recently)),
+            Err(Error::RequestCooldown(1))
+        ));
+        let long_ago = Utc::now() - Duration::hours(2);
+        assert!(queue.submit(1, "10.0.0.1", Some(long_ago)).is_ok());
+    }
+
+    #[test]
+    fn bounds_the_queue() {
+        let mut queue = queue(RequestsConfig {
+            max_queue: 2,
+            moderation: true,
+            ..RequestsConfig::default()
+        });
+        queue.submit(1, "10.0.0.1", None).unwrap();
+        queue.submit(2, "10.0.0.1", None).unwrap();
+        assert!(matches!(
+            queue.submit(3, "10.0.0.1", None),
+            Err(Error::RequestQueueFull)
+        ));
+
+        let mut queue = RequestQueue::new(RequestsConfig {
+            enabled: false,
+            ..RequestsConfig::default()
+        });
+        assert!(matches!(
+            queue.submit(1, "10.0.0.1", None),
+            Err(Error::RequestsDisabled)
+        ));
+    }
+
+    #[test]
+    fn waits_for_moderation() {
+        let mut queue = queue(RequestsConfig {
+            moderation: true,
+            ..RequestsConfig::default()
+        });
+        let approved = queue.submit(1, "10.0.0.1", None).unwrap();
+        let rejected = queue.submit(2, "10.0.0.1", None).unwrap();
+        assert_eq!(approved.status, RequestStatus::Pending);
+        assert!(queue.pop().is_none());
+
+        let approved = queue.approve(approved.id.as_str()).unwrap();
+        assert_eq!(approved.status, RequestStatus::Queued);
+        assert_eq!(queue.reject(rejected.id.as_str()).unwrap().track_id, 2);
+        assert!(matches!(
+            queue.approve(rejected.id.as_str()),
+            Err(Error::RequestNotFound(_))
+        ));
+        assert!(queue.pending().is_empty());
+        assert_eq!(queue.pop().unwrap().track_id, 1);
+    }
+
+    #[test]
+    fn removes_the_requests_of_a_track() {
+        let mut queue = queue(RequestsConfig::default());
+        queue.submit(1, "10.0.0.1", None).unwrap();
+        queue.submit(2, "10.0.0.1", None).unwrap();
+        assert!(queue.remove_track(1));
+        assert!(!queue.remove_track(1));
+        let queued: Vec<u64> = queue.queued().iter().map(|r| r.track_id).collect();
+        assert_eq!(queued, vec![2]);
+    }
+
+    #[test]
+    fn rate_limits_requesters_over_a_sliding_window() {
+        let mut queue = RequestQueue::new(RequestsConfig {
+            per_client_limit: 2,
+            per_client_window_secs: 60,
+            ..RequestsConfig::default()
+        });
+        let now = Utc::now();
+        assert!(queue.check_rate_limit("10.0.0.1", now).is_ok());
+        assert!(queue
+            .check_rate_limit("10.0.0.1", now + Duration::seconds(30))
+            .is_ok());
+        assert!(matches!(
+            queue.check_rate_limit("10.0.0.1", now + Duration::seconds(59)),
+            Err(Error::RequestRateLimited)
+        ));
+        assert!(queue
+            .check_rate_limit("10.0.0.2", now + Duration::seconds(59))
+            .is_ok());
+        // The first request left the window, the second one is still in it
+        assert!(queue
+            .check_rate_limit("10.0.0.1", now + Duration::seconds(60))
+            .is_ok());
+        assert!(matches!(
+            queue.check_rate_limit("10.0.0.1", now + Duration::seconds(61)),
+            Err(Error::RequestRateLimited)
+        ));
+    }
+}
@@ -0,0 +1,334 @@
+use rand::{seq::SliceRandom, thread_rng, Rng};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet, VecDeque};
+
+// Tracks planned ahead, enough for the prefetching and the admin queue
+const LOOKAHEAD: usize = 20;
+
+#[derive(Debug, Clone, Default)]
+struct RotationSource {
+    weight: u32,
+    tracks: Vec<u64>,
+    // Tracks not played yet in this source's cycle, next first
+    queue: VecDeque<u64>,
+}
+
+// Tracks that joined or left the rotation
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct RotationDiff {
+    pub added: Vec<u64>,
+    pub removed: Vec<u64>,
+}
+
+impl RotationDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty()
+    }
+}
+
+// Weighted mix of the station's sources. Each source cycles through its own shuffled tracks, and
+// the next track is picked from a source chosen at random according to the weights, so that a
+// source weighted 2 gets about twice the airtime of one weighted 1.
+#[derive(Debug, Clone, Default)]
+pub struct Rotation {
+    sources: Vec<RotationSource>,
+    // Planned tracks, next first
+    upcoming: VecDeque<u64>,
+    // Tracks gone from SoundCloud, left out even if a source still lists them
+    purged: HashSet<u64>,
+    // How often tracks come up, relative to the others of their source
+    weights: HashMap<u64, f64>,
+}
+
+impl Rotation {
+    pub fn new(weights: &[u32]) -> Self {
+        Self {
+            sources: weights
+                .iter()
+                .map(|weight| RotationSource {
+                    weight: *weight,
+                    ..RotationSource::default()
+                })
+                .collect(),
+            upcoming: VecDeque::new(),
+            purged: HashSet::new(),
+            weights: HashMap::new(),
+        }
+    }
+
+    // Merges the tracks listed by each source, `None` keeping the ones it had. A track listed by
+    // several sources belongs to the first one. Planned tracks keep their place, and new tracks
+    // are spread at random through their source's cycle, so nothing gets reshuffled.
+    pub fn update(&mut self, listed: Vec<Option<Vec<u64>>>) {
+        let mut rng = thread_rng();
+        let mut seen = self.purged.clone();
+        for (source, listed) in self.sources.iter_mut().zip(listed) {
+            let listed = listed.unwrap_or_else(|| source.tracks.clone());
+            let tracks: Vec<u64> = listed.into_iter().filter(|id| seen.insert(*id)).collect();
+
+            let current: HashSet<u64> = tracks.iter().copied().collect();
+            let previous: HashSet<u64> = source.tracks.iter().copied().collect();
+            source.queue.retain(|id| current.contains(id));
+            for id in tracks.iter().filter(|id| !previous.contains(id)) {
+                let position = rng.gen_range(0..=source.queue.len());
+                source.queue.insert(position, *id);
+            }
+            source.tracks = tracks;
+        }
+
+        self.upcoming
+            .retain(|id| seen.contains(id) && !self.purged.contains(id));
+        self.fill();
+    }
+
+    // Drops a track for good, e.g. when it's been deleted from SoundCloud
+    pub fn purge(&mut self, track_id: u64) {
+        self.purged.insert(track_id);
+        self.upcoming.retain(|id| *id != track_id);
+        for source in &mut self.sources {
+            source.tracks.retain(|id| *id != track_id);
+            source.queue.retain(|id| *id != track_id);
+        }
+        self.fill();
+    }
+
+    // Tracks weighted below 1 sit out some cycles, and the ones above 1 may come back later in
+    // the cycle, from the next planned tracks on
+    pub fn set_weights(&mut self, weights: HashMap<u64, f64>) {
+        self.weights = weights;
+    }
+
+    pub fn pop(&mut self) -> Option<u64> {
+        let track_id = self.upcoming.pop_front();
+        self.fill();
+        track_id
+    }
+
+    // Puts a track back at the top of the rotation
+    pub fn requeue(&mut self, track_id: u64) {
+        self.upcoming.retain(|id| *id != track_id);
+        self.upcoming.push_front(track_id);
+    }
+
+    // Drops a track from the current cycle, returns whether it was planned
+    pub fn remove(&mut self, track_id: u64) -> bool {
+        let planned = self.upcoming.len();
+        self.upcoming.retain(|id| *id != track_id);
+        for source in &mut self.sources {
+            source.queue.retain(|id| *id != track_id);
+        }
+        let removed = planned != self.upcoming.len();
+        self.fill();
+        removed
+    }
+
+    pub fn upcoming(&self) -> impl Iterator<Item = u64> + '_ {
+        self.upcoming.iter().copied()
+    }
+
+    pub fn tracks(&self) -> impl Iterator<Item = u64> + '_ {
+        self.sources
+            .iter()
+            .flat_map(|source| source.tracks.iter().copied())
+    }
+
+    // Tracks added and removed since the rotation had the `previous` ones
+    pub fn diff(&self, previous: &HashSet<u64>) -> RotationDiff {
+        let current: HashSet<u64> = self.tracks().collect();
+        RotationDiff {
+            added: self.tracks().filter(|id| !previous.contains(id)).collect(),
+            removed: previous
+                .iter()
+                .copied()
+                .filter(|id| !current.contains(id))
+                .collect(),
+        }
+    }
+
+    pub fn contains(&self, track_id: u64) -> bool {
+        self.sources
+            .iter()
+            .any(|source| source.tracks.contains(&track_id))
+    }
+
+    // Tracks of all the sources
+    pub fn len(&self) -> usize {
+        self.sources.iter().map(|source| source.tracks.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Tracks still to be played before the sources start their cycles over
+    pub fn remaining(&self) -> usize {
+        self.upcoming.len()
+            + self
+                .sources
+                .iter()
+                .map(|source| source.queue.len())
+                .sum::<usize>()
+    }
+
+    // Plans tracks until there's `LOOKAHEAD` of them, or all of them when there are fewer
+    fn fill(&mut self) {
+        let wanted = LOOKAHEAD.min(self.len());
+        let mut rng = thread_rng();
+        let mut attempts = 0;
+        while self.upcoming.len() < wanted && attempts < LOOKAHEAD * 4 {
+            attempts += 1;
+            let source = match self.pick_source(&mut rng) {
+                Some(index) => &mut self.sources[index],
+                None => break,
+            };
+            if source.queue.is_empty() {
+                let mut tracks = source.tracks.clone();
+                tracks.shuffle(&mut rng);
+                source.queue = tracks.into();
+            }
+            if let Some(id) = source.queue.pop_front() {
+                let weight = self.weights.get(&id).copied().unwrap_or(1.0);
+                if weight < 1.0 && !self.upcoming.is_empty() && rng.gen::<f64>() >= weight {
+                    continue;
+                }
+                if weight > 1.0 && rng.gen::<f64>() < weight - 1.0 {
+                    let position = rng.gen_range(0..=source.queue.len());
+                    source.queue.insert(position, id);
+                }
+                if !self.upcoming.contains(&id) {
+                    self.upcoming.push_back(id);
+                }
+            }
+        }
+    }
+
+    fn pick_source(&self, rng: &mut impl Rng) -> Option<usize> {
+        let total: u32 = self
+            .sources
+            .iter()
+            .filter(|source| !source.tracks.is_empty())
+            .map(|source| source.weight)
+            .sum();
+        if total == 0 {
+            return None;
+        }
+
+        let mut pick = rng.gen_range(0..total);
+        self.sources
+            .iter()
+            .enumerate()
+            .filter(|(_, source)| !source.tracks.is_empty())
+            .find(|(_, source)| {
+                if pick < source.weight {
+                    return true;
+                }
+                pick -= source.weight;
+                false
+            })
+            .map(|(index, _)| index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rotation(weights: &[u32], listed: Vec<Vec<u64>>) -> Rotation {
+        let mut rotation = Rotation::new(weights);
+        rotation.update(listed.into_iter().map(Some).collect());
+        rotation
+    }
+
+    #[test]
+    fn plays_every_track_once_per_cycle() {
+        let mut rotation = rotation(&[1], vec![(1..=30).collect()]);
+        assert_eq!(rotation.upcoming().count(), LOOKAHEAD);
+        let mut played: Vec<u64> = (0..30).filter_map(|_| rotation.pop()).collect();
+        played.sort_unstable();
+        assert_eq!(played, (1..=30).collect::<Vec<u64>>());
+    }
+
+    #[test]
+    fn mixes_sources_by_weight() {
+        let mut rotation = rotation(
+            &[3, 1, 0],
+            vec![(1..=100).collect(), (101..=200).collect(), vec![201]],
+        );
+        let played: Vec<u64> = (0..400).filter_map(|_| rotation.pop()).collect();
+        let first = played.iter().filter(|id| **id <= 100).count();
+        assert!((250..=350).contains(&first), "{} of 400", first);
+        assert!(!played.contains(&201));
+    }
+
+    #[test]
+    fn gives_shared_tracks_to_the_first_source() {
+        let rotation = rotation(&[1, 1], vec![vec![1, 2], vec![2, 3]]);
+        assert_eq!(rotation.len(), 3);
+        assert_eq!(rotation.tracks().collect::<Vec<u64>>(), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn keeps_the_plan_on_update() {
+        let mut rotation = rotation(&[1, 1], vec![(1..=30).collect(), vec![50]]);
+        let planned: Vec<u64> = rotation.upcoming().collect();
+        let gone = *planned.iter().find(|id| **id <= 30).unwrap();
+
+        let listed: Vec<u64> = (1..=40).filter(|id| *id != gone).collect();
+        rotation.update(vec![Some(listed), None]);
+        let kept: Vec<u64> = planned.into_iter().filter(|id| *id != gone).collect();
+        assert_eq!(
+            rotation.upcoming().take(kept.len()).collect::<Vec<u64>>(),
+            kept
+        );
+        // The second source kept its track
+        assert!(rotation.contains(50));
+        assert!(!rotation.contains(gone));
+        assert_eq!(rotation.len(), 40);
+    }
+
+    #[test]
+    fn requeues_and_removes_tracks() {
+        let mut rotation = rotation(&[1], vec![(1..=30).collect()]);
+        let last = rotation.upcoming().last().unwrap();
+        rotation.requeue(last);
+        assert_eq!(rotation.upcoming().next(), Some(last));
+        assert_eq!(rotation.upcoming().filter(|id| *id == last).count(), 1);
+
+        assert!(rotation.remove(last));
+        assert!(!rotation.remove(last));
+        assert!(rotation.contains(last));
+        assert_eq!(rotation.remaining(), 29);
+    }
+    #[test]
+    fn never_brings_purged_tracks_back() {
+        let mut rotation = rotation(&[1], vec![vec![1, 2, 3]]);
+        rotation.purge(2);
+        rotation.update(vec![Some(vec![1, 2, 3])]);
+        assert!(!rotation.contains(2));
+        let played: Vec<u64> = (0..10).filter_map(|_| rotation.pop()).collect();
+        assert!(!played.contains(&2));
+    }
+
+    #[test]
+    fn diffs_the_tracks() {
+        let rotation = rotation(&[1], vec![vec![1, 2, 3]]);
+        let previous: HashSet<u64> = [2, 3, 4].into_iter().collect();
+        assert_eq!(
+            rotation.diff(&previous),
+            RotationDiff {
+                added: vec![1],
+                removed: vec![4],
+            }
+        );
+        assert!(rotation.diff(&rotation.tracks().collect()).is_empty());
+    }
+    #[test]
+    fn holds_back_tracks_weighted_below_one() {
+        let mut rotation = Rotation::new(&[1]);
+        rotation.set_weights([(1, 0.0)].into_iter().collect());
+        rotation.update(vec![Some((1..=10).collect())]);
+        let played: Vec<u64> = (0..50).filter_map(|_| rotation.pop()).collect();
+        // Only ever played when there's nothing else planned
+        assert!(played.iter().filter(|id| **id == 1).count() <= 1);
+    }
+}
@@ -0,0 +1,228 @@
+use crate::{
+    config::BreakerConfig,
+    error::{Error, SoundcloudErrorKind},
+    metrics::Metrics,
+};
+use serde::Serialize;
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+// How long a half-open probe can take before another call is let through
+const PROBE_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BreakerState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+#[derive(Debug)]
+enum State {
+    Closed { failures: usize },
+    Open { until: Instant },
+    // A single call probes SoundCloud, the others are rejected until it's done
+    HalfOpen { since: Instant },
+}
+
+// Suspends SoundCloud calls after too many consecutive outage errors, so that a station doesn't
+// hammer SoundCloud while it's down or rate limiting. It's shared by the clones of an API client.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    config: BreakerConfig,
+    state: Mutex<State>,
+    metrics: Arc<Metrics>,
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        Self::new(BreakerConfig::default(), Arc::default())
+    }
+}
+
+impl CircuitBreaker {
+    pub fn new(config: BreakerConfig, metrics: Arc<Metrics>) -> Self {
+        Self {
+            config,
+            state: Mutex::new(State::Closed { failures: 0 }),
+            metrics,
+        }
+    }
+
+    pub fn state(&self) -> BreakerState {
+        match *self.state.lock().unwrap() {
+            State::Closed { .. } => BreakerState::Closed,
+            State::Open { .. } => BreakerState::Open,
+            State::HalfOpen { .. } => BreakerState::HalfOpen,
+        }
+    }
+
+    // Lets a call through, or fails fast while SoundCloud calls are suspended
+    pub fn acquire(&self) -> Result<(), Error> {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        match *state {
+            State::Closed { .. } => Ok(()),
+            State::Open { until } if now >= until => {
+                tracing::info!("probing SoundCloud again");
+                self.transition(&mut state, State::HalfOpen { since: now });
+                Ok(())
+            }
+            State::HalfOpen { since } if now.duration_since(since) >= PROBE_TIMEOUT => {
+                *state = State::HalfOpen { since: now };
+                Ok(())
+            }
+            State::Open { until } => {
+                self.metrics.soundcloud_calls_rejected.inc();
+                Err(Error::SoundcloudCircuitOpen(
+                    until.duration_since(now).as_secs().max(1),
+                ))
+            }
+            State::HalfOpen { .. } => {
+                self.metrics.soundcloud_calls_rejected.inc();
+                Err(Error::SoundcloudCircuitOpen(1))
+            }
+        }
+    }
+
+    // Records the outcome of a call let through by `acquire`
+    pub fn record(&self, err: Option<&Error>) {
+        let config = &self.config;
+        let open_for = |secs: u64| State::Open {
+            until: Instant::now() + Duration::from_secs(secs),
+        };
+
+        let mut state = self.state.lock().unwrap();
+        let next = match (err.and_then(Error::soundcloud_kind), &*state) {
+            (Some(SoundcloudErrorKind::RateLimited), _) => {
+                let retry_after = match err {
+                    Some(Error::SoundcloudRateLimited(Some(secs))) => *secs,
+                    _ => config.open_secs,
+                };
+                tracing::warn!("rate limited by SoundCloud for {}s", retry_after);
+                open_for(retry_after)
+            }
+            (Some(SoundcloudErrorKind::Transient), State::Closed { failures })
+                if failures + 1 < config.threshold =>
+            {
+                State::Closed {
+                    failures: failures + 1,
+                }
+            }
+            (Some(SoundcloudErrorKind::Transient), _) => {
+                tracing::warn!(
+                    "suspending SoundCloud calls for {}s after repeated errors",
+                    config.open_secs
+                );
+                open_for(config.open_secs)
+            }
+            // Auth and not found errors come from a SoundCloud that's up and running
+            _ => State::Closed { failures: 0 },
+        };
+        self.transition(&mut state, next);
+    }
+
+    fn transition(&self, state: &mut State, next: State) {
+        let was_open = matches!(state, State::Open { .. });
+        match next {
+            State::Open { .. } if !was_open => self.metrics.breaker_trips.inc(),
+            State::Closed { .. } if matches!(state, State::HalfOpen { .. }) => {
+                tracing::info!("SoundCloud is back, resuming calls")
+            }
+            _ => {}
+        }
+        *state = next;
+        self.metrics.breaker_state.set(match state {
+            State::Closed { .. } => 0,
+            State::HalfOpen { .. } => 1,
+            State::Open { .. } => 2,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breaker(threshold: usize, open_secs: u64) -> CircuitBreaker {
+        CircuitBreaker::new(
+            BreakerConfig {
+                threshold,
+                open_secs,
+            },
+            Arc::default(),
+        )
+    }
+
+    fn server_error() -> Error {
+        Error::SoundcloudResponseError(503)
+    }
+
+    #[test]
+    fn opens_after_consecutive_outage_errors() {
+        let breaker = breaker(3, 60);
+        breaker.record(Some(&server_error()));
+        breaker.record(Some(&server_error()));
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        assert!(breaker.acquire().is_ok());
+
+        breaker.record(Some(&server_error()));
+        assert_eq!(breaker.state(), BreakerState::Open);
+        assert!(matches!(
+            breaker.acquire(),
+            Err(Error::SoundcloudCircuitOpen(secs)) if secs > 0 && secs <= 60
+        ));
+    }
+
+    #[test]
+    fn resets_on_success_and_resource_errors() {
+        let breaker = breaker(2, 60);
+        breaker.record(Some(&server_error()));
+        breaker.record(None);
+        breaker.record(Some(&server_error()));
+        assert_eq!(breaker.state(), BreakerState::Closed);
+
+        // SoundCloud answered, the track just isn't there
+        breaker.record(Some(&Error::SoundcloudNotFound));
+        breaker.record(Some(&Error::SoundcloudAuthError(401)));
+        breaker.record(Some(&Error::SoundcloudResponseError(400)));
+        breaker.record(Some(&server_error()));
+        assert_eq!(breaker.state(), BreakerState::Closed);
+    }
+
+    #[test]
+    fn opens_right_away_when_rate_limited() {
+        let breaker = breaker(5, 60);
+        breaker.record(Some(&Error::SoundcloudRateLimited(Some(120))));
+        assert_eq!(breaker.state(), BreakerState::Open);
+        assert!(matches!(
+            breaker.acquire(),
+            Err(Error::SoundcloudCircuitOpen(secs)) if secs > 60
+        ));
+    }
+
+    #[test]
+    fn probes_once_before_closing_again() {
+        let breaker = breaker(1, 0);
+        breaker.record(Some(&server_error()));
+        assert_eq!(breaker.state(), BreakerState::Open);
+
+        // The first call probes, the others wait for its outcome
+        assert!(breaker.acquire().is_ok());
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+        assert!(matches!(
+            breaker.acquire(),
+            Err(Error::SoundcloudCircuitOpen(1))
+        ));
+
+        breaker.record(Some(&server_error()));
+        assert_eq!(breaker.state(), BreakerState::Open);
+        assert!(breaker.acquire().is_ok());
+        breaker.record(None);
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        assert!(breaker.acquire().is_ok());
+    }
+}
@@ -1,27 +1,75 @@
-use super::{Playlist, Track};
-use crate::error::Error;
+use super::{breaker::CircuitBreaker, Playlist, Track};
+use crate::{
+    error::{Error, SoundcloudErrorKind},
+    metadata::reduce_waveform,
+    metrics::Metrics,
+};
 use anyhow::Result;
 use lazy_static::lazy_static;
 use regex::Regex;
-use reqwest::{header::HeaderMap, Response};
-use reqwest_middleware::ClientBuilder;
-use reqwest_retry::{policies::ExponentialBackoff, RetryTransientMiddleware};
-use serde::Deserialize;
+use reqwest::{
+    header::{HeaderMap, RETRY_AFTER},
+    Response, StatusCode,
+};
+use serde::{de::DeserializeOwned, Deserialize};
 use serde_json::Value;
+use std::{
+    future::Future,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::time::sleep;
+
+// Retries of transient errors, with an exponential backoff
+const MAX_RETRIES: u32 = 2;
+const RETRY_BACKOFF: Duration = Duration::from_millis(500);
+
+// Collections are fetched by pages of `PAGE_SIZE` items
+const PAGE_SIZE: usize = 200;
+const MAX_PAGES: usize = 25;
 
 static USER_AGENT: &str =
     "Mozilla/5.0 (Macintosh; Intel Mac OS X 10.15; rv:100.0) Gecko/20100101 Firefox/100.0";
 
-pub async fn fetch_playlist_tracks(client_id: &str, playlist_id: &str) -> Result<Playlist, Error> {
+lazy_static! {
+    // One client for all the requests, so that its connections to SoundCloud are reused
+    static ref HTTP_CLIENT: reqwest::Client = reqwest::Client::new();
+}
+
+// What the requests to SoundCloud go through, shared by the clones of an API client
+#[derive(Debug, Clone, Default)]
+pub struct Transport {
+    breaker: Arc<CircuitBreaker>,
+    metrics: Arc<Metrics>,
+}
+
+impl Transport {
+    pub fn new(breaker: Arc<CircuitBreaker>, metrics: Arc<Metrics>) -> Self {
+        Self { breaker, metrics }
+    }
+
+    pub fn breaker(&self) -> &CircuitBreaker {
+        &self.breaker
+    }
+}
+
+pub async fn fetch_playlist_tracks(
+    transport: &Transport,
+    client_id: &str,
+    playlist_id: &str,
+    secret_token: Option<&str>,
+) -> Result<Playlist, Error> {
     let mut headers = HeaderMap::new();
     headers.insert("User-Agent", USER_AGENT.parse().unwrap());
 
     let url = format!(
-        "https://api-v2.soundcloud.com/playlists/{}?client_id={}",
-        playlist_id, client_id
+        "https://api-v2.soundcloud.com/playlists/{}?client_id={}{}",
+        playlist_id,
+        client_id,
+        secret_token_param(secret_token)
     );
 
-    let res = http_get(url.as_str(), &headers).await?;
+    let res = http_get(transport, url.as_str(), &headers).await?;
     match res.json::<PlaylistResponse>().await {
         Ok(res) => Ok(res.into()),
         Err(_) => Err(Error::SoundcloudJsonParseError(String::from(
@@ -30,16 +78,23 @@ pub async fn fetch_playlist_tracks(client_id: &str, playlist_id: &str) -> Result
     }
 }
 
-pub async fn fetch_track_info(client_id: &str, track_id: u64) -> Result<Track, Error> {
+pub async fn fetch_track_info(
+    transport: &Transport,
+    client_id: &str,
+    track_id: u64,
+    secret_token: Option<&str>,
+) -> Result<Track, Error> {
     let mut headers = HeaderMap::new();
     headers.insert("User-Agent", USER_AGENT.parse().unwrap());
 
     let url = format!(
-        "https://api-v2.soundcloud.com/tracks/{}?client_id={}",
-        track_id, client_id
+        "https://api-v2.soundcloud.com/tracks/{}?client_id={}{}",
+        track_id,
+        client_id,
+        secret_token_param(secret_token)
     );
 
-    let res = http_get(url.as_str(), &headers).await?;
+    let res = http_get(transport, url.as_str(), &headers).await?;
     match res.json::<TrackResponse>().await {
         Ok(res) => Ok(res.try_into()?),
         Err(_) => Err(Error::SoundcloudJsonParseError(String::from(
@@ -49,17 +104,23 @@ pub async fn fetch_track_info(client_id: &str, track_id: u64) -> Result<Track, E
 }
 
 pub async fn fetch_track_stream(
+    transport: &Transport,
     client_id: &str,
     track_url: &str,
     token: &str,
 ) -> Result<TrackStreamResponse, Error> {
     let mut headers = HeaderMap::new();
     headers.insert("User-Agent", USER_AGENT.parse().unwrap());
-    headers.insert("Authorization", format!("Oauth {}", token).parse().unwrap());
+    headers.insert(
+        "Authorization",
+        format!("Oauth {}", token)
+            .parse()
+            .map_err(|_| Error::SoundcloudInvalidHeader("Authorization"))?,
+    );
 
     let url = format!("{}?client_id={}", track_url, client_id);
 
-    let res = http_get(url.as_str(), &headers).await?;
+    let res = http_get(transport, url.as_str(), &headers).await?;
     match res.json::<TrackStreamResponse>().await {
         Ok(res) => Ok(res),
         Err(_) => Err(Error::SoundcloudJsonParseError(String::from(
@@ -68,21 +129,137 @@ pub async fn fetch_track_stream(
     }
 }
 
-pub async fn fetch_new_client_id() -> Result<String, Error> {
+pub async fn fetch_resolved(
+    transport: &Transport,
+    client_id: &str,
+    permalink_url: &str,
+) -> Result<ResolveResponse, Error> {
+    let mut headers = HeaderMap::new();
+    headers.insert("User-Agent", USER_AGENT.parse().unwrap());
+
+    let url = format!(
+        "https://api-v2.soundcloud.com/resolve?url={}&client_id={}",
+        encode_query_value(permalink_url),
+        client_id
+    );
+
+    let res = http_get(transport, url.as_str(), &headers).await?;
+    match res.json::<ResolveResponse>().await {
+        Ok(res) => Ok(res),
+        Err(_) => Err(Error::SoundcloudJsonParseError(String::from(
+            "ResolveResponse",
+        ))),
+    }
+}
+
+// Ids of the tracks a user liked, the liked playlists are left out
+pub async fn fetch_user_likes(
+    transport: &Transport,
+    client_id: &str,
+    user_id: u64,
+) -> Result<Vec<u64>, Error> {
+    let url = format!(
+        "https://api-v2.soundcloud.com/users/{}/track_likes?limit={}",
+        user_id, PAGE_SIZE
+    );
+    let likes: Vec<LikeResponse> = fetch_collection(transport, client_id, url).await?;
+    Ok(likes
+        .into_iter()
+        .filter_map(|like| like.track.map(|track| track.id))
+        .collect())
+}
+
+pub async fn fetch_user_tracks(
+    transport: &Transport,
+    client_id: &str,
+    user_id: u64,
+) -> Result<Vec<u64>, Error> {
+    let url = format!(
+        "https://api-v2.soundcloud.com/users/{}/tracks?limit={}",
+        user_id, PAGE_SIZE
+    );
+    let tracks: Vec<ResolveResponse> = fetch_collection(transport, client_id, url).await?;
+    Ok(tracks.into_iter().map(|track| track.id).collect())
+}
+
+// Follows the pages of a collection, up to `MAX_PAGES`
+async fn fetch_collection<T: DeserializeOwned>(
+    transport: &Transport,
+    client_id: &str,
+    url: String,
+) -> Result<Vec<T>, Error> {
+    let mut headers = HeaderMap::new();
+    headers.insert("User-Agent", USER_AGENT.parse().unwrap());
+
+    let mut items = vec![];
+    let mut next = Some(url);
+    for _ in 0..MAX_PAGES {
+        let url = match next.take() {
+            Some(url) => format!("{}&client_id={}", url, client_id),
+            None => break,
+        };
+        let res = http_get(transport, url.as_str(), &headers).await?;
+        let page = res
+            .json::<CollectionResponse<T>>()
+            .await
+            .map_err(|_| Error::SoundcloudJsonParseError(String::from("CollectionResponse")))?;
+        items.extend(page.collection);
+        next = page.next_href;
+    }
+    Ok(items)
+}
+
+// Loudness of a track, from its waveform url. It comes from SoundCloud's CDN rather than the
+// API, so it doesn't go through the circuit breaker.
+pub async fn fetch_waveform(transport: &Transport, waveform_url: &str) -> Result<Vec<f32>, Error> {
+    let mut headers = HeaderMap::new();
+    headers.insert("User-Agent", USER_AGENT.parse().unwrap());
+
+    // Old tracks point to a PNG, the same samples are available as JSON
+    let url = match waveform_url.strip_suffix(".png") {
+        Some(base) => format!("{}.json", base),
+        None => waveform_url.to_string(),
+    };
+    let res = send(transport, url.as_str(), &headers).await?;
+    match res.json::<WaveformResponse>().await {
+        Ok(res) => Ok(reduce_waveform(&res.samples, res.height)),
+        Err(_) => Err(Error::SoundcloudJsonParseError(String::from(
+            "WaveformResponse",
+        ))),
+    }
+}
+
+// Requests a track's audio from its stream url, forwarding the `Range` header if any. The audio
+// comes from SoundCloud's CDN, on behalf of listeners, so it's kept out of the circuit breaker.
+pub async fn fetch_stream(
+    transport: &Transport,
+    stream_url: &str,
+    range: Option<&str>,
+) -> Result<Response, Error> {
+    let mut headers = HeaderMap::new();
+    headers.insert("User-Agent", USER_AGENT.parse().unwrap());
+    if let Some(Ok(range)) = range.map(str::parse) {
+        headers.insert("Range", range);
+    }
+
+    with_retries(|| send(transport, stream_url, &headers)).await
+}
+
+pub async fn fetch_new_client_id(transport: &Transport) -> Result<String, Error> {
     let url = "https://soundcloud.com";
     let mut headers = HeaderMap::new();
     headers.insert("User-Agent", USER_AGENT.parse().unwrap());
 
-    let content = http_get(url, &headers)
+    let content = http_get(transport, url, &headers)
         .await?
         .text_with_charset("utf-8")
         .await
         .map_err(Error::SoundcloudTextResponseError)?;
 
-    find_client_id(content).await
+    find_client_id(transport, content).await
 }
 
-async fn find_client_id(page: String) -> Result<String, Error> {
+async fn find_client_id(transport: &Transport, page: String) -> Result<String, Error> {
     lazy_static! {
         static ref RE_SRC: Regex = Regex::new(r#"<script[^>]+src="([^"]+)""#).unwrap();
         static ref RE_CLIENT_ID: Regex =
@@ -95,7 +272,7 @@ async fn find_client_id(page: String) -> Result<String, Error> {
         let mut headers = HeaderMap::new();
         headers.insert("User-Agent", USER_AGENT.parse().unwrap());
 
-        let js = http_get(url, &headers)
+        let js = http_get(transport, url, &headers)
             .await?
             .text_with_charset("utf-8")
             .await
@@ -114,21 +291,84 @@ async fn find_client_id(page: String) -> Result<String, Error> {
     )))
 }
 
-async fn http_get(url: &str, headers: &HeaderMap) -> Result<Response, Error> {
-    let retry_policy = ExponentialBackoff::builder().build_with_max_retries(3);
-    let client = ClientBuilder::new(reqwest::Client::new())
-        .with(RetryTransientMiddleware::new_with_policy(retry_policy))
-        .build();
+// Private tracks and playlists can only be fetched with their secret token
+fn secret_token_param(secret_token: Option<&str>) -> String {
+    match secret_token {
+        Some(token) => format!("&secret_token={}", encode_query_value(token)),
+        None => String::new(),
+    }
+}
 
-    let res = client
-        .get(url)
-        .headers(headers.clone())
-        .send()
-        .await
-        .map_err(Error::SoundcloudRequestError)?;
+fn encode_query_value(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
 
-    if !res.status().is_success() {
-        let err = Error::SoundcloudResponseError(res.status().as_u16());
+// Sends a GET request through the circuit breaker, retrying transient errors a few times. Rate
+// limits aren't retried, SoundCloud's `Retry-After` is honored by the breaker instead.
+async fn http_get(
+    transport: &Transport,
+    url: &str,
+    headers: &HeaderMap,
+) -> Result<Response, Error> {
+    with_retries(|| async {
+        transport.breaker.acquire()?;
+        let result = send(transport, url, headers).await;
+        transport.breaker.record(result.as_ref().err());
+        result
+    })
+    .await
+}
+
+async fn with_retries<F, R>(mut request: F) -> Result<Response, Error>
+where
+    F: FnMut() -> R,
+    R: Future<Output = Result<Response, Error>>,
+{
+    let mut attempt = 0;
+    loop {
+        match request().await {
+            Err(err)
+                if err.soundcloud_kind() == Some(SoundcloudErrorKind::Transient)
+                    && attempt < MAX_RETRIES =>
+            {
+                attempt += 1;
+                sleep(RETRY_BACKOFF * 2u32.pow(attempt - 1)).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+async fn send(transport: &Transport, url: &str, headers: &HeaderMap) -> Result<Response, Error> {
+    let started_at = Instant::now();
+    let res = HTTP_CLIENT.get(url).headers(headers.clone()).send().await;
+    let status = match &res {
+        Ok(res) => res.status().as_u16().to_string(),
+        Err(_) => String::from("error"),
+    };
+    transport
+        .metrics
+        .observe_soundcloud_request(status.as_str(), started_at.elapsed());
+    let res = res.map_err(Error::SoundcloudRequestError)?;
+
+    let status = res.status();
+    if !status.is_success() {
+        let err = match status {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+                Error::SoundcloudAuthError(status.as_u16())
+            }
+            StatusCode::NOT_FOUND | StatusCode::GONE => Error::SoundcloudNotFound,
+            StatusCode::TOO_MANY_REQUESTS => Error::SoundcloudRateLimited(retry_after(&res)),
+            _ => Error::SoundcloudResponseError(status.as_u16()),
+        };
         tracing::error!("{} requesting `{}`", err, url);
         return Err(err);
     }
@@ -136,33 +376,44 @@ async fn http_get(url: &str, headers: &HeaderMap) -> Result<Response, Error> {
     Ok(res)
 }
 
+// Seconds to wait from a `Retry-After` header, HTTP dates aren't supported
+fn retry_after(res: &Response) -> Option<u64> {
+    res.headers()
+        .get(RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
 #[derive(Default, Debug, Clone, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub struct PlaylistResponse {
     #[serde(rename = "artwork_url")]
     pub artwork_url: Option<Value>,
     #[serde(rename = "created_at")]
-    pub created_at: String,
-    pub description: String,
-    pub duration: u64,
+    pub created_at: Option<String>,
+    pub description: Option<String>,
+    pub duration: Option<u64>,
     #[serde(rename = "embeddable_by")]
-    pub embeddable_by: String,
-    pub genre: String,
+    pub embeddable_by: Option<String>,
+    pub genre: Option<String>,
     pub id: u64,
-    pub kind: String,
+    pub kind: Option<String>,
     #[serde(rename = "label_name")]
-    pub label_name: String,
+    pub label_name: Option<String>,
     #[serde(rename = "last_modified")]
-    pub last_modified: String,
-    pub license: String,
+    pub last_modified: Option<String>,
+    pub license: Option<String>,
     #[serde(rename = "likes_count")]
-    pub likes_count: u64,
+    pub likes_count: Option<u64>,
     #[serde(rename = "managed_by_feeds")]
-    pub managed_by_feeds: bool,
-    pub permalink: String,
+    pub managed_by_feeds: Option<bool>,
+    pub permalink: Option<String>,
     #[serde(rename = "permalink_url")]
-    pub permalink_url: String,
-    pub public: bool,
+    pub permalink_url: Option<String>,
+    pub public: Option<bool>,
     #[serde(rename = "purchase_title")]
     pub purchase_title: Option<Value>,
     #[serde(rename = "purchase_url")]
@@ -170,28 +421,29 @@ pub struct PlaylistResponse {
     #[serde(rename = "release_date")]
     pub release_date: Option<Value>,
     #[serde(rename = "reposts_count")]
-    pub reposts_count: u64,
+    pub reposts_count: Option<u64>,
     #[serde(rename = "secret_token")]
     pub secret_token: Option<Value>,
-    pub sharing: String,
+    pub sharing: Option<String>,
     #[serde(rename = "tag_list")]
-    pub tag_list: String,
-    pub title: String,
-    pub uri: String,
+    pub tag_list: Option<String>,
+    pub title: Option<String>,
+    pub uri: Option<String>,
     #[serde(rename = "user_id")]
-    pub user_id: u64,
+    pub user_id: Option<u64>,
     #[serde(rename = "set_type")]
-    pub set_type: String,
+    pub set_type: Option<String>,
     #[serde(rename = "is_album")]
-    pub is_album: bool,
+    pub is_album: Option<bool>,
     #[serde(rename = "published_at")]
-    pub published_at: String,
+    pub published_at: Option<String>,
     #[serde(rename = "display_date")]
-    pub display_date: String,
-    pub user: User,
+    pub display_date: Option<String>,
+    pub user: Option<User>,
+    #[serde(default)]
     pub tracks: Vec<TrackResponse>,
     #[serde(rename = "track_count")]
-    pub track_count: u64,
+    pub track_count: Option<u64>,
 }
 
 #[derive(Default, Debug, Clone, Deserialize)]
@@ -218,7 +470,7 @@ pub struct TrackResponse {
     #[serde(rename = "has_downloads_left")]
     pub has_downloads_left: Option<bool>,
     pub id: u64,
-    pub kind: String,
+    pub kind: Option<String>,
     #[serde(rename = "label_name")]
     pub label_name: Option<String>,
     #[serde(rename = "last_modified")]
@@ -269,11 +521,37 @@ pub struct TrackResponse {
     #[serde(rename = "track_authorization")]
     pub track_authorization: Option<String>,
     #[serde(rename = "monetization_model")]
-    pub monetization_model: String,
-    pub policy: String,
+    pub monetization_model: Option<String>,
+    pub policy: Option<String>,
     pub user: Option<User>,
 }
 
+#[derive(Default, Debug, Clone, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct ResolveResponse {
+    pub id: u64,
+    pub kind: String,
+    pub secret_token: Option<String>,
+    pub username: Option<String>,
+}
+
+#[derive(Default, Debug, Clone, Deserialize)]
+pub struct WaveformResponse {
+    pub height: u32,
+    pub samples: Vec<u32>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CollectionResponse<T> {
+    pub collection: Vec<T>,
+    pub next_href: Option<String>,
+}
+
+#[derive(Default, Debug, Clone, Deserialize)]
+pub struct LikeResponse {
+    pub track: Option<ResolveResponse>,
+}
+
 #[derive(Default, Debug, Clone, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub struct TrackStreamResponse {
@@ -284,66 +562,67 @@ pub struct TrackStreamResponse {
 #[serde(rename_all = "snake_case")]
 pub struct User {
     #[serde(rename = "avatar_url")]
-    pub avatar_url: String,
+    pub avatar_url: Option<String>,
     #[serde(rename = "first_name")]
-    pub first_name: String,
+    pub first_name: Option<String>,
     #[serde(rename = "followers_count")]
-    pub followers_count: u64,
+    pub followers_count: Option<u64>,
     #[serde(rename = "full_name")]
-    pub full_name: String,
+    pub full_name: Option<String>,
     pub id: u64,
-    pub kind: String,
+    pub kind: Option<String>,
     #[serde(rename = "last_modified")]
-    pub last_modified: String,
+    pub last_modified: Option<String>,
     #[serde(rename = "last_name")]
-    pub last_name: String,
-    pub permalink: String,
+    pub last_name: Option<String>,
+    pub permalink: Option<String>,
     #[serde(rename = "permalink_url")]
-    pub permalink_url: String,
-    pub uri: String,
-    pub urn: String,
-    pub username: String,
-    pub verified: bool,
+    pub permalink_url: Option<String>,
+    pub uri: Option<String>,
+    pub urn: Option<String>,
+    pub username: Option<String>,
+    pub verified: Option<bool>,
     pub city: Option<Value>,
     #[serde(rename = "country_code")]
     pub country_code: Option<Value>,
-    pub badges: Badges,
+    pub badges: Option<Badges>,
     #[serde(rename = "station_urn")]
-    pub station_urn: String,
+    pub station_urn: Option<String>,
     #[serde(rename = "station_permalink")]
-    pub station_permalink: String,
+    pub station_permalink: Option<String>,
 }
 
 #[derive(Default, Debug, Clone, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub struct Badges {
-    pub pro: bool,
+    pub pro: Option<bool>,
     #[serde(rename = "pro_unlimited")]
-    pub pro_unlimited: bool,
-    pub verified: bool,
+    pub pro_unlimited: Option<bool>,
+    pub verified: Option<bool>,
 }
 
 #[derive(Default, Debug, Clone, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub struct Media {
+    #[serde(default)]
     pub transcodings: Vec<Transcoding>,
 }
 
 #[derive(Default, Debug, Clone, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub struct Transcoding {
-    pub url: String,
-    pub preset: String,
-    pub duration: u64,
-    pub snipped: bool,
-    pub format: Format,
-    pub quality: String,
+    pub url: Option<String>,
+    pub preset: Option<String>,
+    pub duration: Option<u64>,
+    pub snipped: Option<bool>,
+    pub format: Option<Format>,
+    pub quality: Option<String>,
 }
 
 #[derive(Default, Debug, Clone, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub struct Format {
-    pub protocol: String,
+    pub protocol: Option<String>,
     #[serde(rename = "mime_type")]
-    pub mime_type: String,
+    pub mime_type: Option<String>,
 }
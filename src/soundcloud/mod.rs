@@ -1,56 +1,237 @@
-use self::client::{
-    fetch_new_client_id, fetch_playlist_tracks, fetch_track_info, fetch_track_stream,
-    PlaylistResponse, TrackResponse,
+use self::{
+    breaker::{BreakerState, CircuitBreaker},
+    client::{
+        fetch_new_client_id, fetch_playlist_tracks, fetch_resolved, fetch_stream, fetch_track_info,
+        fetch_track_stream, fetch_user_likes, fetch_user_tracks, fetch_waveform, PlaylistResponse,
+        TrackResponse, Transport,
+    },
+};
+use crate::{
+    error::Error,
+    metadata::{parse_tags, soundcloud_artwork, text, text_field, TrackMetadata},
+    metrics::Metrics,
 };
-use crate::error::Error;
 use anyhow::Result;
-use serde::Serialize;
-use std::convert::From;
+use reqwest::Response;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    convert::From,
+    sync::{Arc, Mutex},
+};
+use tokio::time::{timeout, Duration};
+
+// The waveform is left out rather than holding up the prefetcher
+const WAVEFORM_TIMEOUT: Duration = Duration::from_secs(5);
 
+pub mod breaker;
 mod client;
+#[cfg(test)]
+mod tests;
 
-#[derive(Debug, Clone)]
-pub struct ApiClient {}
+#[derive(Debug, Clone, Default)]
+pub struct ApiClient {
+    // Secret tokens of the private tracks met so far, shared by the clones, as private tracks
+    // can't be fetched again without them
+    secret_tokens: Arc<Mutex<HashMap<u64, String>>>,
+    transport: Transport,
+}
 
 impl ApiClient {
-    pub fn new() -> Self {
-        ApiClient {}
+    pub fn new(breaker: Arc<CircuitBreaker>, metrics: Arc<Metrics>) -> Self {
+        Self {
+            secret_tokens: Arc::default(),
+            transport: Transport::new(breaker, metrics),
+        }
+    }
+
+    // Whether SoundCloud calls are let through by the circuit breaker
+    pub fn breaker_state(&self) -> BreakerState {
+        self.transport.breaker().state()
     }
 
     pub async fn get_client_id(&self) -> Result<String, Error> {
-        fetch_new_client_id().await
+        fetch_new_client_id(&self.transport).await
     }
 
     pub async fn get_track(&self, client_id: &str, track_id: u64) -> Result<Track, Error> {
-        let mut track = fetch_track_info(client_id, track_id).await?;
-        let track_stream = fetch_track_stream(
+        let secret_token = self.secret_tokens.lock().unwrap().get(&track_id).cloned();
+        let mut track = fetch_track_info(
+            &self.transport,
             client_id,
-            track.url.unwrap().as_ref(),
-            track.token.as_ref().unwrap(),
+            track_id,
+            secret_token.as_deref(),
         )
         .await?;
-        track.url = track_stream.url;
-        Ok(track.clone())
+        let transcoding_url = track
+            .url
+            .as_deref()
+            .ok_or(Error::SoundcloudIncompleteTrack(track_id, "url"))?;
+        let token = track
+            .token
+            .as_deref()
+            .ok_or(Error::SoundcloudIncompleteTrack(
+                track_id,
+                "track_authorization",
+            ))?;
+        let track_stream =
+            fetch_track_stream(&self.transport, client_id, transcoding_url, token).await?;
+        track.url = Some(
+            track_stream
+                .url
+                .ok_or(Error::SoundcloudIncompleteTrack(track_id, "stream url"))?,
+        );
+        Ok(track)
+    }
+
+    // The track's waveform is a nice to have, the track plays fine without it. It's fetched apart,
+    // by the prefetcher, and doesn't count as a SoundCloud failure when it's missing.
+    pub async fn get_waveform(&self, track: &Track) -> Option<Vec<f32>> {
+        let waveform_url = track.metadata.waveform_url.as_deref()?;
+        match timeout(
+            WAVEFORM_TIMEOUT,
+            fetch_waveform(&self.transport, waveform_url),
+        )
+        .await
+        {
+            Ok(Ok(waveform)) => Some(waveform),
+            Ok(Err(err)) => {
+                tracing::debug!("no waveform for track {}: {}", track.id, err);
+                None
+            }
+            Err(_) => {
+                tracing::debug!("no waveform for track {}: timed out", track.id);
+                None
+            }
+        }
+    }
+
+    pub async fn resolve_track_id(&self, client_id: &str, url: &str) -> Result<u64, Error> {
+        match self.resolve(client_id, url).await? {
+            Resource::Track { id, .. } => Ok(id),
+            _ => Err(Error::RequestInvalidTrack(url.to_string())),
+        }
+    }
+
+    // What a SoundCloud permalink points to. Secret links of private tracks and playlists work
+    // too, their token is remembered for the tracks.
+    pub async fn resolve(&self, client_id: &str, url: &str) -> Result<Resource, Error> {
+        let url = permalink(url).ok_or_else(|| Error::InvalidPermalink(url.to_string()))?;
+        let resolved = fetch_resolved(&self.transport, client_id, url.as_str()).await?;
+        Ok(match resolved.kind.as_str() {
+            "track" => {
+                if let Some(token) = &resolved.secret_token {
+                    self.remember_secret_token(resolved.id, token);
+                }
+                Resource::Track {
+                    id: resolved.id,
+                    secret_token: resolved.secret_token,
+                }
+            }
+            "playlist" => Resource::Playlist {
+                id: resolved.id,
+                secret_token: resolved.secret_token,
+            },
+            "user" => Resource::User {
+                id: resolved.id,
+                username: resolved.username.unwrap_or_default(),
+            },
+            _ => Resource::Other {
+                kind: resolved.kind,
+            },
+        })
+    }
+
+    pub async fn get_user_likes(&self, client_id: &str, user_id: u64) -> Result<Vec<u64>, Error> {
+        fetch_user_likes(&self.transport, client_id, user_id).await
+    }
+
+    pub async fn get_user_tracks(&self, client_id: &str, user_id: u64) -> Result<Vec<u64>, Error> {
+        fetch_user_tracks(&self.transport, client_id, user_id).await
+    }
+
+    pub async fn get_stream(
+        &self,
+        stream_url: &str,
+        range: Option<&str>,
+    ) -> Result<Response, Error> {
+        fetch_stream(&self.transport, stream_url, range).await
     }
 
     pub async fn get_playlist(
         &self,
         client_id: &str,
         playlist_id: &str,
+        secret_token: Option<&str>,
     ) -> Result<Playlist, Error> {
-        fetch_playlist_tracks(client_id, playlist_id).await
+        let playlist =
+            fetch_playlist_tracks(&self.transport, client_id, playlist_id, secret_token).await?;
+        for (track_id, token) in &playlist.secret_tokens {
+            self.remember_secret_token(*track_id, token);
+        }
+        Ok(playlist)
+    }
+
+    fn remember_secret_token(&self, track_id: u64, token: &str) {
+        self.secret_tokens
+            .lock()
+            .unwrap()
+            .insert(track_id, token.to_string());
     }
 }
 
-impl Default for ApiClient {
-    fn default() -> Self {
-        Self::new()
+// Normalizes a SoundCloud permalink, with or without its scheme, `www.` or `m.`, dropping the
+// query string and the fragment, e.g. share links' `?si=` tracking. The secret token of a
+// private link is part of its path and is kept.
+pub fn permalink(input: &str) -> Option<String> {
+    let input = input.trim();
+    let url = input
+        .trim_start_matches("https://")
+        .trim_start_matches("http://")
+        .trim_start_matches("www.")
+        .trim_start_matches("m.");
+    let path = url.strip_prefix("soundcloud.com/")?;
+    let path = path
+        .split(['?', '#'])
+        .next()
+        .unwrap_or_default()
+        .trim_end_matches('/');
+    if path.is_empty() {
+        return None;
     }
+    Some(format!("https://soundcloud.com/{}", path))
+}
+
+// What a permalink points to
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Resource {
+    Track {
+        id: u64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        secret_token: Option<String>,
+    },
+    Playlist {
+        id: u64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        secret_token: Option<String>,
+    },
+    User {
+        id: u64,
+        username: String,
+    },
+    // Something the station can't play, e.g. a system playlist
+    Other {
+        kind: String,
+    },
 }
 
 #[derive(Debug)]
 pub struct Playlist {
     pub tracks_ids: Vec<u64>,
+    // Secret tokens of the playlist's private tracks
+    pub secret_tokens: HashMap<u64, String>,
 }
 
 impl std::fmt::Display for Playlist {
@@ -62,12 +243,20 @@ impl std::fmt::Display for Playlist {
 impl From<PlaylistResponse> for Playlist {
     fn from(playlist: PlaylistResponse) -> Self {
         Playlist {
-            tracks_ids: playlist.tracks.into_iter().map(|t| t.id).collect(),
+            tracks_ids: playlist.tracks.iter().map(|t| t.id).collect(),
+            secret_tokens: playlist
+                .tracks
+                .into_iter()
+                .filter_map(|t| match t.secret_token {
+                    Some(Value::String(token)) => Some((t.id, token)),
+                    _ => None,
+                })
+                .collect(),
         }
     }
 }
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Track {
     pub id: u64,
     pub permalink_url: Option<String>,
@@ -78,35 +267,77 @@ pub struct Track {
     pub artist_permalink: Option<String>,
     pub url: Option<String>,
     pub token: Option<String>,
+    // SoundCloud's preset of the streamed transcoding, e.g. `mp3_0_0`
+    pub transcoding: Option<String>,
+    #[serde(default)]
+    pub metadata: TrackMetadata,
 }
 
 impl TryFrom<TrackResponse> for Track {
     type Error = Error;
 
+    // Fails on the fields needed to air the track, the other ones are optional
     fn try_from(track: TrackResponse) -> Result<Self, Error> {
-        let track_url = match track
+        let incomplete = |field| Error::SoundcloudIncompleteTrack(track.id, field);
+        let transcoding = track
             .media
-            .unwrap()
+            .ok_or_else(|| incomplete("media"))?
             .transcodings
             .into_iter()
-            .find(|ts| ts.format.protocol == *"progressive")
-        {
-            Some(transcoding) => Some(transcoding.url),
-            _ => return Err(Error::SoundcloudIncompleteTrack(track.title.unwrap())),
-        };
+            .find(|ts| {
+                ts.format
+                    .as_ref()
+                    .and_then(|format| format.protocol.as_deref())
+                    == Some("progressive")
+            })
+            .ok_or_else(|| incomplete("progressive transcoding"))?;
+        let url = transcoding
+            .url
+            .ok_or_else(|| incomplete("transcoding url"))?;
+        let duration = track.duration.ok_or_else(|| incomplete("duration"))?;
+        let token = track
+            .track_authorization
+            .ok_or_else(|| incomplete("track_authorization"))?;
+        let user = track.user.unwrap_or_default();
 
-        let user = track.user.unwrap();
+        // Tracks without an artwork show their uploader's avatar on SoundCloud
+        let artwork = text(track.artwork_url.clone()).or_else(|| text(user.avatar_url));
+        let publisher = track.publisher_metadata.as_ref();
+        let metadata = TrackMetadata {
+            artwork: artwork
+                .as_deref()
+                .map(soundcloud_artwork)
+                .unwrap_or_default(),
+            genre: text(track.genre),
+            tags: parse_tags(track.tag_list.as_deref().unwrap_or_default()),
+            description: text(track.description),
+            label: text(track.label_name),
+            album: text_field(publisher, "album_title")
+                .or_else(|| text_field(publisher, "release_title")),
+            release_date: track
+                .release_date
+                .as_ref()
+                .and_then(Value::as_str)
+                .and_then(|date| text(Some(date.to_string()))),
+            explicit: publisher
+                .and_then(|publisher| publisher.get("explicit"))
+                .and_then(Value::as_bool),
+            waveform_url: text(track.waveform_url),
+            waveform: vec![],
+        };
 
         Ok(Track {
             id: track.id,
             permalink_url: track.permalink_url,
             artwork_url: track.artwork_url,
-            duration: track.duration,
+            duration: Some(duration),
             title: track.title,
-            artist: Some(user.username),
-            artist_permalink: Some(user.permalink_url),
-            url: track_url,
-            token: track.track_authorization,
+            artist: user.username,
+            artist_permalink: user.permalink_url,
+            url: Some(url),
+            token: Some(token),
+            transcoding: transcoding.preset,
+            metadata,
         })
     }
 }
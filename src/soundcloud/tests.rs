@@ -0,0 +1,138 @@
+use super::{
+    breaker::{BreakerState, CircuitBreaker},
+    client::{PlaylistResponse, TrackResponse},
+    ApiClient, Playlist, Track,
+};
+use crate::{config::BreakerConfig, error::Error, media_player::CurrentTrack};
+use chrono::Utc;
+use std::sync::Arc;
+
+fn track(fixture: &str) -> Result<Track, Error> {
+    let response: TrackResponse = serde_json::from_str(fixture).expect("valid track JSON");
+    Track::try_from(response)
+}
+
+#[test]
+fn converts_complete_track() {
+    let track = track(include_str!("fixtures/track_complete.json")).unwrap();
+    assert_eq!(track.id, 1001);
+    assert_eq!(track.duration, Some(215040));
+    assert_eq!(track.artist.as_deref(), Some("Robo"));
+    assert_eq!(track.token.as_deref(), Some("eyJ0eXAiOiJKV1QifQ.token"));
+    assert_eq!(track.transcoding.as_deref(), Some("mp3_0_0"));
+    assert!(track.url.unwrap().ends_with("/stream/progressive"));
+
+    let metadata = track.metadata;
+    assert_eq!(
+        metadata.artwork_url(),
+        Some("https://i1.sndcdn.com/artworks-000123-abcdef-t500x500.jpg")
+    );
+    assert_eq!(metadata.artwork.len(), 3);
+    assert_eq!(metadata.genre.as_deref(), Some("Deep House"));
+    assert_eq!(metadata.tags, vec!["house", "deep house", "night"]);
+    assert_eq!(metadata.label.as_deref(), Some("Robo Records"));
+    assert_eq!(metadata.album.as_deref(), Some("Night Drives"));
+    assert_eq!(metadata.explicit, Some(false));
+    assert_eq!(
+        metadata.release_date.as_deref(),
+        Some("2021-03-01T00:00:00Z")
+    );
+}
+
+#[test]
+fn converts_partial_track() {
+    let track = track(include_str!("fixtures/track_partial.json")).unwrap();
+    assert_eq!(track.artist, None);
+    assert_eq!(track.transcoding, None);
+    assert!(track.metadata.artwork.is_empty());
+    assert_eq!(track.metadata.genre, None);
+    assert!(track.metadata.tags.is_empty());
+    assert_eq!(track.metadata.album, None);
+
+    let current = CurrentTrack::new(&track, Utc::now()).unwrap();
+    assert_eq!(current.artist, "");
+    assert_eq!(current.artwork_url, None);
+}
+
+#[test]
+fn fails_on_missing_fields() {
+    let cases = [
+        (include_str!("fixtures/track_without_media.json"), "media"),
+        (
+            include_str!("fixtures/track_hls_only.json"),
+            "progressive transcoding",
+        ),
+        (
+            include_str!("fixtures/track_without_authorization.json"),
+            "track_authorization",
+        ),
+        (
+            include_str!("fixtures/track_without_duration.json"),
+            "duration",
+        ),
+    ];
+    for (fixture, missing) in cases {
+        match track(fixture) {
+            Err(Error::SoundcloudIncompleteTrack(_, field)) => assert_eq!(field, missing),
+            other => panic!("expected `{}` to be missing, got {:?}", missing, other),
+        }
+    }
+}
+
+#[test]
+fn rejects_malformed_track() {
+    let fixture = include_str!("fixtures/track_malformed.json");
+    assert!(serde_json::from_str::<TrackResponse>(fixture).is_err());
+}
+
+#[test]
+fn converts_partial_playlist() {
+    let fixture = include_str!("fixtures/playlist_partial.json");
+    let response: PlaylistResponse = serde_json::from_str(fixture).unwrap();
+    let playlist = Playlist::from(response);
+    assert_eq!(playlist.tracks_ids, vec![1001, 1008, 1009]);
+    assert_eq!(playlist.secret_tokens.len(), 1);
+    assert_eq!(
+        playlist.secret_tokens.get(&1008).map(String::as_str),
+        Some("s-FgHiJ")
+    );
+}
+
+#[test]
+fn rejects_malformed_playlist() {
+    let fixture = include_str!("fixtures/playlist_malformed.json");
+    assert!(serde_json::from_str::<PlaylistResponse>(fixture).is_err());
+}
+
+#[test]
+fn current_track_needs_url_and_duration() {
+    let mut track = track(include_str!("fixtures/track_complete.json")).unwrap();
+    track.duration = Some(0);
+    assert!(matches!(
+        CurrentTrack::new(&track, Utc::now()),
+        Err(Error::TrackIncomplete(1001, "duration"))
+    ));
+
+    track.duration = Some(215040);
+    track.url = None;
+    assert!(matches!(
+        CurrentTrack::new(&track, Utc::now()),
+        Err(Error::TrackIncomplete(1001, "url"))
+    ));
+}
+
+#[tokio::test]
+async fn keeps_stream_errors_out_of_the_breaker() {
+    let breaker = CircuitBreaker::new(
+        BreakerConfig {
+            threshold: 1,
+            open_secs: 60,
+        },
+        Arc::default(),
+    );
+    let api = ApiClient::new(Arc::new(breaker), Arc::default());
+    // Nothing listens there, as if the CDN was down
+    let result = api.get_stream("http://127.0.0.1:9/track.mp3", None).await;
+    assert!(matches!(result, Err(Error::SoundcloudRequestError(_))));
+    assert_eq!(api.breaker_state(), BreakerState::Closed);
+}
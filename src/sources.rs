@@ -0,0 +1,385 @@
+use crate::{
+    error::Error,
+    library::LocalLibrary,
+    soundcloud::{permalink, ApiClient, Resource},
+};
+use anyhow::Result;
+use std::{fmt, str::FromStr};
+
+// A collection of tracks feeding the station's rotation
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SourceKind {
+    // Playlist id or permalink
+    Playlist(String),
+    // Tracks liked by a user, given by id, username or permalink
+    Likes(String),
+    // Tracks uploaded by a user, given by id, username or permalink
+    Uploads(String),
+    // Track ids or permalinks
+    Tracks(Vec<String>),
+    // A playlist, a user's uploads or a track, depending on what the permalink points to
+    Permalink(String),
+    // The local library
+    Library,
+}
+
+// Sources are written `kind:value@weight`, e.g. `playlist:123@2`, `likes:someone`,
+// `tracks:https://soundcloud.com/a/b|https://soundcloud.com/c/d` or `library`. A bare permalink
+// is resolved to what it points to. The weight defaults to 1.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceSpec {
+    pub kind: SourceKind,
+    pub weight: u32,
+}
+
+impl SourceSpec {
+    // A playlist given by id or permalink
+    pub fn playlist(playlist: &str) -> Result<Self, Error> {
+        match normalize(playlist) {
+            Some(playlist) if !playlist.is_empty() => Ok(Self {
+                kind: SourceKind::Playlist(playlist),
+                weight: 1,
+            }),
+            _ => Err(Error::InvalidPermalink(playlist.to_string())),
+        }
+    }
+
+    pub fn library(weight: u32) -> Self {
+        Self {
+            kind: SourceKind::Library,
+            weight,
+        }
+    }
+
+    pub fn is_library(&self) -> bool {
+        self.kind == SourceKind::Library
+    }
+
+    // Ids of the tracks listed by the source
+    pub async fn fetch(
+        &self,
+        api: &ApiClient,
+        client_id: &str,
+        library: &LocalLibrary,
+    ) -> Result<Vec<u64>, Error> {
+        match &self.kind {
+            SourceKind::Playlist(playlist) if playlist.parse::<u64>().is_ok() => Ok(api
+                .get_playlist(client_id, playlist.as_str(), None)
+                .await?
+                .tracks_ids),
+            SourceKind::Playlist(playlist) => match api.resolve(client_id, playlist).await? {
+                Resource::Playlist { id, secret_token } => {
+                    fetch_playlist(api, client_id, id, secret_token).await
+                }
+                _ => Err(Error::InvalidSource(self.to_string())),
+            },
+            SourceKind::Likes(user) => {
+                let user_id = self.resolve_user(api, client_id, user).await?;
+                api.get_user_likes(client_id, user_id).await
+            }
+            SourceKind::Uploads(user) => {
+                let user_id = self.resolve_user(api, client_id, user).await?;
+                api.get_user_tracks(client_id, user_id).await
+            }
+            SourceKind::Tracks(tracks) => {
+                let mut tracks_ids = vec![];
+                for track in tracks {
+                    match track.parse::<u64>() {
+                        Ok(id) => tracks_ids.push(id),
+                        Err(_) => match api.resolve_track_id(client_id, track).await {
+                            Ok(id) => tracks_ids.push(id),
+                            Err(err) if err.is_outage() => return Err(err),
+                            Err(err) => tracing::warn!("skipping track `{}`: {}", track, err),
+                        },
+                    }
+                }
+                Ok(tracks_ids)
+            }
+            SourceKind::Permalink(url) => match api.resolve(client_id, url).await? {
+                Resource::Playlist { id, secret_token } => {
+                    fetch_playlist(api, client_id, id, secret_token).await
+                }
+                Resource::User { id, .. } => api.get_user_tracks(client_id, id).await,
+                Resource::Track { id, .. } => Ok(vec![id]),
+                Resource::Other { .. } => Err(Error::InvalidSource(self.to_string())),
+            },
+            SourceKind::Library => Ok(library.tracks_ids()),
+        }
+    }
+
+    async fn resolve_user(
+        &self,
+        api: &ApiClient,
+        client_id: &str,
+        user: &str,
+    ) -> Result<u64, Error> {
+        if let Ok(id) = user.parse() {
+            return Ok(id);
+        }
+        let url = match is_url(user) {
+            true => user.to_string(),
+            false => format!("https://soundcloud.com/{}", user),
+        };
+        match api.resolve(client_id, url.as_str()).await? {
+            Resource::User { id, .. } => Ok(id),
+            _ => Err(Error::InvalidSource(self.to_string())),
+        }
+    }
+}
+
+impl FromStr for SourceSpec {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self, Error> {
+        let invalid = || Error::InvalidSource(value.to_string());
+        let value = value.trim();
+        let (spec, weight) = match value.rsplit_once('@') {
+            Some((spec, weight)) => match weight.parse::<u32>() {
+                Ok(weight) => (spec, weight),
+                Err(_) if weight.trim().is_empty() => return Err(invalid()),
+                Err(_) => (value, 1),
+            },
+            None => (value, 1),
+        };
+        if weight == 0 {
+            return Err(invalid());
+        }
+
+        let kind = match spec.split_once(':') {
+            _ if spec == "library" => SourceKind::Library,
+            Some(("playlist", playlist)) => {
+                SourceKind::Playlist(normalize(playlist).ok_or_else(invalid)?)
+            }
+            Some(("likes", user)) => SourceKind::Likes(normalize(user).ok_or_else(invalid)?),
+            Some(("uploads", user)) => SourceKind::Uploads(normalize(user).ok_or_else(invalid)?),
+            Some(("tracks", tracks)) => SourceKind::Tracks(
+                tracks
+                    .split('|')
+                    .map(str::trim)
+                    .filter(|track| !track.is_empty())
+                    .map(|track| normalize(track).ok_or_else(invalid))
+                    .collect::<Result<_, _>>()?,
+            ),
+            _ if is_url(spec) => SourceKind::Permalink(normalize(spec).ok_or_else(invalid)?),
+            _ => return Err(invalid()),
+        };
+
+        match &kind {
+            SourceKind::Playlist(value) | SourceKind::Likes(value) | SourceKind::Uploads(value)
+                if value.is_empty() =>
+            {
+                Err(invalid())
+            }
+            SourceKind::Tracks(tracks) if tracks.is_empty() => Err(invalid()),
+            _ => Ok(Self { kind, weight }),
+        }
+    }
+}
+
+impl fmt::Display for SourceSpec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.kind {
+            SourceKind::Playlist(playlist) => write!(f, "playlist:{}", playlist)?,
+            SourceKind::Likes(user) => write!(f, "likes:{}", user)?,
+            SourceKind::Uploads(user) => write!(f, "uploads:{}", user)?,
+            SourceKind::Tracks(tracks) => write!(f, "tracks:{}", tracks.join("|"))?,
+            SourceKind::Permalink(url) => write!(f, "{}", url)?,
+            SourceKind::Library => write!(f, "library")?,
+        }
+        if self.weight != 1 {
+            write!(f, "@{}", self.weight)?;
+        }
+        Ok(())
+    }
+}
+
+fn is_url(value: &str) -> bool {
+    value.starts_with("https://")
+        || value.starts_with("http://")
+        || value.contains("soundcloud.com/")
+}
+
+// Permalinks are normalized, ids and usernames are kept as is
+fn normalize(value: &str) -> Option<String> {
+    let value = value.trim();
+    match is_url(value) {
+        true => permalink(value),
+        false => Some(value.to_string()),
+    }
+}
+
+async fn fetch_playlist(
+    api: &ApiClient,
+    client_id: &str,
+    playlist_id: u64,
+    secret_token: Option<String>,
+) -> Result<Vec<u64>, Error> {
+    Ok(api
+        .get_playlist(
+            client_id,
+            playlist_id.to_string().as_str(),
+            secret_token.as_deref(),
+        )
+        .await?
+        .tracks_ids)
+}
+
+// Sources to be fetched again. It's taken out of the media player, so that the network calls
+// don't need to hold its lock.
+#[derive(Debug)]
+pub struct SourcesRefresh {
+    api: ApiClient,
+    client_id: String,
+    sources: Vec<SourceSpec>,
+    library: LocalLibrary,
+}
+
+impl SourcesRefresh {
+    pub fn new(
+        api: ApiClient,
+        client_id: String,
+        sources: Vec<SourceSpec>,
+        library: LocalLibrary,
+    ) -> Self {
+        Self {
+            api,
+            client_id,
+            sources,
+            library,
+        }
+    }
+
+    // The tracks of each source
+    pub async fn run(self) -> FetchedSources {
+        let mut tracks = vec![];
+        for source in &self.sources {
+            let result = source
+                .fetch(&self.api, self.client_id.as_str(), &self.library)
+                .await;
+            if let Err(err) = &result {
+                tracing::warn!("unable to fetch source {}: {}", source, err);
+            }
+            tracks.push(result);
+        }
+        FetchedSources {
+            sources: self.sources,
+            tracks,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct FetchedSources {
+    pub sources: Vec<SourceSpec>,
+    // Tracks of each source, in the same order
+    pub tracks: Vec<Result<Vec<u64>, Error>>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(value: &str) -> Result<SourceSpec, Error> {
+        value.parse()
+    }
+
+    fn spec(kind: SourceKind, weight: u32) -> SourceSpec {
+        SourceSpec { kind, weight }
+    }
+
+    #[test]
+    fn parses_each_kind() {
+        assert_eq!(
+            parse("playlist:123@2").unwrap(),
+            spec(SourceKind::Playlist(String::from("123")), 2)
+        );
+        assert_eq!(
+            parse(" likes:someone@3 ").unwrap(),
+            spec(SourceKind::Likes(String::from("someone")), 3)
+        );
+        assert_eq!(
+            parse("uploads:https://m.soundcloud.com/someone/").unwrap(),
+            spec(
+                SourceKind::Uploads(String::from("https://soundcloud.com/someone")),
+                1
+            )
+        );
+        assert_eq!(
+            parse("tracks:42| https://soundcloud.com/a/b?si=x |").unwrap(),
+            spec(
+                SourceKind::Tracks(vec![
+                    String::from("42"),
+                    String::from("https://soundcloud.com/a/b")
+                ]),
+                1
+            )
+        );
+        assert_eq!(
+            parse("soundcloud.com/someone/sets/mix@4").unwrap(),
+            spec(
+                SourceKind::Permalink(String::from("https://soundcloud.com/someone/sets/mix")),
+                4
+            )
+        );
+        assert_eq!(parse("library@5").unwrap(), SourceSpec::library(5));
+    }
+
+    #[test]
+    fn round_trips_through_display() {
+        for value in ["playlist:123@2", "likes:someone", "tracks:1|2", "library@3"] {
+            assert_eq!(parse(value).unwrap().to_string(), value);
+        }
+    }
+
+    #[test]
+    fn defaults_a_missing_weight_to_one() {
+        assert_eq!(parse("playlist:123").unwrap().weight, 1);
+        assert_eq!(parse("library").unwrap().weight, 1);
+    }
+
+    #[test]
+    fn rejects_a_zero_or_empty_weight() {
+        for value in [
+            "playlist:123@0",
+            "library@0",
+            "playlist:123@",
+            "likes:someone@ ",
+        ] {
+            assert!(
+                matches!(parse(value), Err(Error::InvalidSource(_))),
+                "{}",
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_kinds() {
+        for value in ["album:123", "user:someone", "libraries", "123"] {
+            assert!(
+                matches!(parse(value), Err(Error::InvalidSource(_))),
+                "{}",
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_specs() {
+        for value in [
+            "",
+            "playlist:",
+            "likes: ",
+            "tracks:|",
+            "tracks:42|https://example.com/a/b",
+            "playlist:https://example.com/sets/mix",
+            "https://soundcloud.com/",
+        ] {
+            assert!(
+                matches!(parse(value), Err(Error::InvalidSource(_))),
+                "{}",
+                value
+            );
+        }
+    }
+}
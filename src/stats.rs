@@ -0,0 +1,670 @@
+use crate::{config::StatsConfig, error::Error, history::Play};
+use chrono::{DateTime, Duration, NaiveDate, TimeZone, Timelike, Utc};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+    sync::Mutex,
+};
+
+const DEFAULT_TOP_LIMIT: usize = 10;
+const MAX_TOP_LIMIT: usize = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Resolution {
+    Minute,
+    Hour,
+    Day,
+}
+
+impl Resolution {
+    fn secs(self) -> i64 {
+        match self {
+            Resolution::Minute => 60,
+            Resolution::Hour => 3600,
+            Resolution::Day => 86400,
+        }
+    }
+
+    // Start of the period holding `at`, as a timestamp
+    fn start(self, at: DateTime<Utc>) -> i64 {
+        let timestamp = at.timestamp();
+        timestamp - timestamp.rem_euclid(self.secs())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct LoadCounts {
+    pub attempts: u64,
+    pub errors: u64,
+}
+
+// What happened during a minute, an hour or a day
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct Bucket {
+    samples: u64,
+    listeners_sum: u64,
+    listeners_peak: u64,
+    // Sessions that ended, and how long they lasted
+    sessions: u64,
+    session_secs: u64,
+    plays: u64,
+    skips: u64,
+    // Tracks loaded from each source
+    loads: BTreeMap<String, LoadCounts>,
+}
+
+impl Bucket {
+    fn merge(&mut self, other: &Bucket) {
+        self.samples += other.samples;
+        self.listeners_sum += other.listeners_sum;
+        self.listeners_peak = self.listeners_peak.max(other.listeners_peak);
+        self.sessions += other.sessions;
+        self.session_secs += other.session_secs;
+        self.plays += other.plays;
+        self.skips += other.skips;
+        for (source, counts) in &other.loads {
+            let total = self.loads.entry(source.clone()).or_default();
+            total.attempts += counts.attempts;
+            total.errors += counts.errors;
+        }
+    }
+
+    fn average_listeners(&self) -> f64 {
+        ratio(self.listeners_sum, self.samples)
+    }
+
+    fn average_session_secs(&self) -> f64 {
+        ratio(self.session_secs, self.sessions)
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct TrackCounts {
+    title: String,
+    artist: String,
+    plays: u64,
+    skips: u64,
+}
+
+// Rollups by minute, hour and day, updated as things happen so that reports never go through the
+// raw history
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct Rollups {
+    minutes: BTreeMap<i64, Bucket>,
+    hours: BTreeMap<i64, Bucket>,
+    days: BTreeMap<i64, Bucket>,
+    // Plays of each track, by day
+    tracks: BTreeMap<i64, HashMap<u64, TrackCounts>>,
+}
+
+impl Rollups {
+    fn update(&mut self, at: DateTime<Utc>, update: impl Fn(&mut Bucket)) {
+        for resolution in [Resolution::Minute, Resolution::Hour, Resolution::Day] {
+            let buckets = match resolution {
+                Resolution::Minute => &mut self.minutes,
+                Resolution::Hour => &mut self.hours,
+                Resolution::Day => &mut self.days,
+            };
+            update(buckets.entry(resolution.start(at)).or_default());
+        }
+    }
+
+    fn buckets(&self, resolution: Resolution) -> &BTreeMap<i64, Bucket> {
+        match resolution {
+            Resolution::Minute => &self.minutes,
+            Resolution::Hour => &self.hours,
+            Resolution::Day => &self.days,
+        }
+    }
+
+    fn prune(&mut self, now: DateTime<Utc>, config: &StatsConfig) {
+        let minutes_from = (now - Duration::hours(config.minutes_retention_hours)).timestamp();
+        self.minutes = self.minutes.split_off(&minutes_from);
+        let hours_from = (now - Duration::days(config.hours_retention_days)).timestamp();
+        self.hours = self.hours.split_off(&hours_from);
+    }
+}
+
+// A date range of a report, `from` included and `to` excluded
+#[derive(Debug, Clone)]
+pub struct StatsQuery {
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+    pub resolution: Resolution,
+    pub limit: usize,
+}
+
+impl StatsQuery {
+    // Dates are RFC 3339 or `YYYY-MM-DD`, a day given as `to` is included. Defaults to the last
+    // day by hour.
+    pub fn new(
+        from: Option<&str>,
+        to: Option<&str>,
+        resolution: Option<Resolution>,
+        limit: Option<usize>,
+        now: DateTime<Utc>,
+    ) -> Result<Self, Error> {
+        let to = match to {
+            Some(to) => parse_date(to, true)?,
+            None => now,
+        };
+        let from = match from {
+            Some(from) => parse_date(from, false)?,
+            None => to - Duration::days(1),
+        };
+        if from >= to {
+            return Err(Error::InvalidStatsQuery(String::from(
+                "`from` must be before `to`",
+            )));
+        }
+        Ok(Self {
+            from,
+            to,
+            resolution: resolution.unwrap_or(Resolution::Hour),
+            limit: limit.unwrap_or(DEFAULT_TOP_LIMIT).min(MAX_TOP_LIMIT),
+        })
+    }
+
+    fn contains(&self, start: i64, resolution: Resolution) -> bool {
+        start >= resolution.start(self.from) && start < self.to.timestamp()
+    }
+}
+
+fn parse_date(value: &str, end_of_day: bool) -> Result<DateTime<Utc>, Error> {
+    let value = value.trim();
+    if let Ok(at) = DateTime::parse_from_rfc3339(value) {
+        return Ok(at.with_timezone(&Utc));
+    }
+    let date = NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .map_err(|_| Error::InvalidStatsQuery(format!("`{}` is not a valid date", value)))?;
+    let midnight = Utc.from_utc_datetime(&date.and_hms_opt(0, 0, 0).unwrap());
+    Ok(match end_of_day {
+        true => midnight + Duration::days(1),
+        false => midnight,
+    })
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ListenersPoint {
+    pub at: DateTime<Utc>,
+    pub average: f64,
+    pub peak: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TrackStat {
+    pub track_id: u64,
+    pub title: String,
+    pub artist: String,
+    pub plays: u64,
+    pub skips: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ArtistStat {
+    pub artist: String,
+    pub plays: u64,
+}
+
+// Average listeners at an hour of the day, UTC
+#[derive(Debug, Clone, Serialize)]
+pub struct HourStat {
+    pub hour: u32,
+    pub average_listeners: f64,
+    pub peak_listeners: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SourceStat {
+    pub source: String,
+    pub attempts: u64,
+    pub errors: u64,
+    pub error_rate: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StatsReport {
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+    pub resolution: Resolution,
+    pub listeners: Vec<ListenersPoint>,
+    pub sessions: u64,
+    pub average_session_secs: f64,
+    pub plays: u64,
+    pub skips: u64,
+    pub most_played: Vec<TrackStat>,
+    pub most_skipped: Vec<TrackStat>,
+    pub top_artists: Vec<ArtistStat>,
+    // Busiest hours first
+    pub peak_hours: Vec<HourStat>,
+    pub sources: Vec<SourceStat>,
+}
+
+// Station analytics, fed by the station as listeners come and go and tracks are aired. The
+// rollups are saved to the stats file, when there's one, and read back on start.
+#[derive(Debug, Default)]
+pub struct Stats {
+    config: StatsConfig,
+    rollups: Mutex<Rollups>,
+}
+
+impl Stats {
+    pub fn new(config: StatsConfig) -> Self {
+        let mut rollups = Rollups::default();
+        if let Some(path) = &config.file {
+            match std::fs::read(path) {
+                Ok(content) => match serde_json::from_slice(&content) {
+                    Ok(saved) => rollups = saved,
+                    Err(err) => tracing::warn!("ignoring stats `{}`: {}", path.display(), err),
+                },
+                Err(err) => tracing::info!("no stats read from `{}`: {}", path.display(), err),
+            }
+        }
+        Self {
+            config,
+            rollups: Mutex::new(rollups),
+        }
+    }
+
+    pub fn sample_listeners(&self, listeners: usize, now: DateTime<Utc>) {
+        let mut rollups = self.rollups.lock().unwrap();
+        rollups.update(now, |bucket| {
+            bucket.samples += 1;
+            bucket.listeners_sum += listeners as u64;
+            bucket.listeners_peak = bucket.listeners_peak.max(listeners as u64);
+        });
+        rollups.prune(now, &self.config);
+    }
+
+    pub fn session_ended(&self, connected_secs: i64, now: DateTime<Utc>) {
+        self.rollups.lock().unwrap().update(now, |bucket| {
+            bucket.sessions += 1;
+            bucket.session_secs += connected_secs.max(0) as u64;
+        });
+    }
+
+    pub fn play_finished(&self, play: &Play) {
+        let skipped = play.skipped();
+        let mut rollups = self.rollups.lock().unwrap();
+        rollups.update(play.started_at, |bucket| {
+            bucket.plays += 1;
+            bucket.skips += skipped as u64;
+        });
+        let day = Resolution::Day.start(play.started_at);
+        let counts = rollups
+            .tracks
+            .entry(day)
+            .or_default()
+            .entry(play.track_id)
+            .or_default();
+        counts.title = play.title.clone();
+        counts.artist = play.artist.clone();
+        counts.plays += 1;
+        counts.skips += skipped as u64;
+    }
+
+    // A track loaded, or not, from the `source`
+    pub fn track_loaded(&self, source: &str, ok: bool) {
+        self.rollups.lock().unwrap().update(Utc::now(), |bucket| {
+            let counts = bucket.loads.entry(source.to_string()).or_default();
+            counts.attempts += 1;
+            counts.errors += !ok as u64;
+        });
+    }
+
+    pub fn report(&self, query: &StatsQuery) -> StatsReport {
+        let hours_retention_days = self.config.hours_retention_days;
+        let rollups = self.rollups.lock().unwrap();
+
+        let listeners = series(&rollups, query)
+            .into_iter()
+            .filter(|(_, bucket)| bucket.samples > 0)
+            .map(|(at, bucket)| ListenersPoint {
+                at,
+                average: bucket.average_listeners(),
+                peak: bucket.listeners_peak,
+            })
+            .collect();
+
+        // Totals come from the hours while they're kept, from the whole days otherwise
+        let resolution = match query.from >= Utc::now() - Duration::days(hours_retention_days) {
+            true => Resolution::Hour,
+            false => Resolution::Day,
+        };
+        let mut totals = Bucket::default();
+        for (_, bucket) in rollups
+            .buckets(resolution)
+            .iter()
+            .filter(|(start, _)| query.contains(**start, resolution))
+        {
+            totals.merge(bucket);
+        }
+
+        let tracks = track_stats(&rollups, query);
+        let mut most_played = tracks.clone();
+        most_played.sort_by(|a, b| b.plays.cmp(&a.plays).then(a.track_id.cmp(&b.track_id)));
+        most_played.truncate(query.limit);
+        let mut most_skipped: Vec<TrackStat> =
+            tracks.iter().filter(|t| t.skips > 0).cloned().collect();
+        most_skipped.sort_by(|a, b| b.skips.cmp(&a.skips).then(a.track_id.cmp(&b.track_id)));
+        most_skipped.truncate(query.limit);
+
+        let mut artists: HashMap<&str, u64> = HashMap::new();
+        for track in tracks.iter().filter(|t| !t.artist.is_empty()) {
+            *artists.entry(track.artist.as_str()).or_default() += track.plays;
+        }
+        let mut top_artists: Vec<ArtistStat> = artists
+            .into_iter()
+            .map(|(artist, plays)| ArtistStat {
+                artist: artist.to_string(),
+                plays,
+            })
+            .collect();
+        top_artists.sort_by(|a, b| b.plays.cmp(&a.plays).then(a.artist.cmp(&b.artist)));
+        top_artists.truncate(query.limit);
+
+        StatsReport {
+            from: query.from,
+            to: query.to,
+            resolution: query.resolution,
+            listeners,
+            sessions: totals.sessions,
+            average_session_secs: totals.average_session_secs(),
+            plays: totals.plays,
+            skips: totals.skips,
+            most_played,
+            most_skipped,
+            top_artists,
+            peak_hours: peak_hours(&rollups, query),
+            sources: totals
+                .loads
+                .iter()
+                .map(|(source, counts)| SourceStat {
+                    source: source.clone(),
+                    attempts: counts.attempts,
+                    errors: counts.errors,
+                    error_rate: ratio(counts.errors, counts.attempts),
+                })
+                .collect(),
+        }
+    }
+
+    // One row per period of the query's resolution
+    pub fn series_csv(&self, query: &StatsQuery) -> String {
+        let rollups = self.rollups.lock().unwrap();
+        let mut csv = String::from(
+            "at,average_listeners,peak_listeners,sessions,average_session_secs,plays,skips,load_attempts,load_errors\n",
+        );
+        for (at, bucket) in series(&rollups, query) {
+            let (attempts, errors) = bucket.loads.values().fold((0, 0), |(a, e), counts| {
+                (a + counts.attempts, e + counts.errors)
+            });
+            let _ = writeln!(
+                csv,
+                "{},{:.2},{},{},{:.0},{},{},{},{}",
+                at.to_rfc3339(),
+                bucket.average_listeners(),
+                bucket.listeners_peak,
+                bucket.sessions,
+                bucket.average_session_secs(),
+                bucket.plays,
+                bucket.skips,
+                attempts,
+                errors
+            );
+        }
+        csv
+    }
+
+    // One row per track aired, most played first
+    pub fn tracks_csv(&self, query: &StatsQuery) -> String {
+        let mut tracks = track_stats(&self.rollups.lock().unwrap(), query);
+        tracks.sort_by(|a, b| b.plays.cmp(&a.plays).then(a.track_id.cmp(&b.track_id)));
+        let mut csv = String::from("track_id,title,artist,plays,skips\n");
+        for track in tracks {
+            let _ = writeln!(
+                csv,
+                "{},{},{},{},{}",
+                track.track_id,
+                csv_field(&track.title),
+                csv_field(&track.artist),
+                track.plays,
+                track.skips
+            );
+        }
+        csv
+    }
+
+    // Writes the rollups to the stats file, through a temporary file so that a crash can't leave
+    // it half written
+    pub async fn save(&self) {
+        let path = match self.config.file.clone() {
+            Some(path) => path,
+            None => return,
+        };
+        let content = match serde_json::to_vec(&*self.rollups.lock().unwrap()) {
+            Ok(content) => content,
+            Err(err) => {
+                tracing::warn!("unable to serialize stats: {}", err);
+                return;
+            }
+        };
+        let tmp = path.with_extension("tmp");
+        let result = match tokio::fs::write(&tmp, content).await {
+            Ok(()) => tokio::fs::rename(&tmp, &path).await,
+            Err(err) => Err(err),
+        };
+        if let Err(err) = result {
+            tracing::warn!("unable to save stats to `{}`: {}", path.display(), err);
+        }
+    }
+}
+
+// Periods of the query's resolution within its range, with everything that happened in them
+fn series(rollups: &Rollups, query: &StatsQuery) -> Vec<(DateTime<Utc>, Bucket)> {
+    rollups
+        .buckets(query.resolution)
+        .iter()
+        .filter(|(start, _)| query.contains(**start, query.resolution))
+        .map(|(start, bucket)| (Utc.timestamp_opt(*start, 0).unwrap(), bucket.clone()))
+        .collect()
+}
+
+// Plays of the tracks aired on the days within the query's range
+fn track_stats(rollups: &Rollups, query: &StatsQuery) -> Vec<TrackStat> {
+    let mut tracks: HashMap<u64, TrackStat> = HashMap::new();
+    for (_, day) in rollups
+        .tracks
+        .iter()
+        .filter(|(start, _)| query.contains(**start, Resolution::Day))
+    {
+        for (track_id, counts) in day {
+            let track = tracks.entry(*track_id).or_insert_with(|| TrackStat {
+                track_id: *track_id,
+                title: counts.title.clone(),
+                artist: counts.artist.clone(),
+                plays: 0,
+                skips: 0,
+            });
+            track.plays += counts.plays;
+            track.skips += counts.skips;
+        }
+    }
+    tracks.into_values().collect()
+}
+
+fn peak_hours(rollups: &Rollups, query: &StatsQuery) -> Vec<HourStat> {
+    let mut hours: BTreeMap<u32, Bucket> = BTreeMap::new();
+    for (at, bucket) in rollups
+        .hours
+        .iter()
+        .filter(|(start, _)| query.contains(**start, Resolution::Hour))
+        .filter_map(|(start, bucket)| Some((Utc.timestamp_opt(*start, 0).single()?, bucket)))
+    {
+        hours.entry(at.hour()).or_default().merge(bucket);
+    }
+    let mut hours: Vec<HourStat> = hours
+        .into_iter()
+        .filter(|(_, bucket)| bucket.samples > 0)
+        .map(|(hour, bucket)| HourStat {
+            hour,
+            average_listeners: bucket.average_listeners(),
+            peak_listeners: bucket.listeners_peak,
+        })
+        .collect();
+    hours.sort_by(|a, b| b.average_listeners.total_cmp(&a.average_listeners));
+    hours
+}
+
+fn ratio(value: u64, count: u64) -> f64 {
+    match count {
+        0 => 0.0,
+        count => value as f64 / count as f64,
+    }
+}
+
+// Titles and artists come from uploaders, the ones that would read as a formula in a spreadsheet
+// are prefixed with a quote
+fn csv_field(value: &str) -> String {
+    let value = match value.starts_with(['=', '+', '-', '@', '\t', '\r']) {
+        true => format!("'{}", value),
+        false => value.to_string(),
+    };
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::media_player::Source;
+
+    fn hour_start() -> DateTime<Utc> {
+        Utc.timestamp_opt(Resolution::Hour.start(Utc::now()), 0)
+            .unwrap()
+    }
+
+    fn query(from: DateTime<Utc>, resolution: Resolution) -> StatsQuery {
+        StatsQuery {
+            from,
+            to: from + Duration::hours(3),
+            resolution,
+            limit: DEFAULT_TOP_LIMIT,
+        }
+    }
+
+    fn play(track_id: u64, title: &str, artist: &str, at: DateTime<Utc>, secs: i64) -> Play {
+        Play {
+            track_id,
+            title: title.to_string(),
+            artist: artist.to_string(),
+            source: Source::default(),
+            started_at: at,
+            duration: 180_000,
+            ended_at: Some(at + Duration::seconds(secs)),
+            reactions: Default::default(),
+        }
+    }
+
+    #[test]
+    fn quotes_csv_fields() {
+        assert_eq!(csv_field("Daft Punk"), "Daft Punk");
+        assert_eq!(csv_field("Earth, Wind & Fire"), "\"Earth, Wind & Fire\"");
+        assert_eq!(csv_field("The \"Best\" Mix"), "\"The \"\"Best\"\" Mix\"");
+        assert_eq!(csv_field("Side A\nSide B"), "\"Side A\nSide B\"");
+        assert_eq!(csv_field("Side A\r\nSide B"), "\"Side A\r\nSide B\"");
+    }
+
+    #[test]
+    fn defuses_csv_formulas() {
+        assert_eq!(csv_field("=1+1"), "'=1+1");
+        assert_eq!(csv_field("+33 6 12"), "'+33 6 12");
+        assert_eq!(csv_field("-core"), "'-core");
+        assert_eq!(csv_field("@SUM(A1)"), "'@SUM(A1)");
+        assert_eq!(csv_field("\tTab"), "'\tTab");
+        assert_eq!(
+            csv_field("=HYPERLINK(\"http://x\", \"y\")"),
+            "\"'=HYPERLINK(\"\"http://x\"\", \"\"y\"\")\""
+        );
+        // Only the first character matters
+        assert_eq!(csv_field("A-ha"), "A-ha");
+    }
+
+    #[test]
+    fn exports_tracks_as_csv() {
+        let stats = Stats::default();
+        let at = hour_start();
+        stats.play_finished(&play(1, "Hello, \"World\"", "=cmd|' /C calc'!A0", at, 180));
+        stats.play_finished(&play(1, "Hello, \"World\"", "=cmd|' /C calc'!A0", at, 10));
+        stats.play_finished(&play(2, "Plain", "Artist", at, 180));
+
+        assert_eq!(
+            stats.tracks_csv(&query(at - Duration::hours(1), Resolution::Hour)),
+            "track_id,title,artist,plays,skips\n\
+             1,\"Hello, \"\"World\"\"\",'=cmd|' /C calc'!A0,2,1\n\
+             2,Plain,Artist,1,0\n"
+        );
+    }
+
+    #[test]
+    fn rolls_up_listeners_and_plays() {
+        let stats = Stats::default();
+        let at = hour_start();
+        stats.sample_listeners(2, at);
+        stats.sample_listeners(4, at + Duration::seconds(30));
+        stats.sample_listeners(6, at + Duration::minutes(1));
+        stats.session_ended(100, at);
+        stats.session_ended(300, at);
+        stats.play_finished(&play(1, "One", "Artist", at, 180));
+        stats.play_finished(&play(2, "Two", "Artist", at, 10));
+
+        let minutes = stats.report(&query(at, Resolution::Minute));
+        let points: Vec<(f64, u64)> = minutes
+            .listeners
+            .iter()
+            .map(|point| (point.average, point.peak))
+            .collect();
+        assert_eq!(points, vec![(3.0, 4), (6.0, 6)]);
+
+        let hours = stats.report(&query(at, Resolution::Hour));
+        assert_eq!(hours.listeners.len(), 1);
+        assert_eq!(hours.listeners[0].average, 4.0);
+        assert_eq!(hours.listeners[0].peak, 6);
+        assert_eq!(hours.sessions, 2);
+        assert_eq!(hours.average_session_secs, 200.0);
+        assert_eq!((hours.plays, hours.skips), (2, 1));
+        assert_eq!(hours.most_skipped.len(), 1);
+        assert_eq!(hours.top_artists[0].plays, 2);
+    }
+
+    #[test]
+    fn prunes_minutes_and_hours_past_their_retention() {
+        let config = StatsConfig::default();
+        let now = hour_start();
+        let mut rollups = Rollups::default();
+        let old = now - Duration::hours(config.minutes_retention_hours + 1);
+        let older = now - Duration::days(config.hours_retention_days + 1);
+        for at in [older, old, now] {
+            rollups.update(at, |bucket| bucket.samples += 1);
+        }
+        rollups.prune(now, &config);
+
+        let starts = |resolution: Resolution| -> Vec<i64> {
+            rollups.buckets(resolution).keys().copied().collect()
+        };
+        assert_eq!(starts(Resolution::Minute), vec![now.timestamp()]);
+        assert_eq!(
+            starts(Resolution::Hour),
+            vec![old.timestamp(), now.timestamp()]
+        );
+        // Days are kept forever
+        assert_eq!(starts(Resolution::Day).len(), 3);
+    }
+}
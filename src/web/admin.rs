@@ -0,0 +1,266 @@
+use super::{
+    playout::{PlayoutCommand, PlayoutState},
+    radio::{ListenerInfo, StationService},
+};
+use crate::{error::Error, media_player::UpcomingTrack, soundcloud::Resource};
+use async_trait::async_trait;
+use axum::{
+    extract::{FromRequestParts, Path, Query, State},
+    headers::{authorization::Bearer, Authorization},
+    http::request::Parts,
+    Json, TypedHeader,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::net::IpAddr;
+
+const DEFAULT_QUEUE_LIMIT: usize = 20;
+
+// An authenticated admin, extracted from the `Authorization: Bearer <token>` header.
+#[derive(Debug, Clone)]
+pub struct Admin(pub String);
+
+#[async_trait]
+impl FromRequestParts<StationService> for Admin {
+    type Rejection = Error;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &StationService,
+    ) -> Result<Self, Self::Rejection> {
+        let TypedHeader(auth) =
+            TypedHeader::<Authorization<Bearer>>::from_request_parts(parts, state)
+                .await
+                .map_err(|_| Error::Unauthorized)?;
+
+        match state.admin_name(auth.token()) {
+            Some(name) => Ok(Admin(name)),
+            None => {
+                tracing::warn!(target: "audit", "rejected admin request with invalid token");
+                Err(Error::Unauthorized)
+            }
+        }
+    }
+}
+
+// Every admin action ends up in the audit log, with its outcome
+fn audit<T>(admin: &Admin, action: String, result: &Result<T, Error>) {
+    match result {
+        Ok(_) => tracing::info!(target: "audit", "admin `{}`: {}", admin.0, action),
+        Err(err) => {
+            tracing::warn!(target: "audit", "admin `{}` failed: {}: {}", admin.0, action, err)
+        }
+    }
+}
+
+// The playlist and the tracks can be given by id or by SoundCloud permalink
+#[derive(Debug, Deserialize)]
+pub struct PlaylistParams {
+    #[serde(alias = "url")]
+    pub playlist_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct QueueParams {
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct InsertParams {
+    #[serde(alias = "url")]
+    pub track_id: TrackParam,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum TrackParam {
+    Id(u64),
+    Text(String),
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResolveParams {
+    pub url: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AnnouncementParams {
+    pub message: String,
+}
+
+pub async fn playout_handler(
+    admin: Admin,
+    State(station): State<StationService>,
+    Json(command): Json<PlayoutCommand>,
+) -> Result<Json<PlayoutState>, Error> {
+    let playout = station.playout();
+    let result = playout.send(command.clone()).await;
+    audit(
+        &admin,
+        format!("send playout command {:?}", command),
+        &result,
+    );
+    Ok(Json(result?))
+}
+
+pub async fn skip_handler(
+    admin: Admin,
+    State(station): State<StationService>,
+) -> Result<Json<PlayoutState>, Error> {
+    let playout = station.playout();
+    let result = playout.send(PlayoutCommand::Skip).await;
+    audit(&admin, String::from("skip the current track"), &result);
+    Ok(Json(result?))
+}
+
+pub async fn reload_playlist_handler(
+    admin: Admin,
+    State(station): State<StationService>,
+) -> Result<Json<PlayoutState>, Error> {
+    let playout = station.playout();
+    let result = playout.send(PlayoutCommand::ReloadPlaylist).await;
+    audit(&admin, String::from("reload the playlist"), &result);
+    Ok(Json(result?))
+}
+
+pub async fn switch_playlist_handler(
+    admin: Admin,
+    State(station): State<StationService>,
+    Json(params): Json<PlaylistParams>,
+) -> Result<Json<Value>, Error> {
+    let result = station.switch_playlist(params.playlist_id.as_str()).await;
+    audit(
+        &admin,
+        format!("switch playlist to {}", params.playlist_id),
+        &result,
+    );
+    result?;
+    Ok(Json(json!({ "playlist_id": station.playlist_id().await })))
+}
+
+pub async fn resolve_handler(
+    _admin: Admin,
+    State(station): State<StationService>,
+    Query(params): Query<ResolveParams>,
+) -> Result<Json<Resource>, Error> {
+    Ok(Json(station.resolve(params.url.as_str()).await?))
+}
+
+pub async fn queue_handler(
+    _admin: Admin,
+    State(station): State<StationService>,
+    Query(params): Query<QueueParams>,
+) -> Json<Vec<UpcomingTrack>> {
+    let limit = params.limit.unwrap_or(DEFAULT_QUEUE_LIMIT);
+    Json(station.upcoming(limit).await)
+}
+
+pub async fn insert_queue_handler(
+    admin: Admin,
+    State(station): State<StationService>,
+    Json(params): Json<InsertParams>,
+) -> Result<Json<Vec<UpcomingTrack>>, Error> {
+    let (track, track_id) = match params.track_id {
+        TrackParam::Id(id) => (id.to_string(), Ok(id)),
+        TrackParam::Text(track) => {
+            let track_id = station.resolve_track_id(track.as_str()).await;
+            (track, track_id)
+        }
+    };
+    let result = match track_id {
+        Ok(track_id) => {
+            station
+                .playout()
+                .send(PlayoutCommand::InsertNext { track_id })
+                .await
+        }
+        Err(err) => Err(err),
+    };
+    audit(&admin, format!("insert track {} next", track), &result);
+    result?;
+    Ok(Json(station.upcoming(DEFAULT_QUEUE_LIMIT).await))
+}
+
+pub async fn remove_queue_handler(
+    admin: Admin,
+    State(station): State<StationService>,
+    Path(track_id): Path<u64>,
+) -> Result<Json<Vec<UpcomingTrack>>, Error> {
+    let result = station.remove_upcoming(track_id).await;
+    audit(
+        &admin,
+        format!("remove track {} from the queue", track_id),
+        &result,
+    );
+    result?;
+    Ok(Json(station.upcoming(DEFAULT_QUEUE_LIMIT).await))
+}
+
+pub async fn rotate_client_id_handler(
+    admin: Admin,
+    State(station): State<StationService>,
+) -> Result<Json<Value>, Error> {
+    let result = station.rotate_client_id().await;
+    audit(
+        &admin,
+        String::from("rotate the SoundCloud client id"),
+        &result,
+    );
+    result?;
+    Ok(Json(json!({ "rotated": true })))
+}
+
+pub async fn listeners_handler(
+    _admin: Admin,
+    State(station): State<StationService>,
+) -> Json<Vec<ListenerInfo>> {
+    Json(station.listeners())
+}
+
+pub async fn kick_handler(
+    admin: Admin,
+    State(station): State<StationService>,
+    Path(client_id): Path<String>,
+) -> Result<Json<Value>, Error> {
+    let result = station.kick(client_id.as_str());
+    audit(&admin, format!("kick listener {}", client_id), &result);
+    result?;
+    Ok(Json(json!({ "kicked": client_id })))
+}
+
+pub async fn ban_handler(
+    admin: Admin,
+    State(station): State<StationService>,
+    Path(client_id): Path<String>,
+) -> Result<Json<Value>, Error> {
+    let result = station.ban(client_id.as_str());
+    audit(&admin, format!("ban listener {}", client_id), &result);
+    Ok(Json(json!({ "banned": result?.ip })))
+}
+
+pub async fn bans_handler(
+    _admin: Admin,
+    State(station): State<StationService>,
+) -> Json<Vec<IpAddr>> {
+    Json(station.bans())
+}
+
+pub async fn unban_handler(
+    admin: Admin,
+    State(station): State<StationService>,
+    Path(ip): Path<IpAddr>,
+) -> Json<Value> {
+    let unbanned = station.unban(&ip);
+    audit::<()>(&admin, format!("unban {}", ip), &Ok(()));
+    Json(json!({ "unbanned": unbanned }))
+}
+
+pub async fn announcement_handler(
+    admin: Admin,
+    State(station): State<StationService>,
+    Json(params): Json<AnnouncementParams>,
+) -> Json<Value> {
+    station.announce(params.message.as_str());
+    audit::<()>(&admin, format!("announce {:?}", params.message), &Ok(()));
+    Json(json!({ "announced": true }))
+}
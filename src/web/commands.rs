@@ -0,0 +1,40 @@
+use crate::history::Reaction;
+use serde::Deserialize;
+
+// Commands sent by clients over the websocket, as `{"command": "...", "data": {...}}`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "command", content = "data", rename_all = "snake_case")]
+pub enum Command {
+    Request {
+        track: String,
+    },
+    VoteSkip,
+    // Nickname and avatar seed shown to the other listeners, blank ones are cleared
+    Profile {
+        #[serde(default)]
+        nickname: Option<String>,
+        #[serde(default)]
+        avatar_seed: Option<String>,
+    },
+    Chat {
+        message: String,
+    },
+    // Reaction to the track on air, `track_id` guards against reacting to the next one by mistake
+    React {
+        reaction: Reaction,
+        #[serde(default)]
+        track_id: Option<u64>,
+    },
+}
+
+impl Command {
+    pub fn parse(msg: &str) -> Option<Self> {
+        match serde_json::from_str(msg) {
+            Ok(cmd) => Some(cmd),
+            Err(err) => {
+                tracing::debug!("unrecognized command `{}`: {}", msg, err);
+                None
+            }
+        }
+    }
+}
@@ -0,0 +1,11 @@
+use super::radio::StationService;
+use tokio::time::{sleep, Duration};
+
+// Probes the primary source periodically while the station airs the emergency playlist, and
+// switches back to it as soon as it's up.
+pub async fn probe_primary(service: StationService, probe_every: Duration) {
+    loop {
+        sleep(probe_every).await;
+        service.probe_primary().await;
+    }
+}
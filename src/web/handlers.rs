@@ -1,17 +1,290 @@
-use super::{radio::StationService, ws::handle_client_connection};
+use super::{
+    radio::{PresenceReport, StationService},
+    ws::handle_client_connection,
+};
+use crate::{
+    chat::ChatMessage,
+    error::Error,
+    history::{Play, TrackRanking},
+    request_queue::SongRequest,
+    stats::{Resolution, StatsQuery, StatsReport},
+};
 use axum::{
-    extract::{State, WebSocketUpgrade},
+    extract::{ConnectInfo, Path, Query, State, WebSocketUpgrade},
+    headers::{authorization::Bearer, Authorization},
+    http::{header, HeaderMap, StatusCode},
     response::{Html, IntoResponse},
+    Json, TypedHeader,
 };
+use chrono::Utc;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::net::{IpAddr, SocketAddr};
+use tokio::time::{timeout, Duration};
+
+const DEFAULT_HISTORY_LIMIT: usize = 20;
+const MAX_HISTORY_LIMIT: usize = 100;
+
+// How long `/status` waits for the media player before reporting the station as busy
+const STATUS_LOCK_TIMEOUT: Duration = Duration::from_secs(1);
 
 // Include utf-8 file at **compile** time.
 pub async fn index_handler() -> Html<&'static str> {
     Html(std::include_str!("../../frontend/index.html"))
 }
 
+// The listener's ip, taken from `X-Forwarded-For` only when the peer is a trusted proxy
+fn client_ip(station: &StationService, addr: SocketAddr, headers: &HeaderMap) -> IpAddr {
+    let forwarded_for = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .collect::<Vec<_>>()
+        .join(",");
+    station.client_ip(addr.ip(), Some(forwarded_for.as_str()))
+}
+
 pub async fn websocket_handler(
     ws: WebSocketUpgrade,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    State(station): State<StationService>,
+) -> Result<impl IntoResponse, Error> {
+    let ip = client_ip(&station, addr, &headers);
+    if station.is_banned(&ip) {
+        return Err(Error::Forbidden);
+    }
+    let queue_config = station.client_queue_config();
+    let metrics = station.metrics();
+
+    Ok(ws.on_upgrade(move |socket| {
+        handle_client_connection(socket, station, Some(ip), queue_config, metrics)
+    }))
+}
+
+pub async fn healthz_handler() -> &'static str {
+    "ok"
+}
+
+pub async fn readyz_handler(State(station): State<StationService>) -> (StatusCode, String) {
+    match station.health().readiness() {
+        Ok(_) => (StatusCode::OK, String::from("ok")),
+        Err(reason) => (StatusCode::SERVICE_UNAVAILABLE, reason),
+    }
+}
+
+pub async fn status_handler(State(station): State<StationService>) -> Json<Value> {
+    let status = match timeout(STATUS_LOCK_TIMEOUT, station.status()).await {
+        Ok(status) => json!(status),
+        Err(_) => json!("busy"),
+    };
+
+    let health = station.health();
+    Json(json!({
+        "ready": health.readiness().is_ok(),
+        "not_ready_reason": health.readiness().err(),
+        "health": health.snapshot(),
+        "station": status,
+    }))
+}
+
+pub async fn metrics_handler(State(station): State<StationService>) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        station.metrics().render(station.name()),
+    )
+}
+
+// Who's listening, without their ips
+pub async fn presence_handler(State(station): State<StationService>) -> Json<PresenceReport> {
+    Json(station.presence())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RequestParams {
+    pub track: String,
+}
+
+pub async fn list_requests_handler(State(station): State<StationService>) -> Json<Value> {
+    Json(json!({ "queued": station.queued_requests().await }))
+}
+
+pub async fn create_request_handler(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    State(station): State<StationService>,
+    Json(params): Json<RequestParams>,
+) -> Result<Json<SongRequest>, Error> {
+    let ip = client_ip(&station, addr, &headers);
+    if station.is_banned(&ip) {
+        return Err(Error::Forbidden);
+    }
+
+    // There's no connection to tell http requesters apart, so they're rate limited by ip, shared
+    // with the websocket when listeners are keyed by ip
+    let requester = ip.to_string();
+    let request = station
+        .request_track(params.track.as_str(), requester.as_str())
+        .await?;
+    Ok(Json(request))
+}
+
+pub async fn pending_requests_handler(
+    TypedHeader(auth): TypedHeader<Authorization<Bearer>>,
+    State(station): State<StationService>,
+) -> Result<Json<Vec<SongRequest>>, Error> {
+    if !station.is_moderator(auth.token()) {
+        return Err(Error::Unauthorized);
+    }
+    Ok(Json(station.pending_requests().await))
+}
+
+pub async fn approve_request_handler(
+    TypedHeader(auth): TypedHeader<Authorization<Bearer>>,
+    Path(request_id): Path<String>,
     State(station): State<StationService>,
-) -> impl IntoResponse {
-    ws.on_upgrade(|socket| handle_client_connection(socket, station))
+) -> Result<Json<SongRequest>, Error> {
+    if !station.is_moderator(auth.token()) {
+        return Err(Error::Unauthorized);
+    }
+    Ok(Json(station.approve_request(request_id.as_str()).await?))
+}
+
+pub async fn reject_request_handler(
+    TypedHeader(auth): TypedHeader<Authorization<Bearer>>,
+    Path(request_id): Path<String>,
+    State(station): State<StationService>,
+) -> Result<Json<SongRequest>, Error> {
+    if !station.is_moderator(auth.token()) {
+        return Err(Error::Unauthorized);
+    }
+    Ok(Json(station.reject_request(request_id.as_str()).await?))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LimitParams {
+    pub limit: Option<usize>,
+}
+
+impl LimitParams {
+    fn limit(&self) -> usize {
+        self.limit
+            .unwrap_or(DEFAULT_HISTORY_LIMIT)
+            .min(MAX_HISTORY_LIMIT)
+    }
+}
+
+// Last plays with their reactions, newest first
+pub async fn history_handler(
+    Query(params): Query<LimitParams>,
+    State(station): State<StationService>,
+) -> Json<Vec<Play>> {
+    Json(station.history(params.limit()))
+}
+
+pub async fn most_loved_handler(
+    Query(params): Query<LimitParams>,
+    State(station): State<StationService>,
+) -> Json<Vec<TrackRanking>> {
+    Json(station.most_loved(params.limit()))
+}
+
+pub async fn chat_history_handler(
+    State(station): State<StationService>,
+) -> Result<Json<Vec<ChatMessage>>, Error> {
+    Ok(Json(station.chat_history()?))
+}
+
+pub async fn delete_chat_message_handler(
+    TypedHeader(auth): TypedHeader<Authorization<Bearer>>,
+    Path(message_id): Path<String>,
+    State(station): State<StationService>,
+) -> Result<Json<ChatMessage>, Error> {
+    if !station.is_moderator(auth.token()) {
+        return Err(Error::Unauthorized);
+    }
+    Ok(Json(station.delete_chat_message(message_id.as_str())?))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MuteParams {
+    pub secs: Option<i64>,
+}
+
+pub async fn mute_chat_author_handler(
+    TypedHeader(auth): TypedHeader<Authorization<Bearer>>,
+    Path(message_id): Path<String>,
+    Query(params): Query<MuteParams>,
+    State(station): State<StationService>,
+) -> Result<Json<Value>, Error> {
+    if !station.is_moderator(auth.token()) {
+        return Err(Error::Unauthorized);
+    }
+    let until = station.mute_chat_author(message_id.as_str(), params.secs)?;
+    Ok(Json(json!({ "muted_until": until })))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StatsParams {
+    pub from: Option<String>,
+    pub to: Option<String>,
+    pub resolution: Option<Resolution>,
+    pub limit: Option<usize>,
+}
+
+impl StatsParams {
+    fn query(&self) -> Result<StatsQuery, Error> {
+        StatsQuery::new(
+            self.from.as_deref(),
+            self.to.as_deref(),
+            self.resolution,
+            self.limit,
+            Utc::now(),
+        )
+    }
+}
+
+pub async fn stats_handler(
+    Query(params): Query<StatsParams>,
+    State(station): State<StationService>,
+) -> Result<Json<StatsReport>, Error> {
+    Ok(Json(station.stats().report(&params.query()?)))
+}
+
+// Listeners, sessions, plays and loads over time
+pub async fn stats_csv_handler(
+    Query(params): Query<StatsParams>,
+    State(station): State<StationService>,
+) -> Result<impl IntoResponse, Error> {
+    Ok(csv_response(
+        "stats.csv",
+        station.stats().series_csv(&params.query()?),
+    ))
+}
+
+// Plays and skips of each track
+pub async fn tracks_stats_csv_handler(
+    Query(params): Query<StatsParams>,
+    State(station): State<StationService>,
+) -> Result<impl IntoResponse, Error> {
+    Ok(csv_response(
+        "tracks.csv",
+        station.stats().tracks_csv(&params.query()?),
+    ))
+}
+
+fn csv_response(filename: &str, csv: String) -> impl IntoResponse {
+    (
+        [
+            (
+                header::CONTENT_TYPE,
+                String::from("text/csv; charset=utf-8"),
+            ),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}\"", filename),
+            ),
+        ],
+        csv,
+    )
 }
@@ -0,0 +1,281 @@
+use super::radio::StationService;
+use crate::error::Error;
+use axum::{
+    body::StreamBody,
+    extract::{Path, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use std::io::SeekFrom;
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt},
+    time::{sleep, Duration},
+};
+use tokio_util::io::ReaderStream;
+
+// Inclusive byte range of a resource
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RangeRequest {
+    Full,
+    Partial(ByteRange),
+    Unsatisfiable,
+}
+
+// Parses a `Range` header for a resource of `size` bytes. Only single byte ranges are supported,
+// other units and multiple ranges are ignored and the whole resource is served, while malformed
+// byte ranges can't be satisfied.
+pub fn parse_range(header: Option<&str>, size: u64) -> RangeRequest {
+    let spec = match header.and_then(|h| h.trim().strip_prefix("bytes=")) {
+        Some(spec) if !spec.contains(',') => spec.trim(),
+        _ => return RangeRequest::Full,
+    };
+    let (start, end) = match spec.split_once('-') {
+        Some(bounds) => bounds,
+        None => return RangeRequest::Unsatisfiable,
+    };
+
+    let range = match (start.parse::<u64>(), end.parse::<u64>()) {
+        // `bytes=start-end`
+        (Ok(start), Ok(end)) if start <= end => ByteRange {
+            start,
+            end: end.min(size.saturating_sub(1)),
+        },
+        // `bytes=start-`
+        (Ok(start), Err(_)) if end.is_empty() => ByteRange {
+            start,
+            end: size.saturating_sub(1),
+        },
+        // `bytes=-suffix`
+        (Err(_), Ok(suffix)) if start.is_empty() => {
+            if suffix == 0 {
+                return RangeRequest::Unsatisfiable;
+            }
+            ByteRange {
+                start: size.saturating_sub(suffix),
+                end: size.saturating_sub(1),
+            }
+        }
+        _ => return RangeRequest::Unsatisfiable,
+    };
+
+    if size == 0 || range.start >= size {
+        return RangeRequest::Unsatisfiable;
+    }
+    RangeRequest::Partial(range)
+}
+
+// Serves a file, or the requested range of it
+pub async fn file_response(
+    mut file: File,
+    size: u64,
+    content_type: &str,
+    range: RangeRequest,
+) -> Result<Response, Error> {
+    let (status, start, len) = match range {
+        RangeRequest::Full => (StatusCode::OK, 0, size),
+        RangeRequest::Partial(range) => (
+            StatusCode::PARTIAL_CONTENT,
+            range.start,
+            range.end - range.start + 1,
+        ),
+        RangeRequest::Unsatisfiable => {
+            return Ok((
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                [(header::CONTENT_RANGE, format!("bytes */{}", size))],
+            )
+                .into_response())
+        }
+    };
+
+    file.seek(SeekFrom::Start(start)).await?;
+    let body = StreamBody::new(ReaderStream::new(file.take(len)));
+    let mut response = (
+        status,
+        [
+            (header::CONTENT_TYPE, content_type.to_string()),
+            (header::CONTENT_LENGTH, len.to_string()),
+            (header::ACCEPT_RANGES, String::from("bytes")),
+        ],
+        body,
+    )
+        .into_response();
+
+    if let RangeRequest::Partial(range) = range {
+        let content_range = format!("bytes {}-{}/{}", range.start, range.end, size);
+        if let Ok(value) = content_range.parse() {
+            response.headers_mut().insert(header::CONTENT_RANGE, value);
+        }
+    }
+    Ok(response)
+}
+
+pub async fn media_handler(
+    Path(track_id): Path<u64>,
+    headers: HeaderMap,
+    State(station): State<StationService>,
+) -> Result<Response, Error> {
+    let track = station
+        .library_of(track_id)
+        .and_then(|library| library.get(track_id))
+        .ok_or(Error::MediaNotFound(track_id))?;
+
+    let file = File::open(&track.path).await?;
+    let size = file.metadata().await?.len();
+    let content_type = mime_guess::from_path(&track.path).first_or_octet_stream();
+    let range = parse_range(
+        headers.get(header::RANGE).and_then(|v| v.to_str().ok()),
+        size,
+    );
+    file_response(file, size, content_type.as_ref(), range).await
+}
+
+pub async fn artwork_handler(
+    Path(track_id): Path<u64>,
+    State(station): State<StationService>,
+) -> Result<Response, Error> {
+    let library = station
+        .library_of(track_id)
+        .ok_or(Error::MediaNotFound(track_id))?;
+    match library.artwork(track_id).await? {
+        Some(artwork) => {
+            Ok(([(header::CONTENT_TYPE, artwork.media_type)], artwork.data).into_response())
+        }
+        None => Err(Error::MediaNotFound(track_id)),
+    }
+}
+
+// Rescans the local library and the emergency playlist periodically, and updates the playlist
+// when files are added or removed.
+pub async fn watch_library(service: StationService, rescan_every: Duration) {
+    let library = service.library();
+    let fallback_library = service.fallback_library();
+    if !library.is_enabled() && !fallback_library.is_enabled() {
+        return;
+    }
+
+    loop {
+        sleep(rescan_every).await;
+        match library.scan().await {
+            Ok(true) => service.sync_library().await,
+            Ok(false) => {}
+            Err(err) => tracing::warn!("unable to scan the local library: {}", err),
+        }
+        if let Err(err) = fallback_library.scan().await {
+            tracing::warn!("unable to scan the emergency playlist: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn partial(start: u64, end: u64) -> RangeRequest {
+        RangeRequest::Partial(ByteRange { start, end })
+    }
+
+    #[test]
+    fn parses_open_ranges() {
+        assert_eq!(parse_range(Some("bytes=0-"), 1000), partial(0, 999));
+        assert_eq!(parse_range(Some("bytes=400-"), 1000), partial(400, 999));
+    }
+
+    #[test]
+    fn parses_suffix_ranges() {
+        assert_eq!(parse_range(Some("bytes=-500"), 1000), partial(500, 999));
+        // A suffix longer than the resource is the whole of it
+        assert_eq!(parse_range(Some("bytes=-5000"), 1000), partial(0, 999));
+        assert_eq!(
+            parse_range(Some("bytes=-0"), 1000),
+            RangeRequest::Unsatisfiable
+        );
+    }
+
+    #[test]
+    fn clamps_the_end_to_the_resource() {
+        assert_eq!(parse_range(Some("bytes=100-199"), 1000), partial(100, 199));
+        assert_eq!(parse_range(Some("bytes=100-999"), 1000), partial(100, 999));
+        assert_eq!(parse_range(Some("bytes=100-5000"), 1000), partial(100, 999));
+    }
+
+    #[test]
+    fn refuses_ranges_past_the_end() {
+        assert_eq!(
+            parse_range(Some("bytes=1000-"), 1000),
+            RangeRequest::Unsatisfiable
+        );
+        assert_eq!(
+            parse_range(Some("bytes=2000-3000"), 1000),
+            RangeRequest::Unsatisfiable
+        );
+        assert_eq!(
+            parse_range(Some("bytes=0-"), 0),
+            RangeRequest::Unsatisfiable
+        );
+    }
+
+    #[test]
+    fn refuses_malformed_ranges() {
+        for header in [
+            "bytes=",
+            "bytes=-",
+            "bytes=abc",
+            "bytes=a-b",
+            "bytes=10",
+            "bytes=200-100",
+            "bytes=-10-20",
+        ] {
+            assert_eq!(
+                parse_range(Some(header), 1000),
+                RangeRequest::Unsatisfiable,
+                "{}",
+                header
+            );
+        }
+    }
+
+    #[test]
+    fn ignores_other_ranges() {
+        assert_eq!(parse_range(None, 1000), RangeRequest::Full);
+        assert_eq!(parse_range(Some("items=0-10"), 1000), RangeRequest::Full);
+        assert_eq!(
+            parse_range(Some("bytes=0-10, 20-30"), 1000),
+            RangeRequest::Full
+        );
+    }
+
+    async fn serve(name: &str, range: RangeRequest) -> Response {
+        let path = std::env::temp_dir().join(format!("robo_radio_{}_{}", name, std::process::id()));
+        tokio::fs::write(&path, b"0123456789").await.unwrap();
+        let file = File::open(&path).await.unwrap();
+        let response = file_response(file, 10, "audio/mpeg", range).await.unwrap();
+        tokio::fs::remove_file(&path).await.unwrap();
+        response
+    }
+
+    fn header_of(response: &Response, name: header::HeaderName) -> &str {
+        response.headers()[name].to_str().unwrap()
+    }
+
+    #[tokio::test]
+    async fn serves_the_requested_range() {
+        let response = serve("partial", parse_range(Some("bytes=-4"), 10)).await;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(header_of(&response, header::CONTENT_RANGE), "bytes 6-9/10");
+        assert_eq!(header_of(&response, header::CONTENT_LENGTH), "4");
+    }
+
+    #[tokio::test]
+    async fn answers_416_to_unsatisfiable_ranges() {
+        let response = serve("unsatisfiable", parse_range(Some("bytes=a-b"), 10)).await;
+        assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(header_of(&response, header::CONTENT_RANGE), "bytes */10");
+    }
+}
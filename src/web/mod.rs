@@ -1,3 +1,14 @@
+pub mod admin;
+pub mod commands;
+pub mod failover;
 pub mod handlers;
+pub mod media;
+pub mod playout;
+pub mod prefetch;
+pub mod proxy;
 pub mod radio;
+pub mod send_queue;
+pub mod sources;
+pub mod stats;
+pub mod supervisor;
 pub mod ws;
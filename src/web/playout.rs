@@ -0,0 +1,298 @@
+use super::radio::StationService;
+use crate::{error::Error, health::PlayoutTask};
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::{
+    sync::{
+        mpsc::{channel, error::TrySendError, Receiver, Sender},
+        oneshot, Mutex,
+    },
+    time::{timeout_at, Duration, Instant},
+};
+
+const COMMANDS_BUFFER: usize = 32;
+
+// How late a track can go on air before giving up on its planned start and rescheduling from now
+const MAX_LATENESS_MS: i64 = 10_000;
+
+// How long the playout waits before trying again when no track could be loaded
+const LOAD_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum PlayoutCommand {
+    Skip,
+    // Dead air, or keep looping the current track when `hold` is set
+    Pause {
+        #[serde(default)]
+        hold: bool,
+    },
+    Resume,
+    ReloadPlaylist,
+    InsertNext {
+        track_id: u64,
+    },
+    Stop,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PlayoutState {
+    Playing,
+    Holding,
+    Paused,
+    Stopped,
+}
+
+impl PlayoutState {
+    // Whether listeners hear something, a held track is still on air
+    pub fn is_on_air(&self) -> bool {
+        matches!(self, PlayoutState::Playing | PlayoutState::Holding)
+    }
+}
+
+#[derive(Debug)]
+pub struct PlayoutRequest {
+    command: PlayoutCommand,
+    reply: Option<oneshot::Sender<Result<PlayoutState, Error>>>,
+}
+
+// The commands queue outlives the playout task, so that a restarted task takes over the pending
+// commands and the station's `PlayoutControl` keeps working.
+pub type PlayoutCommands = Arc<Mutex<Receiver<PlayoutRequest>>>;
+
+// Handle to drive the playout task from the station, the web handlers, etc...
+#[derive(Debug, Clone)]
+pub struct PlayoutControl {
+    sender: Sender<PlayoutRequest>,
+}
+
+impl PlayoutControl {
+    pub fn channel() -> (Self, PlayoutCommands) {
+        let (sender, receiver) = channel(COMMANDS_BUFFER);
+        (Self { sender }, Arc::new(Mutex::new(receiver)))
+    }
+
+    // Sends a command and waits for the playout task to acknowledge it.
+    pub async fn send(&self, command: PlayoutCommand) -> Result<PlayoutState, Error> {
+        let (reply, ack) = oneshot::channel();
+        self.sender
+            .send(PlayoutRequest {
+                command,
+                reply: Some(reply),
+            })
+            .await
+            .map_err(|_| Error::PlayoutUnavailable)?;
+        ack.await.map_err(|_| Error::PlayoutUnavailable)?
+    }
+
+    // Sends a command without waiting, so that it's safe to call while holding the station lock.
+    pub fn notify(&self, command: PlayoutCommand) -> Result<(), Error> {
+        match self.sender.try_send(PlayoutRequest {
+            command,
+            reply: None,
+        }) {
+            Ok(_) => Ok(()),
+            Err(TrySendError::Full(_)) | Err(TrySendError::Closed(_)) => {
+                Err(Error::PlayoutUnavailable)
+            }
+        }
+    }
+}
+
+struct Playout {
+    service: StationService,
+    state: PlayoutState,
+    deadline: Instant,
+    // Planned end of the current track, the next one starts from here
+    ends_at: DateTime<Utc>,
+}
+
+impl Playout {
+    // Airs a newly loaded track from its planned start, or from now when it's too late for it
+    async fn air_loaded(&mut self) {
+        let track = self.service.current_track();
+        let lateness = Utc::now().signed_duration_since(track.started_at);
+        if lateness.num_milliseconds() > MAX_LATENESS_MS {
+            tracing::warn!(
+                "track went on air {}ms late, rescheduling from now",
+                lateness.num_milliseconds()
+            );
+            self.service
+                .metrics()
+                .playout_drift_ms
+                .add(lateness.num_milliseconds() as u64);
+            self.service.rewind_current_track(Utc::now()).await;
+        }
+        let lateness = Utc::now()
+            .signed_duration_since(self.service.current_track().started_at)
+            .num_milliseconds();
+        self.service.metrics().playout_lateness_ms.set(lateness);
+        if lateness > 0 {
+            self.service.metrics().playout_late_ms.add(lateness as u64);
+        }
+        self.air();
+    }
+
+    // Broadcasts the current track and schedules its end from the planned start, so that the time
+    // spent loading tracks doesn't add up over the day.
+    fn air(&mut self) {
+        let track = self.service.current_track();
+        tracing::info!(
+            "starting new track at {:?}: {:?}",
+            track.started_at,
+            track.title
+        );
+
+        self.service.air_current_track();
+        self.ends_at = track.ends_at();
+        let remaining = self
+            .ends_at
+            .signed_duration_since(Utc::now())
+            .to_std()
+            .unwrap_or_default();
+        self.deadline = Instant::now() + remaining;
+        self.service.health().on_air(self.ends_at);
+    }
+
+    // Picks up the current track where it is, or the next one when it ended while the playout was
+    // down
+    async fn start(&mut self) {
+        let ends_at = self.service.current_track().ends_at();
+        match self.state {
+            PlayoutState::Paused => {}
+            _ if ends_at <= Utc::now() => {
+                self.ends_at = ends_at;
+                self.on_track_end().await;
+            }
+            _ => self.air(),
+        }
+    }
+
+    // Airs the next track, or tries again in a moment when it can't be loaded: the previous track
+    // stays over, off air, rather than being aired again
+    async fn advance(&mut self, starts_at: DateTime<Utc>) {
+        match self.service.next_track(starts_at).await {
+            Ok(()) => self.air_loaded().await,
+            Err(err) => {
+                tracing::error!(
+                    "unable to load the next track, trying again in {}s: {}",
+                    LOAD_RETRY_DELAY.as_secs(),
+                    err
+                );
+                self.service.health().record_error(&err);
+                self.ends_at = starts_at;
+                self.deadline = Instant::now() + LOAD_RETRY_DELAY;
+            }
+        }
+    }
+
+    async fn on_track_end(&mut self) {
+        match self.state {
+            PlayoutState::Holding => {
+                self.service.rewind_current_track(self.ends_at).await;
+                self.air();
+            }
+            _ => self.advance(self.ends_at).await,
+        }
+    }
+
+    async fn handle(&mut self, command: &PlayoutCommand) -> Result<PlayoutState, Error> {
+        match (command, self.state) {
+            (PlayoutCommand::Skip, PlayoutState::Playing | PlayoutState::Holding) => {
+                self.state = PlayoutState::Playing;
+                self.advance(Utc::now()).await;
+            }
+            (PlayoutCommand::Pause { hold: true }, PlayoutState::Playing) => {
+                self.state = PlayoutState::Holding;
+            }
+            (
+                PlayoutCommand::Pause { hold: false },
+                PlayoutState::Playing | PlayoutState::Holding,
+            ) => {
+                self.state = PlayoutState::Paused;
+            }
+            (PlayoutCommand::Resume, PlayoutState::Holding) => {
+                self.state = PlayoutState::Playing;
+            }
+            (PlayoutCommand::Resume, PlayoutState::Paused) => {
+                self.state = PlayoutState::Playing;
+                self.advance(Utc::now()).await;
+            }
+            (PlayoutCommand::ReloadPlaylist, _) => {
+                self.service.reload_playlist().await?;
+            }
+            (PlayoutCommand::InsertNext { track_id }, _) => {
+                self.service.insert_next(*track_id).await;
+            }
+            (PlayoutCommand::Stop, _) => {
+                self.state = PlayoutState::Stopped;
+            }
+            (_, state) => {
+                return Err(Error::PlayoutInvalidCommand(
+                    format!("{:?}", state).to_lowercase(),
+                ))
+            }
+        }
+
+        Ok(self.state)
+    }
+}
+
+// Runs the playout until it's stopped, resuming from the station's playout state when it's
+// restarted after a crash
+pub async fn go_live(service: StationService, commands: PlayoutCommands) {
+    let mut commands = commands.lock().await;
+    let state = match service.playout_state() {
+        PlayoutState::Stopped => PlayoutState::Playing,
+        state => state,
+    };
+    let mut playout = Playout {
+        service,
+        state,
+        deadline: Instant::now(),
+        ends_at: Utc::now(),
+    };
+    playout.service.health().playout_task(PlayoutTask::Running);
+    playout.start().await;
+
+    while playout.state != PlayoutState::Stopped {
+        let request = match playout.state {
+            PlayoutState::Paused => commands.recv().await,
+            _ => match timeout_at(playout.deadline, commands.recv()).await {
+                Ok(request) => request,
+                Err(_) => {
+                    playout.on_track_end().await;
+                    continue;
+                }
+            },
+        };
+
+        let request = match request {
+            Some(request) => request,
+            None => {
+                tracing::warn!("playout commands channel closed, going off air");
+                break;
+            }
+        };
+
+        tracing::info!("playout received command {:?}", request.command);
+        let result = playout.handle(&request.command).await;
+        match &result {
+            Ok(state) => {
+                playout.service.health().playout_state(*state);
+                playout.service.notify_playout(&request.command, *state);
+            }
+            Err(err) => tracing::warn!("playout command {:?} failed: {}", request.command, err),
+        }
+
+        if let Some(reply) = request.reply {
+            let _ = reply.send(result);
+        }
+    }
+
+    playout.service.health().playout_task(PlayoutTask::Stopped);
+    tracing::info!("playout stopped");
+}
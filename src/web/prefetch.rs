@@ -0,0 +1,13 @@
+use super::radio::StationService;
+use tokio::time::{timeout, Duration};
+
+// How often the prefetched tracks are checked even if nothing changed, to keep them fresh
+const REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+
+// Resolves the upcoming tracks in background, so that the playout can switch tracks on time.
+pub async fn prefetch_upcoming(service: StationService) {
+    loop {
+        service.prefetch().await;
+        let _ = timeout(REFRESH_INTERVAL, service.prefetch_wanted()).await;
+    }
+}
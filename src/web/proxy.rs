@@ -0,0 +1,100 @@
+use super::{
+    media::{file_response, parse_range},
+    radio::StationService,
+};
+use crate::{error::Error, library, soundcloud::Track};
+use axum::{
+    body::StreamBody,
+    extract::{Path, State},
+    http::{header, HeaderMap},
+    response::{IntoResponse, Redirect, Response},
+};
+use tokio::fs::File;
+
+// Upstream headers passed on to the client
+const FORWARDED_HEADERS: [header::HeaderName; 4] = [
+    header::CONTENT_TYPE,
+    header::CONTENT_LENGTH,
+    header::CONTENT_RANGE,
+    header::ACCEPT_RANGES,
+];
+
+// Streams a track's audio through the station, from the cache when possible, so that listeners
+// never talk to SoundCloud directly.
+pub async fn proxy_track_handler(
+    Path(track_id): Path<u64>,
+    headers: HeaderMap,
+    State(station): State<StationService>,
+) -> Result<Response, Error> {
+    if library::is_local(track_id) {
+        return Ok(Redirect::temporary(format!("/media/{}", track_id).as_str()).into_response());
+    }
+    let range = headers.get(header::RANGE).and_then(|v| v.to_str().ok());
+
+    if let Some(audio) = station.audio_cache().get(track_id) {
+        if let Ok(file) = File::open(&audio.path).await {
+            let size = file.metadata().await?.len();
+            let range = parse_range(range, size);
+            return file_response(file, size, audio.content_type.as_str(), range).await;
+        }
+    }
+
+    cache_in_background(station.clone(), track_id);
+    let (_, upstream) = fetch_upstream(&station, track_id, range).await?;
+
+    let status = upstream.status();
+    let upstream_headers = upstream.headers().clone();
+
+    let mut response = StreamBody::new(upstream.bytes_stream()).into_response();
+    *response.status_mut() = status;
+    for name in FORWARDED_HEADERS {
+        if let Some(value) = upstream_headers.get(&name) {
+            response.headers_mut().insert(name, value.clone());
+        }
+    }
+    Ok(response)
+}
+
+// Requests the track from SoundCloud, resolving its stream url again if it expired
+async fn fetch_upstream(
+    station: &StationService,
+    track_id: u64,
+    range: Option<&str>,
+) -> Result<(Track, reqwest::Response), Error> {
+    let api = station.api();
+    let track = station.stream_track(track_id, false).await?;
+    match api.get_stream(stream_url(&track)?, range).await {
+        Err(Error::SoundcloudAuthError(403)) => {
+            tracing::info!(
+                "stream url of track {} expired, resolving it again",
+                track_id
+            );
+            let track = station.stream_track(track_id, true).await?;
+            let response = api.get_stream(stream_url(&track)?, range).await?;
+            Ok((track, response))
+        }
+        result => Ok((track, result?)),
+    }
+}
+
+fn stream_url(track: &Track) -> Result<&str, Error> {
+    track.url.as_deref().ok_or(Error::MediaNotFound(track.id))
+}
+
+fn cache_in_background(station: StationService, track_id: u64) {
+    let cache = station.audio_cache();
+    if !cache.start_download(track_id) {
+        return;
+    }
+
+    tokio::spawn(async move {
+        let result = match fetch_upstream(&station, track_id, None).await {
+            Ok((track, upstream)) => cache.store(&track, upstream).await,
+            Err(err) => Err(err),
+        };
+        if let Err(err) = result {
+            tracing::warn!("unable to cache track with id {}: {}", track_id, err);
+        }
+        cache.finish_download(track_id);
+    });
+}
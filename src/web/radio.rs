@@ -1,107 +1,1137 @@
-use super::ws::{Client, Clients, WebSocketHandler};
+use super::{
+    commands::Command,
+    playout::{PlayoutCommand, PlayoutControl, PlayoutState},
+    ws::{Client, Clients, WebSocketHandler},
+};
 use crate::{
+    audio_cache::AudioCache,
+    chat::{Chat, ChatMessage, ChatTrack},
+    config::{ClientQueueConfig, Config, ListenersConfig, ReactionsConfig, SkipConfig},
     error::Error,
-    media_player::{CurrentTrack, MediaPlayer},
-    web::ws::broadcast_message,
+    health::{Health, PlayoutTask},
+    history::{Play, PlayHistory, Reaction, ReactionCounts, TrackRanking},
+    library::LocalLibrary,
+    media_player::{CurrentTrack, MediaPlayer, Source, UpcomingTrack},
+    metrics::Metrics,
+    presence::{Listener, Presence, PresenceTotals, Profile},
+    request_queue::{RequestStatus, RequestedTrack, SongRequest},
+    rotation::RotationDiff,
+    soundcloud::{
+        breaker::{BreakerState, CircuitBreaker},
+        ApiClient, Resource, Track,
+    },
+    stats::Stats,
 };
 use anyhow::Result;
 use async_trait::async_trait;
 use axum::extract::ws::Message;
-use std::{collections::HashMap, sync::Arc};
-use tokio::{
-    sync::Mutex,
-    time::{sleep, Duration},
+use chrono::{DateTime, Utc};
+use futures::future::{select, Either};
+use serde::Serialize;
+use serde_json::Value;
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, RwLock,
+    },
+};
+use tokio::sync::{
+    broadcast::{self, error::RecvError},
+    Notify,
 };
 
-// Shared station
+// Max events buffered for each listener before it starts lagging behind
+const EVENTS_BUFFER: usize = 256;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ListenerInfo {
+    pub id: String,
+    pub ip: Option<IpAddr>,
+    pub connected_at: DateTime<Utc>,
+    pub connected_secs: i64,
+    pub nickname: Option<String>,
+    pub listening_secs: i64,
+}
+
+// The public "who's listening" feed
+#[derive(Debug, Clone, Serialize)]
+pub struct PresenceReport {
+    pub listeners: Vec<Listener>,
+    pub totals: PresenceTotals,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StationStatus {
+    pub name: String,
+    pub playlist_id: Option<String>,
+    pub sources: Vec<String>,
+    pub playlist_size: usize,
+    pub remaining_tracks: usize,
+    pub queue_depth: usize,
+    pub pending_requests: usize,
+    pub listeners: usize,
+    pub presence: PresenceTotals,
+    pub client_id_age_secs: i64,
+    pub playout_state: PlayoutState,
+    pub source: Source,
+    pub soundcloud: BreakerState,
+    pub current_track: Option<CurrentTrack>,
+}
+
+// Event broadcast to all the listeners. Events with a `key` supersede the older ones with the
+// same key still waiting to be sent to a slow client.
 #[derive(Debug, Clone)]
+pub struct StationEvent {
+    pub key: Option<&'static str>,
+    pub message: Message,
+}
+
+#[derive(Debug, Default)]
+struct SkipVotes {
+    votes: HashSet<String>,
+    requested: bool,
+}
+
+// Shared station. Listeners only touch the events channel and short-lived locks, while the media
+// player sits behind its own lock, used by the playout task and admins. That lock is never held
+// across SoundCloud calls.
+#[derive(Debug)]
 pub struct Station {
-    media_player: MediaPlayer,
-    listeners: Clients,
+    name: String,
+    moderator_token: Option<String>,
+    admin_tokens: HashMap<String, String>,
+    skip_config: SkipConfig,
+    reactions_config: ReactionsConfig,
+    client_queue_config: ClientQueueConfig,
+    listeners_config: ListenersConfig,
+    media_player: tokio::sync::Mutex<MediaPlayer>,
+    library: LocalLibrary,
+    fallback_library: LocalLibrary,
+    audio_cache: Arc<AudioCache>,
+    source: RwLock<Source>,
+    current_track: RwLock<Option<CurrentTrack>>,
+    // Last `next` track announced to the listeners
+    upcoming_track: RwLock<Option<CurrentTrack>>,
+    prefetch_wanted: Notify,
+    events: broadcast::Sender<StationEvent>,
+    listeners: RwLock<Clients>,
+    listeners_count: AtomicUsize,
+    presence: Mutex<Presence>,
+    chat: Mutex<Chat>,
+    history: Mutex<PlayHistory>,
+    banned_ips: RwLock<HashSet<IpAddr>>,
+    skip_votes: Mutex<SkipVotes>,
+    playout: PlayoutControl,
+    playout_state: RwLock<PlayoutState>,
+    // Shares its circuit breaker with the media player's
+    api: ApiClient,
+    health: Arc<Health>,
+    stats: Arc<Stats>,
+    metrics: Arc<Metrics>,
 }
 
 impl Station {
-    pub async fn new(playlist_id: &str) -> Result<Station, Error> {
-        let mut media_player = MediaPlayer::new().await?;
+    pub async fn new(config: &Config, playout: PlayoutControl) -> Result<Station, Error> {
+        let metrics = Arc::new(Metrics::default());
+        let audio_cache = Arc::new(AudioCache::new(&config.audio_cache, metrics.clone()));
+        let health = Arc::new(Health::new(config.readiness_grace_secs));
+        let stats = Arc::new(Stats::new(config.stats.clone()));
+        let breaker = CircuitBreaker::new(config.breaker.clone(), metrics.clone());
+        let api = ApiClient::new(Arc::new(breaker), metrics.clone());
+
+        let media_player = MediaPlayer::new(
+            config,
+            audio_cache.clone(),
+            api.clone(),
+            health.clone(),
+            stats.clone(),
+            metrics.clone(),
+        )
+        .await?;
+        let library = media_player.library().clone();
+        let fallback_library = media_player.fallback_library().clone();
         let listeners: Clients = HashMap::new();
 
-        media_player.load_playlist(playlist_id.as_ref()).await?;
-        media_player.load_next_track().await?;
+        let media_player = tokio::sync::Mutex::new(media_player);
+        MediaPlayer::load_next_track(&media_player, Utc::now()).await?;
+        let (source, current_track) = {
+            let media_player = media_player.lock().await;
+            (media_player.source(), media_player.current_track.clone())
+        };
+
+        let (events, _) = broadcast::channel(EVENTS_BUFFER);
 
         Ok(Station {
-            listeners,
+            name: config.station_name.clone(),
+            moderator_token: config.moderator_token.clone(),
+            admin_tokens: config.admin_tokens.clone(),
+            skip_config: config.skip.clone(),
+            reactions_config: config.reactions.clone(),
+            client_queue_config: config.client_queue.clone(),
+            listeners_config: config.listeners.clone(),
+            source: RwLock::new(source),
+            current_track: RwLock::new(current_track),
             media_player,
+            library,
+            fallback_library,
+            audio_cache,
+            upcoming_track: RwLock::new(None),
+            prefetch_wanted: Notify::new(),
+            events,
+            listeners: RwLock::new(listeners),
+            listeners_count: AtomicUsize::new(0),
+            presence: Mutex::new(Presence::new(true, Utc::now(), metrics.clone())),
+            chat: Mutex::new(Chat::new(config.chat.clone(), metrics.clone())),
+            history: Mutex::new(PlayHistory::new(&config.history, stats.clone())),
+            banned_ips: RwLock::new(HashSet::new()),
+            skip_votes: Mutex::new(SkipVotes::default()),
+            playout,
+            playout_state: RwLock::new(PlayoutState::Playing),
+            api,
+            health,
+            stats,
+            metrics,
         })
     }
 
     // Utils
-    pub async fn current_track(&mut self) -> CurrentTrack {
-        self.media_player.current_track.as_ref().unwrap().clone()
+    pub fn name(&self) -> &str {
+        self.name.as_str()
     }
 
-    pub async fn next_track(&mut self) -> Result<(), Error> {
-        self.media_player.load_next_track().await
+    pub fn client_queue_config(&self) -> ClientQueueConfig {
+        self.client_queue_config.clone()
     }
 
-    async fn notify_listeners_count(&mut self) {
-        broadcast_message(
-            &Message::Text(
-                serde_json::json!({"event": "listeners", "data": self.listeners.keys().count()})
-                    .to_string(),
-            ),
-            &self.listeners,
-        )
-        .await;
+    pub fn client_ip(&self, peer: IpAddr, forwarded_for: Option<&str>) -> IpAddr {
+        self.listeners_config.client_ip(peer, forwarded_for)
     }
 
-    async fn build_current_track_msg(&self) -> Message {
-        Message::Text(
-            serde_json::json!({"event": "track", "data": self.clone().current_track().await})
-                .to_string(),
-        )
+    // Tells listeners apart for the rate limits, the reactions, the chat mutes and the daily
+    // uniques: by connection, or by ip when it's opted in
+    fn listener_key(&self, client: &Client) -> String {
+        match client.ip {
+            Some(ip) if self.listeners_config.key_by_ip => ip.to_string(),
+            _ => client.id.clone(),
+        }
+    }
+
+    pub fn listeners_count(&self) -> usize {
+        self.listeners_count.load(Ordering::Relaxed)
+    }
+
+    pub fn api(&self) -> ApiClient {
+        self.api.clone()
+    }
+
+    pub fn health(&self) -> &Health {
+        &self.health
+    }
+
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+
+    pub async fn status(&self) -> StationStatus {
+        let media_player = self.media_player.lock().await;
+        let client_id_age = Utc::now().signed_duration_since(media_player.client_id_timestamp());
+        StationStatus {
+            name: self.name.clone(),
+            playlist_id: media_player.playlist_id().map(str::to_string),
+            sources: media_player
+                .sources()
+                .iter()
+                .map(ToString::to_string)
+                .collect(),
+            playlist_size: media_player.playlist_size(),
+            remaining_tracks: media_player.remaining_tracks(),
+            queue_depth: media_player.queue_depth(),
+            pending_requests: media_player.pending_requests().len(),
+            listeners: self.listeners_count(),
+            presence: self.presence.lock().unwrap().totals(Utc::now()),
+            client_id_age_secs: client_id_age.num_seconds(),
+            playout_state: self.playout_state(),
+            source: self.source(),
+            soundcloud: self.api.breaker_state(),
+            current_track: self.current_track.read().unwrap().clone(),
+        }
+    }
+
+    pub fn current_track(&self) -> CurrentTrack {
+        self.current_track.read().unwrap().as_ref().unwrap().clone()
+    }
+
+    pub async fn next_track(&self, starts_at: DateTime<Utc>) -> Result<(), Error> {
+        let weights = self
+            .history
+            .lock()
+            .unwrap()
+            .track_weights(&self.reactions_config);
+        self.media_player.lock().await.set_track_weights(weights);
+        let result = MediaPlayer::load_next_track(&self.media_player, starts_at).await;
+        let media_player = self.media_player.lock().await;
+        *self.current_track.write().unwrap() = media_player.current_track.clone();
+        self.set_source(media_player.source());
+        *self.skip_votes.lock().unwrap() = SkipVotes::default();
+        self.want_prefetch();
+        result
+    }
+
+    // Prefetching
+    pub async fn prefetch(&self) {
+        let prefetch = self.media_player.lock().await.prefetch();
+        if !prefetch.is_empty() {
+            let tracks = prefetch.resolve().await;
+            self.cache_tracks(tracks.clone());
+            self.media_player.lock().await.store_prefetched(tracks);
+        }
+        self.notify_next_track().await;
+    }
+
+    // Downloads the upcoming tracks to the audio cache in background, one at a time
+    fn cache_tracks(&self, tracks: Vec<Track>) {
+        let cache = self.audio_cache.clone();
+        if !cache.is_enabled() {
+            return;
+        }
+        let api = self.api.clone();
+        tokio::spawn(async move {
+            for track in tracks {
+                if let Err(err) = cache.download(&api, &track).await {
+                    tracing::warn!("unable to cache track with id {}: {}", track.id, err);
+                }
+            }
+        });
+    }
+
+    // Resolves when the upcoming tracks changed and should be prefetched again
+    pub async fn prefetch_wanted(&self) {
+        self.prefetch_wanted.notified().await
+    }
+
+    fn want_prefetch(&self) {
+        self.prefetch_wanted.notify_one();
+    }
+
+    // Lets the listeners preload the next track, once it's been resolved
+    async fn notify_next_track(&self) {
+        let next = self.media_player.lock().await.next_track();
+        {
+            let mut upcoming = self.upcoming_track.write().unwrap();
+            let changed = match (upcoming.as_ref(), next.as_ref()) {
+                (Some(old), Some(new)) => {
+                    old.id != new.id || old.url != new.url || old.started_at != new.started_at
+                }
+                (None, None) => false,
+                _ => true,
+            };
+            if !changed {
+                return;
+            }
+            *upcoming = next.clone();
+        }
+
+        if let Some(next) = next {
+            self.broadcast(
+                Some("next"),
+                serde_json::json!({"event": "next", "data": next}),
+            );
+        }
+    }
+
+    // Failover
+    pub fn source(&self) -> Source {
+        *self.source.read().unwrap()
+    }
+
+    fn set_source(&self, source: Source) {
+        let changed = {
+            let mut current = self.source.write().unwrap();
+            std::mem::replace(&mut *current, source) != source
+        };
+        if changed {
+            tracing::info!("station switched to the {:?} source", source);
+            self.broadcast(Some("source"), self.build_source_msg());
+        }
+    }
+
+    // Switches back to the primary source when it's up again. The current track keeps playing,
+    // the next one comes from the primary source.
+    pub async fn probe_primary(&self) {
+        let probe = {
+            let media_player = self.media_player.lock().await;
+            if media_player.source() == Source::Primary {
+                return;
+            }
+            media_player.probe()
+        };
+
+        let result = match probe.run().await {
+            Ok((client_id, fetched)) => {
+                let mut media_player = self.media_player.lock().await;
+                let result = media_player.restore(client_id, fetched);
+                self.set_source(media_player.source());
+                result
+            }
+            Err(err) => Err(err),
+        };
+        match result {
+            Ok(_) => self.want_prefetch(),
+            Err(err) => tracing::info!("primary source is still down: {}", err),
+        }
+    }
+
+    // Fetches the sources again and merges their changes into the rotation
+    pub async fn refresh_sources(&self) {
+        let refresh = {
+            let media_player = self.media_player.lock().await;
+            if media_player.source() == Source::Fallback {
+                return;
+            }
+            media_player.refresh_sources()
+        };
+
+        let fetched = refresh.run().await;
+        let result = self.media_player.lock().await.apply_sources(fetched);
+        match result {
+            Ok(diff) => self.synced(diff),
+            Err(err) => tracing::warn!("unable to refresh the sources: {}", err),
+        }
+    }
+
+    // Lets the listeners know about tracks that joined or left the rotation
+    fn synced(&self, diff: RotationDiff) {
+        if !diff.is_empty() {
+            self.broadcast(
+                None,
+                serde_json::json!({"event": "playlist_synced", "data": diff}),
+            );
+        }
+        self.want_prefetch();
+    }
+
+    // Local library
+    pub fn library(&self) -> LocalLibrary {
+        self.library.clone()
+    }
+
+    pub fn fallback_library(&self) -> LocalLibrary {
+        self.fallback_library.clone()
+    }
+
+    // Library holding the local track, either the main one or the emergency playlist
+    pub fn library_of(&self, track_id: u64) -> Option<LocalLibrary> {
+        [&self.library, &self.fallback_library]
+            .into_iter()
+            .find(|library| library.get(track_id).is_some())
+            .cloned()
+    }
+
+    pub async fn sync_library(&self) {
+        self.media_player.lock().await.sync_library();
+        self.want_prefetch();
+    }
+
+    // Media proxy
+    pub fn audio_cache(&self) -> Arc<AudioCache> {
+        self.audio_cache.clone()
+    }
+
+    // Resolved track known to the station, resolved again when `refresh` is set or when it
+    // hasn't been yet
+    pub async fn stream_track(&self, track_id: u64, refresh: bool) -> Result<Track, Error> {
+        let prefetch = {
+            let media_player = self.media_player.lock().await;
+            if !media_player.is_known(track_id) {
+                return Err(Error::MediaNotFound(track_id));
+            }
+            if let (false, Some(track)) = (refresh, media_player.stream_track(track_id)) {
+                return Ok(track);
+            }
+            media_player.prefetch_track(track_id)
+        };
+
+        let tracks = prefetch.resolve().await;
+        let track = tracks
+            .first()
+            .cloned()
+            .ok_or(Error::MediaNotFound(track_id))?;
+        self.media_player.lock().await.store_prefetched(tracks);
+        Ok(track)
+    }
+
+    // Playout
+    pub fn playout(&self) -> PlayoutControl {
+        self.playout.clone()
+    }
+
+    pub fn playout_state(&self) -> PlayoutState {
+        *self.playout_state.read().unwrap()
+    }
+
+    pub async fn rewind_current_track(&self, started_at: DateTime<Utc>) {
+        let mut media_player = self.media_player.lock().await;
+        media_player.rewind_current_track(started_at);
+        *self.current_track.write().unwrap() = media_player.current_track.clone();
+        self.want_prefetch();
+    }
+
+    pub async fn reload_playlist(&self) -> Result<(), Error> {
+        let refresh = self.media_player.lock().await.refresh_sources();
+        let fetched = refresh.run().await;
+        let diff = self.media_player.lock().await.apply_sources(fetched)?;
+        self.synced(diff);
+        Ok(())
+    }
+
+    pub async fn insert_next(&self, track_id: u64) {
+        self.media_player.lock().await.insert_next(track_id);
+        self.want_prefetch();
+    }
+
+    // Lets the listeners know about the track on air, and starts its play in the history
+    pub fn air_current_track(&self) {
+        let track = self.current_track();
+        if self.history.lock().unwrap().start(&track, self.source()) {
+            self.notify_reactions(track.id, ReactionCounts::default());
+        }
+        self.broadcast(Some("track"), self.build_current_track_msg());
+    }
+
+    // Lets the listeners know the station is back on air after the playout task crashed
+    pub fn notify_playout_crashed(&self, reason: &str) {
+        self.metrics.playout_crashes.inc();
+        self.health
+            .record_error(&format!("playout task crashed: {}", reason));
+        self.health.playout_task(PlayoutTask::Crashed);
+    }
+
+    pub fn notify_playout_restarted(&self, restarts: u64) {
+        self.metrics.playout_restarts.inc();
+        self.health.playout_restarted();
+        self.broadcast(
+            None,
+            serde_json::json!({
+                "event": "playout_restarted",
+                "data": {"restarts": restarts, "state": self.playout_state()}
+            }),
+        );
+    }
+
+    pub fn notify_playout(&self, command: &PlayoutCommand, state: PlayoutState) {
+        *self.playout_state.write().unwrap() = state;
+        self.presence
+            .lock()
+            .unwrap()
+            .set_on_air(state.is_on_air(), Utc::now());
+        self.broadcast(None, self.build_playout_msg(Some(command)));
+    }
+
+    // Skip voting
+    pub fn vote_skip(&self, client_id: &str) -> Result<(), Error> {
+        if self.listeners_count() < self.skip_config.min_listeners {
+            return Err(Error::SkipNotEnoughListeners(
+                self.skip_config.min_listeners,
+            ));
+        }
+        if !self
+            .skip_votes
+            .lock()
+            .unwrap()
+            .votes
+            .insert(client_id.to_string())
+        {
+            return Err(Error::SkipAlreadyVoted);
+        }
+
+        self.notify_skip_votes();
+        self.check_skip_votes();
+        Ok(())
+    }
+
+    fn skip_votes_needed(&self) -> usize {
+        let needed = (self.listeners_count() as f64 * self.skip_config.vote_ratio).ceil() as usize;
+        needed.max(1)
+    }
+
+    fn check_skip_votes(&self) {
+        let listeners = self.listeners_count();
+        let needed = self.skip_votes_needed();
+        let mut skip_votes = self.skip_votes.lock().unwrap();
+        if skip_votes.requested
+            || skip_votes.votes.is_empty()
+            || listeners < self.skip_config.min_listeners
+            || skip_votes.votes.len() < needed
+        {
+            return;
+        }
+
+        tracing::info!(
+            "listeners voted to skip the current track ({}/{})",
+            skip_votes.votes.len(),
+            listeners
+        );
+        match self.playout.notify(PlayoutCommand::Skip) {
+            Ok(_) => skip_votes.requested = true,
+            Err(err) => tracing::warn!("unable to skip the current track: {}", err),
+        }
+    }
+
+    fn notify_skip_votes(&self) {
+        let votes = self.skip_votes.lock().unwrap().votes.len();
+        self.broadcast(
+            Some("skip_votes"),
+            serde_json::json!({
+                "event": "skip_votes",
+                "data": {"votes": votes, "needed": self.skip_votes_needed()}
+            }),
+        );
+    }
+
+    // Reactions
+    // Reactions are keyed by listener rather than by connection, so that reconnecting doesn't
+    // allow reacting again
+    pub fn react(
+        &self,
+        listener: &str,
+        track_id: Option<u64>,
+        reaction: Reaction,
+    ) -> Result<ReactionCounts, Error> {
+        let (track_id, counts) = self
+            .history
+            .lock()
+            .unwrap()
+            .react(listener, track_id, reaction)?;
+        self.notify_reactions(track_id, counts);
+        Ok(counts)
+    }
+
+    pub fn history(&self, limit: usize) -> Vec<Play> {
+        self.history.lock().unwrap().recent(limit)
+    }
+
+    pub fn most_loved(&self, limit: usize) -> Vec<TrackRanking> {
+        self.history
+            .lock()
+            .unwrap()
+            .most_loved(limit, &self.reactions_config)
+    }
+
+    fn notify_reactions(&self, track_id: u64, counts: ReactionCounts) {
+        self.broadcast(Some("reactions"), build_reactions_msg(track_id, counts));
+    }
+
+    // Song requests
+    pub async fn request_track(&self, track: &str, requester: &str) -> Result<SongRequest, Error> {
+        let track = RequestedTrack::parse(track)?;
+        let lookup = {
+            let media_player = self.media_player.lock().await;
+            media_player.check_request(&track)?;
+            media_player.lookup()
+        };
+        // Permalinks are resolved without holding the media player, so that a slow SoundCloud
+        // doesn't stall the playout
+        let track_id = match track {
+            RequestedTrack::Id(id) => id,
+            RequestedTrack::Url(url) => lookup.resolve_track_id(url.as_str()).await?,
+        };
+        let request = self
+            .media_player
+            .lock()
+            .await
+            .request_track(track_id, requester)?;
+        if request.status == RequestStatus::Queued {
+            self.notify_requests().await;
+        }
+        Ok(request)
+    }
+
+    pub async fn approve_request(&self, request_id: &str) -> Result<SongRequest, Error> {
+        let request = self.media_player.lock().await.approve_request(request_id)?;
+        self.notify_requests().await;
+        Ok(request)
+    }
+
+    pub async fn reject_request(&self, request_id: &str) -> Result<SongRequest, Error> {
+        self.media_player.lock().await.reject_request(request_id)
+    }
+
+    pub async fn queued_requests(&self) -> Vec<SongRequest> {
+        self.media_player.lock().await.queued_requests()
+    }
+
+    pub async fn pending_requests(&self) -> Vec<SongRequest> {
+        self.media_player.lock().await.pending_requests()
+    }
+
+    pub fn is_moderator(&self, token: &str) -> bool {
+        matches!(&self.moderator_token, Some(t) if t == token)
+            || self.admin_tokens.contains_key(token)
+    }
+
+    // Administration
+    pub fn admin_name(&self, token: &str) -> Option<String> {
+        self.admin_tokens.get(token).cloned()
+    }
+
+    pub async fn playlist_id(&self) -> Option<String> {
+        self.media_player
+            .lock()
+            .await
+            .playlist_id()
+            .map(str::to_string)
+    }
+
+    pub async fn switch_playlist(&self, playlist: &str) -> Result<(), Error> {
+        let refresh = self.media_player.lock().await.switch_playlist(playlist)?;
+        let fetched = refresh.run().await;
+        let diff = self.media_player.lock().await.apply_sources(fetched)?;
+        self.synced(diff);
+        Ok(())
+    }
+
+    pub async fn resolve(&self, url: &str) -> Result<Resource, Error> {
+        let lookup = self.media_player.lock().await.lookup();
+        lookup.resolve(url).await
+    }
+
+    // A track given by id or permalink
+    pub async fn resolve_track_id(&self, track: &str) -> Result<u64, Error> {
+        match RequestedTrack::parse(track)? {
+            RequestedTrack::Id(id) => Ok(id),
+            RequestedTrack::Url(url) => match self.resolve(url.as_str()).await? {
+                Resource::Track { id, .. } => Ok(id),
+                _ => Err(Error::RequestInvalidTrack(track.to_string())),
+            },
+        }
+    }
+
+    pub async fn upcoming(&self, limit: usize) -> Vec<UpcomingTrack> {
+        self.media_player.lock().await.upcoming(limit)
+    }
+
+    pub async fn remove_upcoming(&self, track_id: u64) -> Result<(), Error> {
+        if !self.media_player.lock().await.remove_upcoming(track_id) {
+            return Err(Error::QueueTrackNotFound(track_id));
+        }
+        self.notify_requests().await;
+        Ok(())
+    }
+
+    pub async fn rotate_client_id(&self) -> Result<(), Error> {
+        MediaPlayer::refresh_client_id(&self.media_player).await?;
+        self.want_prefetch();
+        Ok(())
+    }
+
+    pub fn listeners(&self) -> Vec<ListenerInfo> {
+        let now = Utc::now();
+        let presence = self.presence.lock().unwrap();
+        let mut listeners: Vec<ListenerInfo> = self
+            .listeners
+            .read()
+            .unwrap()
+            .values()
+            .map(|client| {
+                let listener = presence.listener(client.id.as_str(), now);
+                ListenerInfo {
+                    id: client.id.clone(),
+                    ip: client.ip,
+                    connected_at: client.connected_at,
+                    connected_secs: now.signed_duration_since(client.connected_at).num_seconds(),
+                    nickname: listener.as_ref().and_then(|l| l.nickname.clone()),
+                    listening_secs: listener.map_or(0, |l| l.listening_secs),
+                }
+            })
+            .collect();
+        listeners.sort_by_key(|l| l.connected_at);
+        listeners
+    }
+
+    // Presence
+    pub fn presence(&self) -> PresenceReport {
+        let now = Utc::now();
+        let mut presence = self.presence.lock().unwrap();
+        PresenceReport {
+            listeners: presence.listeners(now),
+            totals: presence.totals(now),
+        }
+    }
+
+    pub fn set_profile(
+        &self,
+        client_id: &str,
+        nickname: Option<&str>,
+        avatar_seed: Option<&str>,
+    ) -> Result<Listener, Error> {
+        let mut profile = Profile::new(nickname, avatar_seed)?;
+        // Nicknames are shown to everyone, blocked words are masked like in messages
+        profile.nickname = profile
+            .nickname
+            .map(|nickname| self.chat.lock().unwrap().mask(nickname.as_str()));
+        let listener = self
+            .presence
+            .lock()
+            .unwrap()
+            .set_profile(client_id, profile, Utc::now())
+            .ok_or_else(|| Error::ListenerNotFound(client_id.to_string()))?;
+        self.broadcast(
+            None,
+            serde_json::json!({"event": "listener_updated", "data": listener}),
+        );
+        Ok(listener)
+    }
+
+    // Chat
+    pub fn chat_history(&self) -> Result<Vec<ChatMessage>, Error> {
+        let chat = self.chat.lock().unwrap();
+        if !chat.is_enabled() {
+            return Err(Error::ChatDisabled);
+        }
+        Ok(chat.history())
+    }
+
+    pub fn chat(&self, client: &Client, text: &str) -> Result<ChatMessage, Error> {
+        let now = Utc::now();
+        let author = self
+            .presence
+            .lock()
+            .unwrap()
+            .listener(client.id.as_str(), now)
+            .ok_or_else(|| Error::ListenerNotFound(client.id.clone()))?;
+        let track = self
+            .current_track
+            .read()
+            .unwrap()
+            .as_ref()
+            .map(ChatTrack::from);
+        let message = self.chat.lock().unwrap().post(
+            &author,
+            self.listener_key(client).as_str(),
+            text,
+            track,
+            now,
+        )?;
+        self.broadcast(
+            None,
+            serde_json::json!({"event": "chat_message", "data": message}),
+        );
+        Ok(message)
+    }
+
+    pub fn delete_chat_message(&self, message_id: &str) -> Result<ChatMessage, Error> {
+        let message = self.chat.lock().unwrap().delete(message_id, Utc::now())?;
+        self.broadcast(
+            None,
+            serde_json::json!({"event": "chat_deleted", "data": {"id": message.id}}),
+        );
+        Ok(message)
+    }
+
+    // Mutes the author of the message, returns until when
+    pub fn mute_chat_author(
+        &self,
+        message_id: &str,
+        secs: Option<i64>,
+    ) -> Result<DateTime<Utc>, Error> {
+        let (message, until) = self
+            .chat
+            .lock()
+            .unwrap()
+            .mute(message_id, secs, Utc::now())?;
+        self.broadcast(
+            None,
+            serde_json::json!({
+                "event": "chat_muted",
+                "data": {"author": message.author, "until": until}
+            }),
+        );
+        Ok(until)
+    }
+
+    // Closes the client's connection, it will be removed from listeners on disconnect
+    pub fn kick(&self, client_id: &str) -> Result<Client, Error> {
+        let client = match self.listeners.read().unwrap().get(client_id) {
+            Some(client) => client.clone(),
+            None => return Err(Error::ListenerNotFound(client_id.to_string())),
+        };
+        client.disconnect();
+        Ok(client)
+    }
+
+    pub fn ban(&self, client_id: &str) -> Result<Client, Error> {
+        let client = self.kick(client_id)?;
+        if let Some(ip) = client.ip {
+            self.banned_ips.write().unwrap().insert(ip);
+        }
+        Ok(client)
+    }
+
+    pub fn unban(&self, ip: &IpAddr) -> bool {
+        self.banned_ips.write().unwrap().remove(ip)
+    }
+
+    pub fn is_banned(&self, ip: &IpAddr) -> bool {
+        self.banned_ips.read().unwrap().contains(ip)
+    }
+
+    pub fn bans(&self) -> Vec<IpAddr> {
+        self.banned_ips.read().unwrap().iter().copied().collect()
+    }
+
+    pub fn announce(&self, message: &str) {
+        self.broadcast(
+            None,
+            serde_json::json!({"event": "announcement", "data": {"message": message}}),
+        );
+    }
+
+    async fn notify_requests(&self) {
+        let requests = self.queued_requests().await;
+        self.broadcast(
+            Some("requests"),
+            serde_json::json!({"event": "requests", "data": requests}),
+        );
+        self.want_prefetch();
+    }
+
+    fn notify_listeners_count(&self) {
+        self.broadcast(
+            Some("listeners"),
+            serde_json::json!({"event": "listeners", "data": self.listeners_count()}),
+        );
+    }
+
+    // Sends an event to all the listeners, it's fine if there's none
+    fn broadcast(&self, key: Option<&'static str>, event: Value) {
+        tracing::debug!("sending broadcast message {}", event);
+        let _ = self.events.send(StationEvent {
+            key,
+            message: Message::Text(event.to_string()),
+        });
+    }
+
+    fn build_playout_msg(&self, command: Option<&PlayoutCommand>) -> Value {
+        serde_json::json!({
+            "event": "playout",
+            "data": {"command": command, "state": self.playout_state()}
+        })
+    }
+
+    fn build_source_msg(&self) -> Value {
+        serde_json::json!({"event": "source_changed", "data": {"source": self.source()}})
+    }
+
+    fn build_current_track_msg(&self) -> Value {
+        serde_json::json!({"event": "track", "data": self.current_track()})
     }
 }
 
 #[async_trait]
 impl WebSocketHandler for Station {
-    async fn on_connect(&mut self, client: &Client) {
-        self.listeners.insert(client.clone().id, client.clone());
+    async fn on_connect(&self, client: &Client) {
+        // Subscribe before announcing the new listener, so that it gets the count too
+        tokio::spawn(forward_events(
+            self.events.subscribe(),
+            client.clone(),
+            self.metrics.clone(),
+        ));
 
-        // Notify clients with listeners count
-        self.notify_listeners_count().await;
+        self.listeners
+            .write()
+            .unwrap()
+            .insert(client.clone().id, client.clone());
+        let count = self.listeners_count.fetch_add(1, Ordering::Relaxed) + 1;
+        self.metrics.set_listeners(count);
 
-        // Notify client with the current playing track
+        let listener = self.presence.lock().unwrap().join(
+            client.id.as_str(),
+            self.listener_key(client).as_str(),
+            Utc::now(),
+        );
+
+        // Notify clients with listeners count, and the new listener with who's there
+        self.notify_listeners_count();
+        self.broadcast(
+            None,
+            serde_json::json!({"event": "listener_joined", "data": listener}),
+        );
+        let presence = serde_json::json!({
+            "event": "presence",
+            "data": {"you": listener.id, "report": self.presence()}
+        });
         client
-            .clone()
-            .send_message(&self.build_current_track_msg().await)
+            .send_latest("presence", &Message::Text(presence.to_string()))
             .await;
+
+        let reactions = self.history.lock().unwrap().current_reactions();
+        if let Some((track_id, counts)) = reactions {
+            let msg = build_reactions_msg(track_id, counts);
+            client
+                .send_latest("reactions", &Message::Text(msg.to_string()))
+                .await;
+        }
+
+        // Replay the last chat messages to the newcomer
+        if let Ok(history) = self.chat_history() {
+            let msg = serde_json::json!({"event": "chat_history", "data": history});
+            client
+                .send_latest("chat_history", &Message::Text(msg.to_string()))
+                .await;
+        }
+
+        // Notify client with the current playing track, unless the station is off air
+        match self.playout_state() {
+            PlayoutState::Playing | PlayoutState::Holding => {
+                client
+                    .send_latest(
+                        "track",
+                        &Message::Text(self.build_current_track_msg().to_string()),
+                    )
+                    .await;
+                if self.source() == Source::Fallback {
+                    client
+                        .send_latest(
+                            "source",
+                            &Message::Text(self.build_source_msg().to_string()),
+                        )
+                        .await;
+                }
+                let next = self.upcoming_track.read().unwrap().clone();
+                if let Some(next) = next {
+                    let msg = serde_json::json!({"event": "next", "data": next});
+                    client
+                        .send_latest("next", &Message::Text(msg.to_string()))
+                        .await;
+                }
+            }
+            _ => {
+                client
+                    .send_message(&Message::Text(self.build_playout_msg(None).to_string()))
+                    .await
+            }
+        }
     }
 
-    async fn on_disconnect(&mut self, client: &Client) {
-        self.listeners.remove(&client.id);
-        self.notify_listeners_count().await;
+    async fn on_disconnect(&self, client: &Client) {
+        if self.listeners.write().unwrap().remove(&client.id).is_some() {
+            let count = self.listeners_count.fetch_sub(1, Ordering::Relaxed) - 1;
+            self.metrics.set_listeners(count);
+        }
+        self.notify_listeners_count();
+        let left = self
+            .presence
+            .lock()
+            .unwrap()
+            .leave(client.id.as_str(), Utc::now());
+        if let Some(listener) = left {
+            self.stats
+                .session_ended(listener.connected_secs, Utc::now());
+            self.broadcast(
+                None,
+                serde_json::json!({"event": "listener_left", "data": listener}),
+            );
+        }
+        if self.skip_votes.lock().unwrap().votes.remove(&client.id) {
+            self.notify_skip_votes();
+        }
+        self.check_skip_votes();
         tracing::info!("client disconnected: {}", client.id);
     }
 
-    async fn on_message(&mut self, _client: &Client) {}
+    async fn on_message(&self, client: &Client, msg: &str) {
+        let reply = match Command::parse(msg) {
+            Some(Command::Request { track }) => command_reply(
+                "request",
+                self.request_track(track.as_str(), self.listener_key(client).as_str())
+                    .await
+                    .map(|request| serde_json::json!(request)),
+            ),
+            Some(Command::VoteSkip) => command_reply(
+                "vote_skip",
+                self.vote_skip(client.id.as_str()).map(|_| Value::Null),
+            ),
+            Some(Command::Profile {
+                nickname,
+                avatar_seed,
+            }) => command_reply(
+                "profile",
+                self.set_profile(
+                    client.id.as_str(),
+                    nickname.as_deref(),
+                    avatar_seed.as_deref(),
+                )
+                .map(|listener| serde_json::json!(listener)),
+            ),
+            Some(Command::React { reaction, track_id }) => command_reply(
+                "react",
+                self.react(self.listener_key(client).as_str(), track_id, reaction)
+                    .map(|counts| serde_json::json!(counts)),
+            ),
+            Some(Command::Chat { message }) => command_reply(
+                "chat",
+                self.chat(client, message.as_str())
+                    .map(|message| serde_json::json!(message)),
+            ),
+            None => return,
+        };
+        client.send_message(&reply).await;
+    }
+}
+
+fn build_reactions_msg(track_id: u64, counts: ReactionCounts) -> Value {
+    serde_json::json!({
+        "event": "reactions",
+        "data": {"track_id": track_id, "counts": counts, "score": counts.score()}
+    })
 }
 
-pub type StationService = Arc<Mutex<Station>>;
+// Replies to a client's command with `{"event": <command>, "data": ...}`, or an `error` event.
+fn command_reply(command: &str, result: Result<Value, Error>) -> Message {
+    let reply = match result {
+        Ok(data) => serde_json::json!({"event": command, "data": data}),
+        Err(err) => serde_json::json!({
+            "event": "error",
+            "data": {"command": command, "reason": err.to_string()}
+        }),
+    };
+    Message::Text(reply.to_string())
+}
 
-pub async fn go_live(service: StationService) {
+// Relays station events to the client's own queue, until it disconnects
+async fn forward_events(
+    mut events: broadcast::Receiver<StationEvent>,
+    client: Client,
+    metrics: Arc<Metrics>,
+) {
     loop {
-        let track = service.lock().await.current_track().await;
-        tracing::info!(
-            "starting new track at {:?}: {:?}",
-            track.started_at,
-            track.title
-        );
-
-        let msg = service.lock().await.build_current_track_msg().await;
-        broadcast_message(&msg, &service.lock().await.listeners).await;
+        let event = match select(Box::pin(events.recv()), Box::pin(client.sender.closed())).await {
+            Either::Left((event, _)) => event,
+            Either::Right(_) => break,
+        };
 
-        let duration = Duration::from_millis(track.duration);
-        sleep(duration).await;
-        let _ = service.lock().await.next_track().await;
+        match event {
+            Ok(event) => match event.key {
+                Some(key) => client.send_latest(key, &event.message).await,
+                None => client.send_message(&event.message).await,
+            },
+            Err(RecvError::Lagged(skipped)) => {
+                tracing::warn!(
+                    "client {} lagged behind, {} events skipped",
+                    client.id,
+                    skipped
+                );
+                metrics.messages_dropped.add(skipped);
+            }
+            Err(RecvError::Closed) => break,
+        }
     }
 }
+
+pub type StationService = Arc<Station>;
@@ -0,0 +1,209 @@
+use crate::{config::ClientQueueConfig, metrics::Metrics};
+use axum::extract::ws::Message;
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+use tokio::{
+    sync::Notify,
+    time::{Duration, Instant},
+};
+
+#[derive(Debug)]
+pub enum Enqueued {
+    Queued,
+    Coalesced,
+    Dropped,
+    Evicted,
+}
+
+#[derive(Debug, Default)]
+struct QueueState {
+    // Messages with a key replace any queued message with the same key
+    messages: VecDeque<(Option<&'static str>, Message)>,
+    full_since: Option<Instant>,
+    closed: bool,
+}
+
+// Bounded buffer of the messages waiting to be written to a client's socket. When the client
+// can't keep up, messages are dropped, and if it stays full past the deadline it gets evicted.
+#[derive(Debug)]
+pub struct SendQueue {
+    state: Mutex<QueueState>,
+    depth: usize,
+    evict_after: Duration,
+    ready: Notify,
+    closed: Notify,
+    metrics: Arc<Metrics>,
+}
+
+impl SendQueue {
+    pub fn new(config: &ClientQueueConfig, metrics: Arc<Metrics>) -> Self {
+        Self {
+            state: Mutex::new(QueueState::default()),
+            depth: config.depth.max(1),
+            evict_after: Duration::from_secs(config.evict_after_secs),
+            ready: Notify::new(),
+            closed: Notify::new(),
+            metrics,
+        }
+    }
+
+    pub fn push(&self, key: Option<&'static str>, msg: Message) -> Enqueued {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return Enqueued::Dropped;
+        }
+
+        if let Some(key) = key {
+            if let Some(queued) = state.messages.iter_mut().find(|(k, _)| *k == Some(key)) {
+                queued.1 = msg;
+                self.metrics.messages_coalesced.inc();
+                return Enqueued::Coalesced;
+            }
+        }
+
+        if state.messages.len() >= self.depth {
+            let now = Instant::now();
+            let full_since = *state.full_since.get_or_insert(now);
+            if now.duration_since(full_since) >= self.evict_after {
+                state.closed = true;
+                drop(state);
+                self.metrics.clients_evicted.inc();
+                self.ready.notify_one();
+                self.closed.notify_waiters();
+                return Enqueued::Evicted;
+            }
+            self.metrics.messages_dropped.inc();
+            return Enqueued::Dropped;
+        }
+
+        state.messages.push_back((key, msg));
+        drop(state);
+        self.ready.notify_one();
+        Enqueued::Queued
+    }
+
+    // Next message to be written, or `None` once the queue has been closed
+    pub async fn pop(&self) -> Option<Message> {
+        loop {
+            {
+                let mut state = self.state.lock().unwrap();
+                if state.closed {
+                    return None;
+                }
+                if let Some((_, msg)) = state.messages.pop_front() {
+                    if state.messages.len() < self.depth {
+                        state.full_since = None;
+                    }
+                    return Some(msg);
+                }
+            }
+            self.ready.notified().await;
+        }
+    }
+
+    pub fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.ready.notify_one();
+        self.closed.notify_waiters();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.state.lock().unwrap().closed
+    }
+
+    // Resolves when the queue gets closed, either on eviction or by `close`
+    pub async fn closed(&self) {
+        loop {
+            let closed = self.closed.notified();
+            if self.is_closed() {
+                return;
+            }
+            closed.await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue(depth: usize, evict_after_secs: u64) -> SendQueue {
+        SendQueue::new(
+            &ClientQueueConfig {
+                depth,
+                evict_after_secs,
+            },
+            Arc::default(),
+        )
+    }
+
+    fn text(text: &str) -> Message {
+        Message::Text(text.to_string())
+    }
+
+    #[tokio::test]
+    async fn sends_messages_in_order() {
+        let queue = queue(4, 30);
+        assert!(matches!(queue.push(None, text("one")), Enqueued::Queued));
+        assert!(matches!(queue.push(None, text("two")), Enqueued::Queued));
+        assert_eq!(queue.pop().await, Some(text("one")));
+        assert_eq!(queue.pop().await, Some(text("two")));
+    }
+
+    #[tokio::test]
+    async fn coalesces_messages_with_the_same_key() {
+        let queue = queue(4, 30);
+        queue.push(Some("listeners"), text("1 listener"));
+        queue.push(None, text("chat"));
+        assert!(matches!(
+            queue.push(Some("listeners"), text("2 listeners")),
+            Enqueued::Coalesced
+        ));
+        // The update keeps the place of the one it replaced
+        assert_eq!(queue.pop().await, Some(text("2 listeners")));
+        assert_eq!(queue.pop().await, Some(text("chat")));
+    }
+
+    #[tokio::test]
+    async fn drops_messages_while_full() {
+        let queue = queue(2, 30);
+        queue.push(None, text("one"));
+        queue.push(None, text("two"));
+        assert!(matches!(queue.push(None, text("three")), Enqueued::Dropped));
+        assert!(!queue.is_closed());
+
+        assert_eq!(queue.pop().await, Some(text("one")));
+        assert!(matches!(queue.push(None, text("four")), Enqueued::Queued));
+        assert_eq!(queue.pop().await, Some(text("two")));
+        assert_eq!(queue.pop().await, Some(text("four")));
+    }
+
+    #[tokio::test]
+    async fn evicts_clients_that_stay_full() {
+        let queue = queue(1, 0);
+        queue.push(None, text("one"));
+        assert!(matches!(queue.push(None, text("two")), Enqueued::Evicted));
+        assert!(queue.is_closed());
+        queue.closed().await;
+        assert_eq!(queue.pop().await, None);
+        assert!(matches!(queue.push(None, text("three")), Enqueued::Dropped));
+    }
+
+    #[tokio::test]
+    async fn wakes_up_the_writer() {
+        let queue = std::sync::Arc::new(queue(4, 30));
+        let pop = |queue: std::sync::Arc<SendQueue>| tokio::spawn(async move { queue.pop().await });
+
+        let writer = pop(queue.clone());
+        tokio::task::yield_now().await;
+        queue.push(None, text("one"));
+        assert_eq!(writer.await.unwrap(), Some(text("one")));
+
+        let writer = pop(queue.clone());
+        tokio::task::yield_now().await;
+        queue.close();
+        assert_eq!(writer.await.unwrap(), None);
+    }
+}
@@ -0,0 +1,11 @@
+use super::radio::StationService;
+use tokio::time::{sleep, Duration};
+
+// Fetches the station's sources periodically, so that tracks added to or removed from them get
+// into the rotation without a restart.
+pub async fn refresh_sources(service: StationService, refresh_every: Duration) {
+    loop {
+        sleep(refresh_every).await;
+        service.refresh_sources().await;
+    }
+}
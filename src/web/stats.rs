@@ -0,0 +1,14 @@
+use super::radio::StationService;
+use chrono::Utc;
+use tokio::time::{sleep, Duration};
+
+// Counts the listeners periodically for the station's statistics, and saves them along the way.
+pub async fn sample_stats(service: StationService, sample_every: Duration) {
+    loop {
+        sleep(sample_every).await;
+        service
+            .stats()
+            .sample_listeners(service.listeners_count(), Utc::now());
+        service.stats().save().await;
+    }
+}
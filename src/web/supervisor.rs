@@ -0,0 +1,149 @@
+use super::{
+    playout::{go_live, PlayoutCommands, PlayoutState},
+    radio::StationService,
+};
+use crate::config::SupervisorConfig;
+use std::{any::Any, future::Future};
+use tokio::time::{sleep, Duration, Instant};
+
+// A task that ran this long before crashing starts over from the shortest backoff
+const STABLE_AFTER: Duration = Duration::from_secs(300);
+
+// Runs the playout task, and restarts it with an exponential backoff whenever it crashes, so that
+// a single bad track can't leave the station frozen. It returns once the playout is stopped.
+pub async fn supervise_playout(
+    service: StationService,
+    commands: PlayoutCommands,
+    config: SupervisorConfig,
+) {
+    let crashed = service.clone();
+    let restarted = service.clone();
+    let stopped = service.clone();
+    supervise(
+        &config,
+        || go_live(service.clone(), commands.clone()),
+        |reason| crashed.notify_playout_crashed(reason),
+        |restarts| restarted.notify_playout_restarted(restarts),
+        || stopped.playout_state() == PlayoutState::Stopped,
+    )
+    .await
+}
+
+// Restarts the task whenever it panics, or returns without being `stopped`. Panics are caught by
+// the runtime, which needs the `unwind` panic strategy.
+async fn supervise<T, F>(
+    config: &SupervisorConfig,
+    mut task: T,
+    mut on_crash: impl FnMut(&str),
+    mut on_restart: impl FnMut(u64),
+    stopped: impl Fn() -> bool,
+) where
+    T: FnMut() -> F,
+    F: Future<Output = ()> + Send + 'static,
+{
+    let min_backoff = Duration::from_secs(config.backoff_secs);
+    let max_backoff = Duration::from_secs(config.max_backoff_secs).max(min_backoff);
+    let mut backoff = min_backoff;
+    let mut restarts = 0;
+
+    loop {
+        let started_at = Instant::now();
+        let reason = match tokio::spawn(task()).await {
+            Ok(()) if stopped() => {
+                tracing::warn!("playout stopped, the station is off air");
+                return;
+            }
+            Ok(()) => String::from("returned while on air"),
+            Err(err) => match err.try_into_panic() {
+                Ok(panic) => panic_message(panic),
+                Err(err) => err.to_string(),
+            },
+        };
+        tracing::error!("playout task crashed: {}", reason);
+        on_crash(reason.as_str());
+
+        if started_at.elapsed() >= STABLE_AFTER {
+            backoff = min_backoff;
+        }
+        tracing::info!("restarting the playout task in {:?}", backoff);
+        sleep(backoff).await;
+        backoff = (backoff * 2).min(max_backoff);
+
+        restarts += 1;
+        on_restart(restarts);
+    }
+}
+
+fn panic_message(panic: Box<dyn Any + Send>) -> String {
+    match panic.downcast::<String>() {
+        Ok(message) => *message,
+        Err(panic) => match panic.downcast::<&'static str>() {
+            Ok(message) => message.to_string(),
+            Err(_) => String::from("unknown panic"),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    };
+
+    const NO_BACKOFF: SupervisorConfig = SupervisorConfig {
+        backoff_secs: 0,
+        max_backoff_secs: 0,
+    };
+
+    #[tokio::test]
+    async fn restarts_a_panicking_task() {
+        let runs = Arc::new(AtomicUsize::new(0));
+        let restarts = Arc::new(Mutex::new(Vec::new()));
+        let task_runs = runs.clone();
+        let task_restarts = restarts.clone();
+
+        supervise(
+            &NO_BACKOFF,
+            || {
+                let runs = task_runs.clone();
+                async move {
+                    if runs.fetch_add(1, Ordering::SeqCst) < 2 {
+                        panic!("bad track");
+                    }
+                }
+            },
+            |_| {},
+            |restart| task_restarts.lock().unwrap().push(restart),
+            || true,
+        )
+        .await;
+
+        assert_eq!(runs.load(Ordering::SeqCst), 3);
+        assert_eq!(*restarts.lock().unwrap(), vec![1, 2]);
+    }
+
+    #[tokio::test]
+    async fn restarts_a_task_that_returns_while_on_air() {
+        let runs = Arc::new(AtomicUsize::new(0));
+        let task_runs = runs.clone();
+        let stopped_runs = runs.clone();
+
+        supervise(
+            &NO_BACKOFF,
+            || {
+                let runs = task_runs.clone();
+                async move {
+                    runs.fetch_add(1, Ordering::SeqCst);
+                }
+            },
+            |_| {},
+            |_| {},
+            || stopped_runs.load(Ordering::SeqCst) >= 2,
+        )
+        .await;
+
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+    }
+}
@@ -1,81 +1,132 @@
 // use super::radio::Arc<Mutex<impl WebSocketHandler>>;
-use anyhow::Result;
+use super::send_queue::{Enqueued, SendQueue};
+use crate::{config::ClientQueueConfig, metrics::Metrics};
 use async_trait::async_trait;
 use axum::extract::ws::{Message, WebSocket};
-use futures::stream::SplitStream;
-use futures::FutureExt;
+use chrono::{DateTime, Utc};
+use futures::future::select;
+use futures::stream::{SplitSink, SplitStream};
+use futures::SinkExt;
 use futures::StreamExt;
 use std::collections::HashMap;
 use std::marker::Send;
+use std::marker::Sync;
+use std::net::IpAddr;
 use std::sync::Arc;
-use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
-use tokio::sync::Mutex;
-use tokio_stream::wrappers::UnboundedReceiverStream;
 use uuid::Uuid;
 
 #[derive(Debug, Clone)]
 pub struct Client {
     pub id: String,
     pub sender: Sender,
+    pub ip: Option<IpAddr>,
+    pub connected_at: DateTime<Utc>,
+    metrics: Arc<Metrics>,
 }
 
 impl Client {
-    pub fn new(sender: Sender) -> Client {
+    pub fn new(sender: Sender, ip: Option<IpAddr>, metrics: Arc<Metrics>) -> Client {
         let id = Uuid::new_v4().as_simple().to_string();
-        Client { id, sender }
+        Client {
+            id,
+            sender,
+            ip,
+            connected_at: Utc::now(),
+            metrics,
+        }
     }
 
     pub async fn send_message(&self, msg: &Message) {
-        if self.sender.send(Ok(msg.clone())).is_err() {
-            tracing::error!("error sending message to client: {}", self.id)
+        self.enqueue(None, msg);
+    }
+
+    // Sends a message that supersedes any older one with the same `key` still waiting in queue
+    pub async fn send_latest(&self, key: &'static str, msg: &Message) {
+        self.enqueue(Some(key), msg);
+    }
+
+    pub fn disconnect(&self) {
+        self.sender.close();
+    }
+
+    fn enqueue(&self, key: Option<&'static str>, msg: &Message) {
+        if self.sender.is_closed() {
+            self.metrics.broadcast_send_failures.inc();
+            tracing::error!("error sending message to client: {}", self.id);
+            return;
+        }
+
+        match self.sender.push(key, msg.clone()) {
+            Enqueued::Queued | Enqueued::Coalesced => {}
+            Enqueued::Dropped => {
+                tracing::debug!("client {} is too slow, dropping message", self.id)
+            }
+            Enqueued::Evicted => {
+                tracing::warn!(
+                    "client {} has been too slow for too long, evicting",
+                    self.id
+                )
+            }
         }
     }
 }
 
-pub type Sender = UnboundedSender<Result<Message, axum::Error>>;
+pub type Sender = Arc<SendQueue>;
 pub type Clients = HashMap<String, Client>;
-pub type WebSocketService = Arc<Mutex<dyn WebSocketHandler + Send>>;
+pub type WebSocketService = Arc<dyn WebSocketHandler + Send + Sync>;
 
 #[async_trait]
 pub trait WebSocketHandler {
-    async fn on_connect(&mut self, client: &Client);
-    async fn on_disconnect(&mut self, client: &Client);
-    async fn on_message(&mut self, _client: &Client);
+    async fn on_connect(&self, client: &Client);
+    async fn on_disconnect(&self, client: &Client);
+    async fn on_message(&self, _client: &Client, _msg: &str);
 }
 
-pub async fn handle_client_connection(ws: WebSocket, service: WebSocketService) {
+pub async fn handle_client_connection(
+    ws: WebSocket,
+    service: WebSocketService,
+    ip: Option<IpAddr>,
+    queue_config: ClientQueueConfig,
+    metrics: Arc<Metrics>,
+) {
     // Split the socket into a sender and receive of messages.
     let (ws_tx, mut ws_rx) = ws.split();
 
-    // Use an unbounded channel to handle buffering and flushing of messages to the socket
-    let (tx, rx) = unbounded_channel();
-    let rx = UnboundedReceiverStream::new(rx);
-
-    tokio::task::spawn(rx.forward(ws_tx).map(|result| {
-        if let Err(e) = result {
-            tracing::error!("error sending websocket message: {}", e);
-        }
-    }));
+    // Use a bounded queue to handle buffering and flushing of messages to the socket
+    let queue: Sender = Arc::new(SendQueue::new(&queue_config, metrics.clone()));
+    tokio::task::spawn(write_messages(queue.clone(), ws_tx));
 
     // Store client
-    let client = Client::new(tx.clone());
+    let client = Client::new(queue.clone(), ip, metrics.clone());
     tracing::info!("client connected with id: {}", client.id.clone());
 
-    service.lock().await.on_connect(&client).await;
+    metrics.ws_connects.inc();
+    service.on_connect(&client).await;
 
-    receive_messages(&mut ws_rx, &client, &service).await;
+    // Stop receiving as soon as the client gets evicted or kicked
+    select(
+        Box::pin(receive_messages(&mut ws_rx, &client, &service)),
+        Box::pin(queue.closed()),
+    )
+    .await;
+    queue.close();
 
-    service.lock().await.on_disconnect(&client.clone()).await;
+    service.on_disconnect(&client.clone()).await;
+    metrics.ws_disconnects.inc();
 }
 
-pub async fn broadcast_message(msg: &Message, clients: &Clients) {
-    tracing::debug!("sending broadcast message {:#?}", msg);
-    for (_id, client) in clients.iter() {
-        client.send_message(msg).await;
+// Private helpers
+async fn write_messages(queue: Sender, mut ws_tx: SplitSink<WebSocket, Message>) {
+    while let Some(msg) = queue.pop().await {
+        if let Err(e) = ws_tx.send(msg).await {
+            tracing::error!("error sending websocket message: {}", e);
+            queue.close();
+            break;
+        }
     }
+    let _ = ws_tx.close().await;
 }
 
-// Private helpers
 async fn receive_messages(
     ws_rx: &mut SplitStream<WebSocket>,
     client: &Client,
@@ -98,7 +149,7 @@ async fn handle_received_message(client: &Client, msg: &Message, service: &WebSo
         if handle_received_ping(text.as_str(), client).await {
             return;
         }
-        service.lock().await.on_message(&client.clone()).await;
+        service.on_message(&client.clone(), text.as_str()).await;
     }
 }
 